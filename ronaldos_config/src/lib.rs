@@ -1,7 +1,7 @@
-use serde::Deserialize;
+use serde::{de::Error, Deserialize, Deserializer};
 use std::fmt::Debug;
 use std::io::{self};
-use std::path::{Path, PathBuf};
+use std::path::{Component, Path, PathBuf};
 
 pub const DEFAULT_DATA: &str = concat!("/opt/share/ronaldos-webserver");
 pub const CFG_PATH: &str = concat!("/opt/etc/ronaldos-webserver/config.cfg");
@@ -13,6 +13,252 @@ pub struct Login {
     pub password: String,
 }
 
+/// Single quality level of the HLS bitrate ladder.
+#[derive(Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Rendition {
+    pub width: u32,
+    pub height: u32,
+    /// bits per second
+    pub bitrate: u64,
+}
+
+/// Settings of the capture card and the encoder used by the screen grabber.
+/// Missing keys fall back to the values of [Capture::default].
+#[derive(Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(default)]
+pub struct Capture {
+    /// name the capture card reports in /sys/class/video4linux/*/name
+    pub device: String,
+    pub width: u32,
+    pub height: u32,
+    pub fps: u32,
+    /// length of a HLS segment in seconds
+    pub segment_duration: u32,
+    /// distance between keyframes in frames
+    pub gop_size: u32,
+    /// x264 preset, e.g. "ultrafast", "veryfast" or "medium"
+    pub preset: String,
+    /// bits per second of the full resolution rendition
+    pub bitrate: u64,
+    /// lower quality renditions that are encoded next to the full resolution
+    pub ladder: Vec<Rendition>,
+    /// ALSA device to record audio from. "auto" uses the capture card's own
+    /// sound card, an empty string disables audio.
+    pub audio_device: String,
+    /// bits per second of the AAC audio track
+    pub audio_bitrate: u64,
+}
+
+impl Default for Capture {
+    fn default() -> Self {
+        Self {
+            device: "Game Capture HD60 S+".to_string(),
+            width: 1920,
+            height: 1080,
+            fps: 60,
+            segment_duration: 10,
+            gop_size: 120,
+            preset: "veryfast".to_string(),
+            bitrate: 4_000_000,
+            ladder: vec![
+                Rendition {
+                    width: 1280,
+                    height: 720,
+                    bitrate: 2_500_000,
+                },
+                Rendition {
+                    width: 854,
+                    height: 480,
+                    bitrate: 1_000_000,
+                },
+            ],
+            audio_device: "auto".to_string(),
+            audio_bitrate: 128_000,
+        }
+    }
+}
+
+/// When the recording scheduler records a fixture, relative to its kickoff.
+#[derive(Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(default)]
+pub struct Schedule {
+    /// minutes the recording starts before kickoff
+    pub lead_minutes: u32,
+    /// minutes the recording runs after kickoff
+    pub match_minutes: u32,
+}
+
+impl Default for Schedule {
+    fn default() -> Self {
+        Self {
+            lead_minutes: 10,
+            match_minutes: 120,
+        }
+    }
+}
+
+/// Stream key an encoder publishes with, and the name its streams get.
+#[derive(Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct StreamKey {
+    pub key: String,
+    pub name: String,
+}
+
+/// RTMP ingest of external encoders. Disabled as long as no keys are
+/// configured.
+#[derive(Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(default)]
+pub struct Rtmp {
+    pub port: u16,
+    /// length of a HLS segment in seconds
+    pub segment_duration: u32,
+    pub keys: Vec<StreamKey>,
+}
+
+impl Default for Rtmp {
+    fn default() -> Self {
+        Self {
+            port: 1935,
+            segment_duration: 4,
+            keys: Vec::new(),
+        }
+    }
+}
+
+/// Token an encoder uploads HLS output over HTTP with, and the directory
+/// below `video_dir` it may write to.
+#[derive(Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct IngestToken {
+    pub token: String,
+    #[serde(deserialize_with = "ingest_prefix")]
+    pub prefix: PathBuf,
+}
+
+/// Prefixes that name no directory, e.g. an empty one, would grant the whole
+/// `video_dir` and are refused.
+fn ingest_prefix<'de, D: Deserializer<'de>>(deserializer: D) -> Result<PathBuf, D::Error> {
+    let prefix = PathBuf::deserialize(deserializer)?;
+    if !prefix
+        .components()
+        .any(|c| matches!(c, Component::Normal(_)))
+    {
+        return Err(D::Error::custom(format!(
+            "ingest token prefix {:?} does not name a directory",
+            prefix
+        )));
+    }
+    Ok(prefix)
+}
+
+/// Whether an SRT endpoint waits for the encoder or connects to it.
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SrtMode {
+    #[default]
+    Listener,
+    Caller,
+}
+
+/// SRT endpoint receiving MPEG-TS from an encoder.
+#[derive(Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct SrtEndpoint {
+    /// name the streams of this endpoint are registered under
+    pub name: String,
+    /// address to listen on, or of the encoder in caller mode
+    pub address: String,
+    #[serde(default)]
+    pub mode: SrtMode,
+    /// encrypts the connection when set, 10 to 79 characters
+    #[serde(default)]
+    pub passphrase: String,
+    /// receive latency in milliseconds
+    #[serde(default = "default_srt_latency")]
+    pub latency_ms: u32,
+}
+
+fn default_srt_latency() -> u32 {
+    120
+}
+
+/// SRT ingest, an alternative to RTMP for encoders on lossy networks.
+#[derive(Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(default)]
+pub struct Srt {
+    /// length of a HLS segment in seconds
+    pub segment_duration: u32,
+    pub endpoints: Vec<SrtEndpoint>,
+}
+
+impl Default for Srt {
+    fn default() -> Self {
+        Self {
+            segment_duration: 4,
+            endpoints: Vec::new(),
+        }
+    }
+}
+
+/// How the stream store notices changes in the video directory.
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum WatchMode {
+    /// inotify, or whatever the platform offers
+    #[default]
+    Native,
+    /// scans the directory periodically, e.g. for network filesystems
+    Poll,
+}
+
+/// Watching of the video directory for new, changed and removed streams.
+#[derive(Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(default)]
+pub struct FileWatch {
+    pub mode: WatchMode,
+    /// milliseconds changes to a path are collected before they are handled
+    pub debounce_ms: u64,
+    /// seconds between two scans in poll mode
+    pub poll_interval: u64,
+}
+
+impl Default for FileWatch {
+    fn default() -> Self {
+        Self {
+            mode: WatchMode::Native,
+            debounce_ms: 500,
+            poll_interval: 5,
+        }
+    }
+}
+
+/// Automatic removal of old recordings. Live and pinned streams are never
+/// removed, without limits nothing is.
+#[derive(Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(default)]
+pub struct Retention {
+    /// streams older than this are removed, 0 keeps them forever
+    pub max_age_days: u32,
+    /// oldest streams are removed while video_dir uses more, 0 for no quota
+    pub max_bytes: u64,
+    /// minutes between two runs
+    pub interval_minutes: u64,
+}
+
+impl Default for Retention {
+    fn default() -> Self {
+        Self {
+            max_age_days: 0,
+            max_bytes: 0,
+            interval_minutes: 60,
+        }
+    }
+}
+
+impl Retention {
+    pub fn enabled(&self) -> bool {
+        self.max_age_days > 0 || self.max_bytes > 0
+    }
+}
+
 macro_rules! config_definitions {
     ($($name:ident : $type:ty = $default:expr),+) => {
         #[derive(Deserialize, Clone, Debug, Default)]
@@ -55,9 +301,19 @@ config_definitions!(
     verbose: bool = false,
     api_key: String = String::new(),
     video_dir: PathBuf = PathBuf::from(format!("{}/videos", DEFAULT_DATA)),
+    catalog: PathBuf = PathBuf::from(format!("{}/catalog.sqlite", DEFAULT_DATA)),
     login: Login = Default::default(),
     hostname: String = String::from("localhost"),
-    interval_days: u64 = 7
+    interval_days: u64 = 7,
+    capture: Capture = Capture::default(),
+    schedule: Schedule = Schedule::default(),
+    admin_token: String = String::new(),
+    rtmp: Rtmp = Rtmp::default(),
+    srt: Srt = Srt::default(),
+    file_watch: FileWatch = FileWatch::default(),
+    retention: Retention = Retention::default(),
+    ingest_tokens: Vec<IngestToken> = Vec::new(),
+    max_upload_bytes: u64 = 256 * 1024 * 1024
 );
 
 pub fn get_application_config<P: AsRef<Path>>(config: &P) -> Config {
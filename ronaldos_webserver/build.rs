@@ -10,12 +10,24 @@ fn main() {
     println!("cargo:rustc-link-lib=swscale");
     println!("cargo:rustc-link-lib=swresample");
     println!("cargo:rustc-link-lib=avfilter");
+    println!("cargo:rustc-link-lib=avdevice");
 
     println!("cargo:rerun-if-changed=src/screen_grabber.cpp");
+    println!("cargo:rerun-if-changed=src/capture_source.cpp");
+    println!("cargo:rerun-if-changed=src/capture_source.hpp");
+    println!("cargo:rerun-if-changed=src/stream_remuxer.cpp");
+    println!("cargo:rerun-if-changed=src/thumbnailer.cpp");
+    println!("cargo:rerun-if-changed=src/media_probe.cpp");
+    println!("cargo:rerun-if-changed=src/clipper.cpp");
     println!("cargo:rerun-if-changed=src/tracing.hpp");
     cc::Build::new()
         .cpp(true)
         .file("src/screen_grabber.cpp")
+        .file("src/capture_source.cpp")
+        .file("src/stream_remuxer.cpp")
+        .file("src/thumbnailer.cpp")
+        .file("src/media_probe.cpp")
+        .file("src/clipper.cpp")
         .cpp_set_stdlib("c++")
         .flag("-std=c++23")
         .flag("-O3")
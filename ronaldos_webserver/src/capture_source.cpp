@@ -0,0 +1,668 @@
+#include "capture_source.hpp"
+#include "tracing.hpp"
+#include <algorithm>
+#include <atomic>
+#include <cstdio>
+#include <deque>
+#include <filesystem>
+#include <format>
+#include <fstream>
+#include <linux/videodev2.h>
+#include <mutex>
+#include <optional>
+#include <string.h>
+#include <string>
+#include <sys/ioctl.h>
+#include <thread>
+#include <unistd.h>
+#include <vector>
+
+extern "C" {
+#include <fcntl.h>
+#include <libavcodec/avcodec.h>
+#include <libavdevice/avdevice.h>
+#include <libavformat/avformat.h>
+#include <libavutil/time.h>
+#include <libswscale/swscale.h>
+#include <poll.h>
+#include <sys/mman.h>
+}
+
+#define CAPTURE_BUFFERS 4
+#define CAPTURE_TIMEOUT_MS 2000
+#define TEST_TONE_FREQUENCY 1000
+#define TEST_TONE_SAMPLE_RATE 48000
+#define MAX_QUEUED_AUDIO 64
+
+std::optional<std::filesystem::path>
+find_video_path(const std::string &dev_name) {
+  std::filesystem::path video4linux_dir("/sys/class/video4linux");
+
+  if (!std::filesystem::exists(video4linux_dir)) {
+    return std::nullopt;
+  }
+
+  for (const auto &entry :
+       std::filesystem::directory_iterator(video4linux_dir)) {
+    std::filesystem::path name_file = entry.path() / "name";
+
+    std::ifstream name_stream(name_file);
+    if (name_stream) {
+      std::string name;
+      std::getline(name_stream, name);
+
+      info("found video input device: {} - {}", entry.path().c_str(), name);
+      if (name.find(dev_name) != std::string::npos) {
+        return std::filesystem::path("/dev") / entry.path().filename();
+      }
+    }
+  }
+
+  return std::nullopt;
+}
+
+struct MappedBuffer {
+  void *start = MAP_FAILED;
+  size_t length = 0;
+};
+
+/// Owns the file descriptor and the mmap-ed kernel buffers of a V4L2 capture
+/// device. Buffers are unmapped and streaming is turned off on destruction.
+class V4l2Capture {
+public:
+  explicit V4l2Capture(int fd) : fd(fd) {}
+  V4l2Capture(const V4l2Capture &) = delete;
+  V4l2Capture &operator=(const V4l2Capture &) = delete;
+
+  ~V4l2Capture() {
+    if (streaming) {
+      v4l2_buf_type type = V4L2_BUF_TYPE_VIDEO_CAPTURE;
+      ioctl(fd, VIDIOC_STREAMOFF, &type);
+    }
+
+    for (auto &buffer : buffers) {
+      if (buffer.start != MAP_FAILED) {
+        munmap(buffer.start, buffer.length);
+      }
+    }
+    close(fd);
+  }
+
+  /// Negotiates YUYV at the requested resolution and frame rate. The driver
+  /// is allowed to adjust the resolution, the negotiated values are stored in
+  /// `width` and `height`.
+  int set_format(int requested_width, int requested_height, int fps) {
+    struct v4l2_format format;
+    memset(&format, 0, sizeof(format));
+    format.type = V4L2_BUF_TYPE_VIDEO_CAPTURE;
+    format.fmt.pix.width = requested_width;
+    format.fmt.pix.height = requested_height;
+    format.fmt.pix.pixelformat = V4L2_PIX_FMT_YUYV;
+    format.fmt.pix.field = V4L2_FIELD_ANY;
+
+    if (ioctl(fd, VIDIOC_S_FMT, &format) < 0) {
+      error("Could not set format: {}", strerror(errno));
+      return -1;
+    }
+
+    if (format.fmt.pix.pixelformat != V4L2_PIX_FMT_YUYV) {
+      error("capture device does not support YUYV");
+      return -1;
+    }
+
+    width = format.fmt.pix.width;
+    height = format.fmt.pix.height;
+    bytes_per_line = format.fmt.pix.bytesperline;
+
+    struct v4l2_streamparm streamparm;
+    memset(&streamparm, 0, sizeof(streamparm));
+    streamparm.type = V4L2_BUF_TYPE_VIDEO_CAPTURE;
+    streamparm.parm.capture.timeperframe.numerator = 1;
+    streamparm.parm.capture.timeperframe.denominator = fps;
+
+    if (ioctl(fd, VIDIOC_S_PARM, &streamparm) < 0) {
+      warn("Could not set frame rate: {}", strerror(errno));
+    }
+
+    info("capturing {}x{} YUYV", width, height);
+    return 0;
+  }
+
+  /// Requests and maps the kernel buffers, queues them and starts streaming.
+  int start_streaming() {
+    struct v4l2_requestbuffers request;
+    memset(&request, 0, sizeof(request));
+    request.count = CAPTURE_BUFFERS;
+    request.type = V4L2_BUF_TYPE_VIDEO_CAPTURE;
+    request.memory = V4L2_MEMORY_MMAP;
+
+    if (ioctl(fd, VIDIOC_REQBUFS, &request) < 0) {
+      error("Could not request buffers: {}", strerror(errno));
+      return -1;
+    }
+
+    if (request.count < 2) {
+      error("insufficient buffer memory on capture device");
+      return -1;
+    }
+
+    buffers.resize(request.count);
+    for (uint32_t i = 0; i < request.count; ++i) {
+      struct v4l2_buffer buf;
+      memset(&buf, 0, sizeof(buf));
+      buf.type = V4L2_BUF_TYPE_VIDEO_CAPTURE;
+      buf.memory = V4L2_MEMORY_MMAP;
+      buf.index = i;
+
+      if (ioctl(fd, VIDIOC_QUERYBUF, &buf) < 0) {
+        error("Could not query buffer {}: {}", i, strerror(errno));
+        return -1;
+      }
+
+      buffers[i].length = buf.length;
+      buffers[i].start = mmap(nullptr, buf.length, PROT_READ | PROT_WRITE,
+                              MAP_SHARED, fd, buf.m.offset);
+      if (buffers[i].start == MAP_FAILED) {
+        error("Could not mmap buffer {}: {}", i, strerror(errno));
+        return -1;
+      }
+
+      if (ioctl(fd, VIDIOC_QBUF, &buf) < 0) {
+        error("Could not queue buffer {}: {}", i, strerror(errno));
+        return -1;
+      }
+    }
+
+    v4l2_buf_type type = V4L2_BUF_TYPE_VIDEO_CAPTURE;
+    if (ioctl(fd, VIDIOC_STREAMON, &type) < 0) {
+      error("Could not start streaming: {}", strerror(errno));
+      return -1;
+    }
+
+    streaming = true;
+    return 0;
+  }
+
+  /// Blocks until a filled buffer is available and dequeues it. The buffer
+  /// has to be handed back with `requeue` once the frame is consumed.
+  int dequeue(struct v4l2_buffer &buf) {
+    struct pollfd pfd = {fd, POLLIN, 0};
+    int ready = poll(&pfd, 1, CAPTURE_TIMEOUT_MS);
+    if (ready < 0) {
+      if (errno == EINTR) {
+        return EAGAIN;
+      }
+      error("poll on capture device failed: {}", strerror(errno));
+      return -1;
+    }
+
+    if (ready == 0) {
+      warn("no frame received within {}ms", CAPTURE_TIMEOUT_MS);
+      return EAGAIN;
+    }
+
+    memset(&buf, 0, sizeof(buf));
+    buf.type = V4L2_BUF_TYPE_VIDEO_CAPTURE;
+    buf.memory = V4L2_MEMORY_MMAP;
+    if (ioctl(fd, VIDIOC_DQBUF, &buf) < 0) {
+      if (errno == EAGAIN) {
+        return EAGAIN;
+      }
+      error("Could not dequeue buffer: {}", strerror(errno));
+      return -1;
+    }
+    return 0;
+  }
+
+  int requeue(struct v4l2_buffer &buf) {
+    if (ioctl(fd, VIDIOC_QBUF, &buf) < 0) {
+      error("Could not requeue buffer: {}", strerror(errno));
+      return -1;
+    }
+    return 0;
+  }
+
+  const uint8_t *data(const struct v4l2_buffer &buf) const {
+    return static_cast<const uint8_t *>(buffers[buf.index].start);
+  }
+
+  int width = 0;
+  int height = 0;
+  int bytes_per_line = 0;
+
+private:
+  int fd;
+  bool streaming = false;
+  std::vector<MappedBuffer> buffers;
+};
+
+/// Scans /proc/asound/cards for a sound card whose description contains
+/// `dev_name`. Capture cards usually register a USB audio interface with the
+/// same name as the video device.
+std::optional<std::string> find_audio_device(const std::string &dev_name) {
+  std::ifstream cards("/proc/asound/cards");
+  std::string line;
+  while (std::getline(cards, line)) {
+    // " 1 [S              ]: USB-Audio - Game Capture HD60 S+"
+    int index = -1;
+    if (std::sscanf(line.c_str(), " %d [", &index) != 1) {
+      continue;
+    }
+
+    if (line.find(dev_name) != std::string::npos) {
+      info("found audio input device: {}", line);
+      return std::format("hw:{}", index);
+    }
+  }
+
+  return std::nullopt;
+}
+
+/// Frames decoded from anything libavformat can open: a lavfi filter graph,
+/// a local file or an ALSA device. Video is converted to the requested size
+/// and pixel format, audio is passed on as decoded. Unless `realtime` is
+/// disabled, reading is paced to real-time so the output behaves like a live
+/// capture.
+class AvInputSource : public FrameSource {
+public:
+  AvInputSource() = default;
+  AvInputSource(const AvInputSource &) = delete;
+  AvInputSource &operator=(const AvInputSource &) = delete;
+
+  ~AvInputSource() override {
+    sws_freeContext(sws_ctx);
+    av_frame_free(&decoded);
+    av_packet_free(&packet);
+    avcodec_free_context(&video_decoder);
+    avcodec_free_context(&audio_decoder);
+    avformat_close_input(&input_ctx);
+  }
+
+  int open(const char *url, const AVInputFormat *format,
+           AVDictionary **options = nullptr, bool realtime = true) {
+    this->realtime = realtime;
+    if (avformat_open_input(&input_ctx, url, format, options) < 0) {
+      error("could not open input {}", url);
+      return -1;
+    }
+
+    if (avformat_find_stream_info(input_ctx, nullptr) < 0) {
+      error("could not find stream info of {}", url);
+      return -1;
+    }
+
+    video_index = open_decoder(AVMEDIA_TYPE_VIDEO, video_decoder);
+    audio_index = open_decoder(AVMEDIA_TYPE_AUDIO, audio_decoder);
+    if (video_index < 0 && audio_index < 0) {
+      error("no audio or video stream found in {}", url);
+      return -1;
+    }
+
+    packet = av_packet_alloc();
+    decoded = av_frame_alloc();
+    if (!packet || !decoded) {
+      return -1;
+    }
+
+    if (video_decoder) {
+      info("reading {} ({}x{})", url, video_decoder->width,
+           video_decoder->height);
+    }
+    if (audio_decoder) {
+      info("reading audio from {} ({}Hz)", url, audio_decoder->sample_rate);
+    }
+    return 0;
+  }
+
+  bool has_video() const { return video_decoder != nullptr; }
+
+  bool has_audio() const override { return audio_decoder != nullptr; }
+
+  int read(AVFrame *video, AVFrame *audio, AVMediaType &type,
+           int64_t &timestamp_us) override {
+    for (;;) {
+      if (video_decoder) {
+        int ret = avcodec_receive_frame(video_decoder, decoded);
+        if (ret == 0) {
+          type = AVMEDIA_TYPE_VIDEO;
+          ret = convert(video);
+          if (ret == 0) {
+            ret = stamp(decoded, video_index, timestamp_us);
+          }
+          av_frame_unref(decoded);
+          return ret;
+        }
+        if (ret != AVERROR(EAGAIN) && ret != AVERROR_EOF) {
+          return ret;
+        }
+      }
+
+      if (audio_decoder) {
+        int ret = avcodec_receive_frame(audio_decoder, audio);
+        if (ret == 0) {
+          type = AVMEDIA_TYPE_AUDIO;
+          return stamp(audio, audio_index, timestamp_us);
+        }
+        if (ret != AVERROR(EAGAIN) && ret != AVERROR_EOF) {
+          return ret;
+        }
+      }
+
+      if (draining) {
+        return AVERROR_EOF;
+      }
+
+      int ret = av_read_frame(input_ctx, packet);
+      if (ret == AVERROR_EOF) {
+        draining = true;
+        if (video_decoder) {
+          avcodec_send_packet(video_decoder, nullptr);
+        }
+        if (audio_decoder) {
+          avcodec_send_packet(audio_decoder, nullptr);
+        }
+        continue;
+      }
+
+      if (ret < 0) {
+        return ret;
+      }
+
+      ret = 0;
+      if (packet->stream_index == video_index) {
+        ret = avcodec_send_packet(video_decoder, packet);
+      } else if (packet->stream_index == audio_index) {
+        ret = avcodec_send_packet(audio_decoder, packet);
+      }
+      av_packet_unref(packet);
+      if (ret < 0) {
+        return ret;
+      }
+    }
+  }
+
+private:
+  int open_decoder(AVMediaType type, AVCodecContext *&decoder) {
+    const AVCodec *codec = nullptr;
+    int index = av_find_best_stream(input_ctx, type, -1, -1, &codec, 0);
+    if (index < 0 || !codec) {
+      return -1;
+    }
+
+    decoder = avcodec_alloc_context3(codec);
+    if (!decoder ||
+        avcodec_parameters_to_context(decoder,
+                                      input_ctx->streams[index]->codecpar) <
+            0 ||
+        avcodec_open2(decoder, codec, nullptr) < 0) {
+      error("could not open {} decoder", av_get_media_type_string(type));
+      avcodec_free_context(&decoder);
+      return -1;
+    }
+    return index;
+  }
+
+  int convert(AVFrame *video) {
+    sws_ctx = sws_getCachedContext(
+        sws_ctx, decoded->width, decoded->height,
+        static_cast<AVPixelFormat>(decoded->format), video->width,
+        video->height, static_cast<AVPixelFormat>(video->format),
+        SWS_BILINEAR, nullptr, nullptr, nullptr);
+    if (!sws_ctx) {
+      return AVERROR(ENOMEM);
+    }
+
+    sws_scale(sws_ctx, decoded->data, decoded->linesize, 0, decoded->height,
+              video->data, video->linesize);
+    return 0;
+  }
+
+  /// Converts the timestamp of `frame` to microseconds since the first frame
+  /// of any stream, so audio and video share the same origin.
+  int stamp(const AVFrame *frame, int stream_index, int64_t &timestamp_us) {
+    int64_t pts = frame->best_effort_timestamp;
+    if (pts == AV_NOPTS_VALUE) {
+      pts = 0;
+    }
+
+    int64_t pts_us = av_rescale_q(
+        pts, input_ctx->streams[stream_index]->time_base, AV_TIME_BASE_Q);
+    if (origin_us == AV_NOPTS_VALUE) {
+      origin_us = pts_us;
+      start_time = av_gettime_relative();
+    }
+
+    timestamp_us = std::max<int64_t>(0, pts_us - origin_us);
+    if (realtime) {
+      int64_t ahead = start_time + timestamp_us - av_gettime_relative();
+      if (ahead > 0) {
+        av_usleep(ahead);
+      }
+    }
+    return 0;
+  }
+
+  AVFormatContext *input_ctx = nullptr;
+  AVCodecContext *video_decoder = nullptr;
+  AVCodecContext *audio_decoder = nullptr;
+  SwsContext *sws_ctx = nullptr;
+  AVPacket *packet = nullptr;
+  AVFrame *decoded = nullptr;
+  int video_index = -1;
+  int audio_index = -1;
+  bool realtime = true;
+  bool draining = false;
+  int64_t origin_us = AV_NOPTS_VALUE;
+  int64_t start_time = 0;
+};
+
+/// Frames captured from a V4L2 device in YUYV, converted to the encoder's
+/// pixel format. Audio is optionally captured from an ALSA device on a
+/// separate thread. Both are stamped with the same monotonic clock.
+class V4l2Source : public FrameSource {
+public:
+  explicit V4l2Source(int fd) : capture(fd) {}
+
+  ~V4l2Source() override {
+    stop_audio.store(true);
+    if (audio_thread.joinable()) {
+      audio_thread.join();
+    }
+
+    for (auto &chunk : audio_queue) {
+      av_frame_free(&chunk.frame);
+    }
+    sws_freeContext(sws_ctx);
+  }
+
+  int open(int width, int height, int fps, const char *audio_device) {
+    if (capture.set_format(width, height, fps) < 0) {
+      return -1;
+    }
+
+    if (audio_device && audio_device[0] != '\0') {
+      audio = std::make_unique<AvInputSource>();
+      // the ALSA demuxer delivers samples as they come in, no pacing needed
+      if (audio->open(audio_device, av_find_input_format("alsa"), nullptr,
+                      false) < 0) {
+        warn("could not open audio device {}, recording without audio",
+             audio_device);
+        audio.reset();
+      }
+    }
+
+    start_time = av_gettime_relative();
+    if (capture.start_streaming() < 0) {
+      return -1;
+    }
+
+    if (audio) {
+      audio_thread = std::thread([this] { capture_audio(); });
+    }
+    return 0;
+  }
+
+  bool has_audio() const override { return audio != nullptr; }
+
+  int read(AVFrame *video, AVFrame *audio_frame, AVMediaType &type,
+           int64_t &timestamp_us) override {
+    {
+      std::lock_guard lock(audio_mutex);
+      if (!audio_queue.empty()) {
+        auto chunk = audio_queue.front();
+        audio_queue.pop_front();
+        av_frame_move_ref(audio_frame, chunk.frame);
+        av_frame_free(&chunk.frame);
+        type = AVMEDIA_TYPE_AUDIO;
+        timestamp_us = chunk.timestamp_us;
+        return 0;
+      }
+    }
+
+    struct v4l2_buffer buf;
+    int dequeued = capture.dequeue(buf);
+    if (dequeued == EAGAIN) {
+      return AVERROR(EAGAIN);
+    }
+    if (dequeued < 0) {
+      return AVERROR(EIO);
+    }
+
+    sws_ctx = sws_getCachedContext(
+        sws_ctx, capture.width, capture.height, AV_PIX_FMT_YUYV422,
+        video->width, video->height, static_cast<AVPixelFormat>(video->format),
+        SWS_BILINEAR, nullptr, nullptr, nullptr);
+    if (!sws_ctx) {
+      capture.requeue(buf);
+      return AVERROR(ENOMEM);
+    }
+
+    const uint8_t *src_data[4] = {capture.data(buf), nullptr, nullptr,
+                                  nullptr};
+    const int src_linesize[4] = {capture.bytes_per_line, 0, 0, 0};
+    sws_scale(sws_ctx, src_data, src_linesize, 0, capture.height, video->data,
+              video->linesize);
+
+    if (capture.requeue(buf) < 0) {
+      return AVERROR(EIO);
+    }
+
+    type = AVMEDIA_TYPE_VIDEO;
+    timestamp_us = av_gettime_relative() - start_time;
+    return 0;
+  }
+
+private:
+  struct AudioChunk {
+    AVFrame *frame;
+    int64_t timestamp_us;
+  };
+
+  /// Reads the ALSA device until the source is destroyed. Samples are
+  /// stamped on arrival so they share the clock of the video frames.
+  void capture_audio() {
+    AVFrame *unused = av_frame_alloc();
+    AVFrame *samples = av_frame_alloc();
+    while (!stop_audio.load()) {
+      AVMediaType type;
+      int64_t ignored = 0;
+      int ret = audio->read(unused, samples, type, ignored);
+      if (ret == AVERROR(EAGAIN)) {
+        continue;
+      }
+      if (ret < 0) {
+        error("audio capture stopped: {}", ret);
+        break;
+      }
+
+      // the samples were recorded before they were handed to us
+      int64_t duration_us = av_rescale(samples->nb_samples, AV_TIME_BASE,
+                                       samples->sample_rate);
+      AudioChunk chunk = {av_frame_alloc(), av_gettime_relative() -
+                                                start_time - duration_us};
+      av_frame_move_ref(chunk.frame, samples);
+
+      std::lock_guard lock(audio_mutex);
+      if (audio_queue.size() >= MAX_QUEUED_AUDIO) {
+        warn("dropping audio, encoder is falling behind");
+        av_frame_free(&audio_queue.front().frame);
+        audio_queue.pop_front();
+      }
+      audio_queue.push_back(chunk);
+    }
+    av_frame_free(&samples);
+    av_frame_free(&unused);
+  }
+
+  V4l2Capture capture;
+  SwsContext *sws_ctx = nullptr;
+  int64_t start_time = 0;
+  std::unique_ptr<AvInputSource> audio;
+  std::thread audio_thread;
+  std::atomic_bool stop_audio = false;
+  std::mutex audio_mutex;
+  std::deque<AudioChunk> audio_queue;
+};
+
+std::unique_ptr<FrameSource> open_frame_source(SourceKind kind,
+                                               const char *input,
+                                               const char *audio_device,
+                                               int width, int height, int fps) {
+  switch (kind) {
+  case SourceKind::Device: {
+    auto video_path = find_video_path(input);
+    if (!video_path) {
+      error("could not find {}", input);
+      return nullptr;
+    }
+
+    std::optional<std::string> alsa_device;
+    if (audio_device && strcmp(audio_device, AUTO_AUDIO_DEVICE) == 0) {
+      alsa_device = find_audio_device(input);
+      if (!alsa_device) {
+        warn("no audio interface found for {}", input);
+      }
+    } else if (audio_device) {
+      alsa_device = audio_device;
+    }
+
+    // Open the video device
+    int fd = ::open(video_path->c_str(), O_RDWR | O_NONBLOCK);
+    if (fd < 0) {
+      error("Failed to open {}: {}", video_path->c_str(), strerror(errno));
+      return nullptr;
+    }
+
+    auto source = std::make_unique<V4l2Source>(fd);
+    if (source->open(width, height, fps,
+                     alsa_device ? alsa_device->c_str() : nullptr) < 0) {
+      return nullptr;
+    }
+    return source;
+  }
+  case SourceKind::TestPattern: {
+    auto graph = std::format(
+        "testsrc=size={}x{}:rate={}[out0];sine=frequency={}:sample_rate={}[out1]",
+        width, height, fps, TEST_TONE_FREQUENCY, TEST_TONE_SAMPLE_RATE);
+    auto source = std::make_unique<AvInputSource>();
+    if (source->open(graph.c_str(), av_find_input_format("lavfi")) < 0) {
+      return nullptr;
+    }
+    return source;
+  }
+  case SourceKind::File: {
+    auto source = std::make_unique<AvInputSource>();
+    if (source->open(input, nullptr) < 0) {
+      return nullptr;
+    }
+    if (!source->has_video()) {
+      error("{} does not contain video", input);
+      return nullptr;
+    }
+    return source;
+  }
+  }
+
+  error("unknown capture source {}", static_cast<int32_t>(kind));
+  return nullptr;
+}
@@ -0,0 +1,53 @@
+#pragma once
+#include <cstdint>
+#include <memory>
+
+extern "C" {
+#include <libavutil/avutil.h>
+#include <libavutil/frame.h>
+}
+
+/// Audio device name that makes the grabber look up the sound card that
+/// belongs to the capture device.
+#define AUTO_AUDIO_DEVICE "auto"
+
+/// Where the grabber gets its frames from. Shared with the rust side, keep
+/// in sync with `CaptureSource` in screen_grabber.rs.
+enum class SourceKind : int32_t {
+  /// V4L2 capture card, looked up by the name it reports in sysfs
+  Device = 0,
+  /// lavfi test pattern with a sine tone, does not need any hardware
+  TestPattern = 1,
+  /// local video file, played back in real-time
+  File = 2,
+};
+
+/// Produces raw video frames and audio samples for the grabber.
+class FrameSource {
+public:
+  virtual ~FrameSource() = default;
+
+  /// Reads the next picture or chunk of audio samples, `type` tells which
+  /// one. Pictures are converted to the size and pixel format of `video`,
+  /// samples are written to `audio` in the format of the input.
+  /// `timestamp_us` is set to the presentation time in microseconds, audio
+  /// and video share the same origin.
+  ///
+  /// returns 0 on success, AVERROR(EAGAIN) when nothing is available yet,
+  /// AVERROR_EOF when the input ended and a negative value on errors.
+  virtual int read(AVFrame *video, AVFrame *audio, AVMediaType &type,
+                   int64_t &timestamp_us) = 0;
+
+  /// true when `read` delivers audio next to video
+  virtual bool has_audio() const = 0;
+};
+
+/// Opens the source described by `kind` and `input`. `audio_device` is the
+/// ALSA device recorded next to a capture card, `AUTO_AUDIO_DEVICE` looks up
+/// the card's own audio interface and nullptr or "" records no audio.
+/// `width`, `height` and `fps` are the requested capture parameters.
+/// returns nullptr on failure.
+std::unique_ptr<FrameSource> open_frame_source(SourceKind kind,
+                                               const char *input,
+                                               const char *audio_device,
+                                               int width, int height, int fps);
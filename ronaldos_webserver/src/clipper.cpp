@@ -0,0 +1,432 @@
+#include "tracing.hpp"
+#include <algorithm>
+#include <cstdint>
+#include <filesystem>
+#include <string>
+
+extern "C" {
+#include <libavcodec/avcodec.h>
+#include <libavformat/avformat.h>
+#include <libavutil/opt.h>
+#include <libswscale/swscale.h>
+}
+
+static const AVRational MICROSECONDS = {1, AV_TIME_BASE};
+
+/// Cuts a range of a recording into a HLS playlist of its own. Packets are
+/// copied from the keyframe at or before the start of the range. Accurate
+/// clips decode the video from there and encode it again from the first
+/// frame of the range. Audio is copied in both cases, its packets are short
+/// enough to cut anywhere.
+class Clipper {
+public:
+  ~Clipper() {
+    if (header_written) {
+      av_write_trailer(output_ctx);
+    }
+    sws_freeContext(sws_ctx);
+    av_frame_free(&converted);
+    av_frame_free(&decoded);
+    av_packet_free(&encoded);
+    av_packet_free(&packet);
+    avcodec_free_context(&encoder);
+    avcodec_free_context(&decoder);
+    avformat_free_context(output_ctx);
+    avformat_close_input(&input_ctx);
+  }
+
+  /// Opens `input` and picks its best video and audio stream, the other
+  /// streams are not read.
+  int open(const char *input) {
+    if (avformat_open_input(&input_ctx, input, nullptr, nullptr) < 0) {
+      debug("Could not open {}", input);
+      return 1;
+    }
+    if (avformat_find_stream_info(input_ctx, nullptr) < 0) {
+      error("Could not find stream information of {}", input);
+      return 2;
+    }
+
+    video = av_find_best_stream(input_ctx, AVMEDIA_TYPE_VIDEO, -1, -1, nullptr,
+                                0);
+    if (video < 0) {
+      debug("{} has no video", input);
+      return 3;
+    }
+    audio = av_find_best_stream(input_ctx, AVMEDIA_TYPE_AUDIO, -1, video,
+                                nullptr, 0);
+    for (unsigned i = 0; i < input_ctx->nb_streams; ++i) {
+      if (static_cast<int>(i) != video && static_cast<int>(i) != audio) {
+        input_ctx->streams[i]->discard = AVDISCARD_ALL;
+      }
+    }
+
+    packet = av_packet_alloc();
+    if (!packet) {
+      error("Could not allocate packet");
+      return 4;
+    }
+    return 0;
+  }
+
+  /// Creates the VOD playlist `output`, its segments are written next to
+  /// it.
+  int open_output(const char *output, int32_t segment_duration,
+                  bool accurate) {
+    std::filesystem::path playlist(output);
+    auto segment_pattern =
+        playlist.parent_path() / (playlist.stem().string() + "_%05d.ts");
+
+    avformat_alloc_output_context2(&output_ctx, nullptr, "hls", output);
+    if (!output_ctx) {
+      error("Could not create output context");
+      return 5;
+    }
+
+    video_out = avformat_new_stream(output_ctx, nullptr);
+    if (!video_out) {
+      error("Failed to create stream");
+      return 5;
+    }
+    if (accurate) {
+      int ret = open_encoder(segment_duration);
+      if (ret != 0) {
+        return ret;
+      }
+    } else {
+      avcodec_parameters_copy(video_out->codecpar,
+                              input_ctx->streams[video]->codecpar);
+      video_out->codecpar->codec_tag = 0;
+    }
+
+    if (audio >= 0) {
+      audio_out = avformat_new_stream(output_ctx, nullptr);
+      if (!audio_out) {
+        error("Failed to create stream");
+        return 5;
+      }
+      avcodec_parameters_copy(audio_out->codecpar,
+                              input_ctx->streams[audio]->codecpar);
+      audio_out->codecpar->codec_tag = 0;
+    }
+
+    AVDictionary *hls_options = nullptr;
+    av_dict_set_int(&hls_options, "hls_time", segment_duration, 0);
+    av_dict_set_int(&hls_options, "hls_list_size", 0, 0);
+    av_dict_set(&hls_options, "hls_playlist_type", "vod", 0);
+    av_dict_set(&hls_options, "hls_segment_filename", segment_pattern.c_str(),
+                0);
+
+    int ret = avformat_write_header(output_ctx, &hls_options);
+    av_dict_free(&hls_options);
+    if (ret < 0) {
+      error("Error occurred when writing header");
+      return 8;
+    }
+    header_written = true;
+    return 0;
+  }
+
+  /// Writes the range from `start` to `end` seconds of the input, counted
+  /// from its first timestamp. Returns the second the clip starts at in
+  /// `clip_start`.
+  int run(double start, double end, bool accurate, double *clip_start) {
+    int64_t origin =
+        input_ctx->start_time != AV_NOPTS_VALUE ? input_ctx->start_time : 0;
+    int64_t target = origin + static_cast<int64_t>(start * AV_TIME_BASE);
+    stop = origin + static_cast<int64_t>(end * AV_TIME_BASE);
+    if (start > 0) {
+      // the keyframe before the target, copying or decoding starts there
+      if (avformat_seek_file(input_ctx, -1, INT64_MIN, target, target, 0) <
+          0) {
+        debug("Could not seek to {}s", start);
+      }
+    }
+    // copied clips start at their first keyframe
+    cut = accurate ? target : AV_NOPTS_VALUE;
+
+    int ret = 0;
+    while (ret == 0 && !(video_done && audio_done())) {
+      if (av_read_frame(input_ctx, packet) < 0) {
+        break;
+      }
+      if (packet->stream_index == video) {
+        ret = accurate ? transcode(packet) : copy_video(packet);
+      } else if (packet->stream_index == audio) {
+        ret = copy_audio(packet);
+      }
+      av_packet_unref(packet);
+    }
+    if (ret == 0 && accurate) {
+      // frames the decoder and the encoder still hold
+      ret = transcode(nullptr);
+      if (ret == 0) {
+        ret = encode(nullptr);
+      }
+    }
+
+    if (av_write_trailer(output_ctx) < 0 && ret == 0) {
+      error("could not write trailer");
+      ret = 14;
+    }
+    header_written = false;
+    if (ret == 0 && cut == AV_NOPTS_VALUE) {
+      debug("no keyframe between {}s and {}s", start, end);
+      ret = 15;
+    }
+    if (ret == 0) {
+      *clip_start = (cut - origin) / static_cast<double>(AV_TIME_BASE);
+      info("clipped {}s from {}s", end - *clip_start, *clip_start);
+    }
+    return ret;
+  }
+
+private:
+  int open_encoder(int32_t segment_duration) {
+    AVStream *in = input_ctx->streams[video];
+    const AVCodec *codec = avcodec_find_decoder(in->codecpar->codec_id);
+    decoder = codec ? avcodec_alloc_context3(codec) : nullptr;
+    decoded = av_frame_alloc();
+    encoded = av_packet_alloc();
+    if (!decoder || !decoded || !encoded) {
+      error("Could not allocate decoder");
+      return 6;
+    }
+    avcodec_parameters_to_context(decoder, in->codecpar);
+    if (avcodec_open2(decoder, codec, nullptr) < 0) {
+      error("Could not open decoder");
+      return 6;
+    }
+
+    const AVCodec *h264 = avcodec_find_encoder(AV_CODEC_ID_H264);
+    encoder = h264 ? avcodec_alloc_context3(h264) : nullptr;
+    if (!encoder) {
+      error("Could not allocate encoder");
+      return 7;
+    }
+    AVRational fps = av_guess_frame_rate(input_ctx, in, nullptr);
+    if (fps.num <= 0 || fps.den <= 0) {
+      fps = AVRational{25, 1};
+    }
+    encoder->width = decoder->width;
+    encoder->height = decoder->height;
+    encoder->sample_aspect_ratio = decoder->sample_aspect_ratio;
+    encoder->pix_fmt = AV_PIX_FMT_YUV420P;
+    encoder->time_base = in->time_base;
+    encoder->framerate = fps;
+    // a keyframe per segment
+    encoder->gop_size =
+        std::max(1, static_cast<int>(av_q2d(fps) * segment_duration));
+    encoder->max_b_frames = 1;
+    av_opt_set(encoder->priv_data, "preset", "veryfast", 0);
+    if (output_ctx->oformat->flags & AVFMT_GLOBALHEADER) {
+      encoder->flags |= AV_CODEC_FLAG_GLOBAL_HEADER;
+    }
+    if (avcodec_open2(encoder, h264, nullptr) < 0) {
+      error("Could not open encoder for {}x{}", encoder->width,
+            encoder->height);
+      return 7;
+    }
+
+    avcodec_parameters_from_context(video_out->codecpar, encoder);
+    video_out->time_base = encoder->time_base;
+    return 0;
+  }
+
+  bool audio_done() const { return audio < 0 || audio_ended; }
+
+  /// `timestamp` of `stream` in microseconds
+  int64_t microseconds(int64_t timestamp, int stream) const {
+    return av_rescale_q(timestamp, input_ctx->streams[stream]->time_base,
+                        MICROSECONDS);
+  }
+
+  /// Moves the timestamps of `packet` to the start of the clip and writes
+  /// it to `out`.
+  int write(AVPacket *packet, AVStream *out) {
+    AVStream *in = input_ctx->streams[packet->stream_index];
+    int64_t shift = av_rescale_q(cut, MICROSECONDS, in->time_base);
+    if (packet->pts != AV_NOPTS_VALUE) {
+      packet->pts -= shift;
+    }
+    if (packet->dts != AV_NOPTS_VALUE) {
+      packet->dts -= shift;
+    }
+    av_packet_rescale_ts(packet, in->time_base, out->time_base);
+    packet->stream_index = out->index;
+    packet->pos = -1;
+    if (av_interleaved_write_frame(output_ctx, packet) < 0) {
+      error("Error writing packet");
+      return 13;
+    }
+    return 0;
+  }
+
+  int copy_video(AVPacket *packet) {
+    int64_t dts = packet->dts != AV_NOPTS_VALUE ? packet->dts : packet->pts;
+    if (video_done || dts == AV_NOPTS_VALUE) {
+      return 0;
+    }
+    if (cut == AV_NOPTS_VALUE) {
+      if (!(packet->flags & AV_PKT_FLAG_KEY)) {
+        return 0;
+      }
+      // decoding timestamps keep the copied timestamps positive
+      cut = microseconds(dts, video);
+    }
+    if (microseconds(dts, video) >= stop) {
+      video_done = true;
+      return 0;
+    }
+    return write(packet, video_out);
+  }
+
+  int copy_audio(AVPacket *packet) {
+    int64_t pts = packet->pts != AV_NOPTS_VALUE ? packet->pts : packet->dts;
+    if (audio_ended || cut == AV_NOPTS_VALUE || pts == AV_NOPTS_VALUE) {
+      return 0;
+    }
+    int64_t at = microseconds(pts, audio);
+    if (at >= stop) {
+      audio_ended = true;
+      return 0;
+    }
+    return at < cut ? 0 : write(packet, audio_out);
+  }
+
+  /// Encodes the frames of `packet` that are part of the clip, a null
+  /// packet drains the decoder.
+  int transcode(const AVPacket *packet) {
+    if (video_done && packet) {
+      return 0;
+    }
+    if (avcodec_send_packet(decoder, packet) < 0) {
+      debug("Error decoding packet");
+      return 0;
+    }
+
+    int ret;
+    while ((ret = avcodec_receive_frame(decoder, decoded)) == 0) {
+      int64_t pts = decoded->best_effort_timestamp;
+      int64_t at = pts == AV_NOPTS_VALUE ? cut : microseconds(pts, video);
+      video_done = video_done || at >= stop;
+      if (at < cut || video_done) {
+        av_frame_unref(decoded);
+        continue;
+      }
+
+      AVFrame *frame = decoded;
+      if (decoded->format != AV_PIX_FMT_YUV420P) {
+        ret = convert();
+        if (ret != 0) {
+          av_frame_unref(decoded);
+          return ret;
+        }
+        frame = converted;
+      }
+      frame->pts = av_rescale_q(at - cut, MICROSECONDS, encoder->time_base);
+      frame->pict_type = AV_PICTURE_TYPE_NONE;
+      ret = encode(frame);
+      av_frame_unref(decoded);
+      if (ret != 0) {
+        return ret;
+      }
+    }
+    return ret == AVERROR(EAGAIN) || ret == AVERROR_EOF ? 0 : 6;
+  }
+
+  /// Converts `decoded` to the pixel format of the encoder.
+  int convert() {
+    if (!converted) {
+      converted = av_frame_alloc();
+      if (!converted) {
+        error("Could not allocate frame");
+        return 9;
+      }
+      converted->format = encoder->pix_fmt;
+      converted->width = encoder->width;
+      converted->height = encoder->height;
+      if (av_frame_get_buffer(converted, 0) < 0) {
+        error("Could not allocate frame buffer");
+        return 9;
+      }
+    }
+    sws_ctx = sws_getCachedContext(
+        sws_ctx, decoded->width, decoded->height,
+        static_cast<AVPixelFormat>(decoded->format), converted->width,
+        converted->height, encoder->pix_fmt, SWS_BICUBIC, nullptr, nullptr,
+        nullptr);
+    if (!sws_ctx || av_frame_make_writable(converted) < 0) {
+      error("Could not convert frame");
+      return 9;
+    }
+    sws_scale(sws_ctx, decoded->data, decoded->linesize, 0, decoded->height,
+              converted->data, converted->linesize);
+    return 0;
+  }
+
+  /// Hands `frame` to the encoder and writes the packets it has ready, a
+  /// null frame flushes the encoder.
+  int encode(AVFrame *frame) {
+    if (avcodec_send_frame(encoder, frame) < 0) {
+      error("Error sending frame to encoder");
+      return 10;
+    }
+
+    int ret;
+    while ((ret = avcodec_receive_packet(encoder, encoded)) == 0) {
+      av_packet_rescale_ts(encoded, encoder->time_base, video_out->time_base);
+      encoded->stream_index = video_out->index;
+      if (av_interleaved_write_frame(output_ctx, encoded) < 0) {
+        error("Error writing packet");
+        return 13;
+      }
+    }
+    return ret == AVERROR(EAGAIN) || ret == AVERROR_EOF ? 0 : 11;
+  }
+
+  AVFormatContext *input_ctx = nullptr;
+  AVFormatContext *output_ctx = nullptr;
+  AVCodecContext *decoder = nullptr;
+  AVCodecContext *encoder = nullptr;
+  AVPacket *packet = nullptr;
+  AVPacket *encoded = nullptr;
+  AVFrame *decoded = nullptr;
+  AVFrame *converted = nullptr;
+  SwsContext *sws_ctx = nullptr;
+  AVStream *video_out = nullptr;
+  AVStream *audio_out = nullptr;
+  int video = -1;
+  int audio = -1;
+  /// microseconds of the input the clip starts and ends at
+  int64_t cut = AV_NOPTS_VALUE;
+  int64_t stop = 0;
+  bool video_done = false;
+  bool audio_ended = false;
+  bool header_written = false;
+};
+
+extern "C" {
+/// Writes the range from `*start` to `end` seconds of `input` as VOD
+/// playlist `output`, with segments of about `segment_duration` seconds.
+/// Unless `accurate` is set the clip starts at the keyframe before `*start`,
+/// on success `*start` holds the second the clip starts at.
+int clipper_clip(const char *input, const char *output, double *start,
+                 double end, int32_t segment_duration, int32_t accurate) {
+  if (!input || !output || !start || *start < 0 || end <= *start ||
+      segment_duration <= 0) {
+    error("invalid clip arguments");
+    return -1;
+  }
+
+  Clipper clipper;
+  int ret = clipper.open(input);
+  if (ret == 0) {
+    ret = clipper.open_output(output, segment_duration, accurate != 0);
+  }
+  if (ret == 0) {
+    ret = clipper.run(*start, end, accurate != 0, start);
+  }
+  return ret;
+}
+}
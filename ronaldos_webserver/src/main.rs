@@ -4,8 +4,11 @@ mod services;
 mod tls_config;
 pub mod ffi_log;
 
-use crate::middleware::screen_grabber::ScreenGrabber;
-use crate::middleware::{FootballApi, SessionMananger};
+use crate::middleware::screen_grabber::{CaptureSettings, CaptureSource, ScreenGrabber};
+use crate::middleware::{
+    FootballApi, Recorder, RecordingScheduler, RtmpServer, SessionMananger, SrtIngest,
+};
+use crate::services::admin_service::admin_service_config;
 use crate::services::authentication_service::RonaldoAuthentication;
 use crate::services::fixture_service::fixture_service_config;
 use crate::services::redirect_service::RedirectScheme;
@@ -51,15 +54,58 @@ fn main() -> anyhow::Result<()> {
 
 async fn application_main(config: web::Data<Config>) -> anyhow::Result<()> {
     let video_dir = config.video_dir().to_path_buf();
-    let screen = ScreenGrabber::new("Game Capture HD60 S+".to_string(), video_dir.clone())?;
-    let recordings_disk = LocalStreamStore::new(video_dir, PathBuf::from_str(STREAM_SCOPE)?);
+    let screen = ScreenGrabber::new(
+        CaptureSource::Device(config.capture().device.clone()),
+        video_dir.clone(),
+        CaptureSettings::try_from(config.capture())?,
+    )?;
+    let recordings_disk = LocalStreamStore::new(video_dir, PathBuf::from_str(STREAM_SCOPE)?)
+        .with_catalog(config.catalog())?;
     let stream_store = web::Data::new(RwLock::new(recordings_disk));
-    LocalStreamStore::run(&stream_store).await;
+    LocalStreamStore::run(&stream_store, config.file_watch()).await;
+    LocalStreamStore::run_retention(
+        stream_store.clone().into_inner(),
+        config.retention().clone(),
+    );
+
+    if !config.rtmp().keys.is_empty() {
+        let rtmp_address: SocketAddr = format!("{}:{}", config.host(), config.rtmp().port)
+            .parse()
+            .context("could not parse rtmp address")?;
+        RtmpServer::new(
+            config.rtmp(),
+            config.video_dir().clone(),
+            stream_store.clone().into_inner(),
+        )?
+        .run(rtmp_address)
+        .await?;
+    }
+
+    for endpoint in &config.srt().endpoints {
+        SrtIngest::new(
+            config.srt(),
+            endpoint,
+            config.video_dir().clone(),
+            stream_store.clone().into_inner(),
+        )?
+        .run();
+    }
 
     let cert_store = Arc::new(native_cert_store());
     let football_api = web::Data::new(
         FootballApi::new("2024", "1857", config.api_key().clone(), cert_store).await,
     );
+    LocalStreamStore::run_markers(
+        stream_store.clone().into_inner(),
+        football_api.clone().into_inner(),
+    );
+    let recorder = web::Data::new(Recorder::new(screen, stream_store.clone().into_inner()));
+    RecordingScheduler::new(
+        recorder.clone().into_inner(),
+        football_api.clone().into_inner(),
+        config.schedule(),
+    )
+    .run();
 
     let viewer_credentials_set = !config.login().username.is_empty();
     let session_mananger = viewer_credentials_set.then(|| SessionMananger::new(config.login()));
@@ -78,6 +124,7 @@ async fn application_main(config: web::Data<Config>) -> anyhow::Result<()> {
             .wrap(RedirectScheme::new(tls_enabled))
             .configure(|cfg| stream_service_config(cfg, stream_store.clone()))
             .configure(|cfg| fixture_service_config(cfg, football_api.clone()))
+            .configure(|cfg| admin_service_config(cfg, recorder.clone(), stream_store.clone()))
             .default_service(
                 Files::new("/", cfg.www_dir()).index_file(index_file.to_string_lossy()),
             )
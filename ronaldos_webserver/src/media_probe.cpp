@@ -0,0 +1,78 @@
+#include "tracing.hpp"
+#include <cstdint>
+#include <cstring>
+
+extern "C" {
+#include <libavcodec/avcodec.h>
+#include <libavformat/avformat.h>
+}
+
+/// Filled in by `media_probe`, zeroed or empty fields are unknown. Has to
+/// match `RawMediaInfo` in media_probe.rs.
+struct MediaInfo {
+  double duration;
+  int64_t bit_rate;
+  int32_t width;
+  int32_t height;
+  char video_codec[32];
+  char audio_codec[32];
+};
+
+struct FormatContext {
+  ~FormatContext() { avformat_close_input(&ctx); }
+
+  AVFormatContext *ctx = nullptr;
+};
+
+static void copy_codec_name(const AVStream *stream, char (&name)[32]) {
+  const char *codec = avcodec_get_name(stream->codecpar->codec_id);
+  std::strncpy(name, codec, sizeof(name) - 1);
+  name[sizeof(name) - 1] = '\0';
+}
+
+extern "C" {
+/// Reads the container of `input` and the parameters of its best video and
+/// audio stream into `info`.
+int media_probe(const char *input, MediaInfo *info) {
+  if (!input || !info) {
+    error("invalid probe arguments");
+    return -1;
+  }
+  std::memset(info, 0, sizeof(MediaInfo));
+
+  FormatContext format;
+  if (avformat_open_input(&format.ctx, input, nullptr, nullptr) < 0) {
+    debug("Could not open {}", input);
+    return 1;
+  }
+  if (avformat_find_stream_info(format.ctx, nullptr) < 0) {
+    debug("Could not find stream information of {}", input);
+    return 2;
+  }
+
+  if (format.ctx->duration > 0) {
+    info->duration = format.ctx->duration / static_cast<double>(AV_TIME_BASE);
+  }
+  info->bit_rate = format.ctx->bit_rate;
+
+  int video = av_find_best_stream(format.ctx, AVMEDIA_TYPE_VIDEO, -1, -1,
+                                  nullptr, 0);
+  if (video >= 0) {
+    const AVStream *stream = format.ctx->streams[video];
+    info->width = stream->codecpar->width;
+    info->height = stream->codecpar->height;
+    copy_codec_name(stream, info->video_codec);
+  }
+  int audio = av_find_best_stream(format.ctx, AVMEDIA_TYPE_AUDIO, -1, video,
+                                  nullptr, 0);
+  if (audio >= 0) {
+    copy_codec_name(format.ctx->streams[audio], info->audio_codec);
+  }
+
+  if (video < 0 && audio < 0) {
+    debug("{} has neither video nor audio", input);
+    return 3;
+  }
+  return 0;
+}
+}
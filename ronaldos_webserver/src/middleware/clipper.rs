@@ -0,0 +1,56 @@
+//! Clips of recordings, cut by the native clipper in clipper.cpp. Cutting
+//! reads and writes the whole range, so it has to run on a blocking thread.
+use anyhow::ensure;
+use std::{
+    ffi::{c_char, CString},
+    os::unix::ffi::OsStrExt,
+    path::Path,
+};
+
+/// Seconds per segment of a clip
+const SEGMENT_DURATION: i32 = 4;
+
+extern "C" {
+    fn clipper_clip(
+        input: *const c_char,
+        output: *const c_char,
+        start: *mut f64,
+        end: f64,
+        segment_duration: i32,
+        accurate: i32,
+    ) -> i32;
+}
+
+/// Writes the range from `start` to `end` seconds of the playlist or
+/// container `input` as VOD playlist `output`. The packets are copied from
+/// the keyframe before `start`, `accurate` clips encode the video again to
+/// start at the exact frame. Returns the second of `input` the clip starts
+/// at.
+pub fn clip(
+    input: &Path,
+    output: &Path,
+    start: f64,
+    end: f64,
+    accurate: bool,
+) -> anyhow::Result<f64> {
+    let input_c = CString::new(input.as_os_str().as_bytes())?;
+    let output_c = CString::new(output.as_os_str().as_bytes())?;
+    let mut clip_start = start;
+    let status = unsafe {
+        clipper_clip(
+            input_c.as_ptr(),
+            output_c.as_ptr(),
+            &mut clip_start,
+            end,
+            SEGMENT_DURATION,
+            accurate.into(),
+        )
+    };
+    ensure!(
+        status == 0,
+        "could not clip {} ({})",
+        input.to_string_lossy(),
+        status
+    );
+    Ok(clip_start)
+}
@@ -1,13 +1,13 @@
 use actix_web::http::{self};
 use anyhow::{Context, Result};
-use chrono::{serde::ts_seconds, DateTime, Utc};
+use chrono::{serde::ts_seconds, DateTime, TimeDelta, Utc};
 use rustls::ClientConfig;
 use rustls::RootCertStore;
 use serde::{Deserialize, Serialize};
 use simd_json::{json, owned::Value};
 use simd_json::prelude::{ValueAsScalar, ValueAsContainer};
 use std::{collections::HashMap, io::Write, str::FromStr, sync::Arc};
-use tokio::sync::RwLock;
+use tokio::sync::{RwLock, RwLockReadGuard};
 use tracing::{debug, info};
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -21,6 +21,67 @@ pub struct Fixture {
     timestamp: DateTime<Utc>,
 }
 
+impl Fixture {
+    pub fn kickoff(&self) -> DateTime<Utc> {
+        self.timestamp
+    }
+
+    /// "home - away", used as description of recordings
+    pub fn title(&self) -> String {
+        format!("{} - {}", self.home, self.away)
+    }
+}
+
+/// Periods and events of a single fixture.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FixtureTimeline {
+    pub kickoff: DateTime<Utc>,
+    /// start of the halves, once they started
+    pub first_half: Option<DateTime<Utc>>,
+    pub second_half: Option<DateTime<Utc>>,
+    pub events: Vec<FixtureEvent>,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct FixtureEvent {
+    /// minute of the match, `extra` counts the minutes of stoppage time
+    pub elapsed: u32,
+    pub extra: Option<u32>,
+    pub team: String,
+    pub player: Option<String>,
+    /// "Goal", "Card", "subst" or "Var"
+    pub kind: String,
+    /// e.g. "Normal Goal", "Penalty" or "Yellow Card"
+    pub detail: String,
+}
+
+impl FixtureEvent {
+    /// Wall clock time of the event, counted from the start of its half.
+    /// Halves that have no start yet are assumed to follow the schedule,
+    /// extra time is counted as part of the second half.
+    pub fn time(&self, timeline: &FixtureTimeline) -> DateTime<Utc> {
+        let (start, first_minute) = match self.elapsed {
+            0..=45 => (timeline.first_half.unwrap_or(timeline.kickoff), 0),
+            _ => (
+                timeline
+                    .second_half
+                    .unwrap_or(timeline.kickoff + TimeDelta::minutes(60)),
+                45,
+            ),
+        };
+        let minutes = self.elapsed.saturating_sub(first_minute) + self.extra.unwrap_or(0);
+        start + TimeDelta::minutes(minutes.into())
+    }
+
+    /// "45+2'", the minute as shown on a scoreboard
+    pub fn minute(&self) -> String {
+        match self.extra {
+            Some(extra) if extra > 0 => format!("{}+{}'", self.elapsed, extra),
+            _ => format!("{}'", self.elapsed),
+        }
+    }
+}
+
 pub struct FootballApi {
     /// map of league name as key and Fixture as item
     cache: RwLock<HashMap<String, Vec<Value>>>,
@@ -29,6 +90,47 @@ pub struct FootballApi {
     cert_store: Arc<RootCertStore>,
 }
 
+/// The parts of a fixture of the api that make up a [FixtureTimeline]
+#[derive(Deserialize)]
+struct ApiFixture {
+    fixture: ApiFixtureInfo,
+    #[serde(default)]
+    events: Vec<ApiEvent>,
+}
+
+#[derive(Deserialize)]
+struct ApiFixtureInfo {
+    timestamp: i64,
+    periods: ApiPeriods,
+}
+
+#[derive(Deserialize)]
+struct ApiPeriods {
+    first: Option<i64>,
+    second: Option<i64>,
+}
+
+#[derive(Deserialize)]
+struct ApiEvent {
+    time: ApiTime,
+    team: ApiName,
+    player: ApiName,
+    #[serde(rename = "type")]
+    kind: String,
+    detail: String,
+}
+
+#[derive(Deserialize)]
+struct ApiTime {
+    elapsed: u32,
+    extra: Option<u32>,
+}
+
+#[derive(Deserialize)]
+struct ApiName {
+    name: Option<String>,
+}
+
 impl FootballApi {
     pub async fn new(
         season: &str,
@@ -51,16 +153,50 @@ impl FootballApi {
     }
 
     pub async fn fixtures<T: Write>(&self, writer: &mut T) -> Result<()> {
+        let cache = self.cached().await?;
+        Ok(simd_json::to_writer(writer, &*cache)?)
+    }
+
+    /// All fixtures of all leagues, in no particular order.
+    pub async fn fixture_list(&self) -> Result<Vec<Fixture>> {
+        let cache = self.cached().await?;
+        cache
+            .values()
+            .flatten()
+            .map(|fixture| {
+                simd_json::serde::from_refowned_value::<Fixture>(fixture)
+                    .with_context(|| format!("invalid fixture: {}", fixture))
+            })
+            .collect()
+    }
+
+    /// Periods and events of the fixture `fixture_id`, requested on every
+    /// call. None without api key or for unknown fixtures.
+    pub async fn fixture_timeline(&self, fixture_id: u64) -> Result<Option<FixtureTimeline>> {
+        if self.api_key.is_empty() {
+            return Ok(None);
+        }
+
+        let url = http::Uri::from_str(&format!(
+            "https://v3.football.api-sports.io/fixtures?id={}",
+            fixture_id
+        ))?;
+        let raw = self.football_api_request(&url).await?;
+        to_timeline(&raw)
+    }
+
+    /// Returns the cached fixtures, downloading them on first use.
+    async fn cached(&self) -> Result<RwLockReadGuard<'_, HashMap<String, Vec<Value>>>> {
         let mut cache = self.cache.read().await;
 
         if cache.is_empty() {
             if self.api_key.is_empty() {
                 info!("no football api key set. omitting fixture data");
-                return Ok(());
+                return Ok(cache);
             }
 
             debug!("cache not loaded yet, sending football request");
-            let raw = self.football_api_request().await?;
+            let raw = self.football_api_request(&self.url).await?;
             let map = to_data_model(raw).await?;
 
             // relock as write
@@ -69,10 +205,10 @@ impl FootballApi {
             cache = self.cache.read().await;
         }
 
-        Ok(simd_json::to_writer(writer, &*cache)?)
+        Ok(cache)
     }
 
-    async fn football_api_request(&self) -> anyhow::Result<Value> {
+    async fn football_api_request(&self, url: &http::Uri) -> anyhow::Result<Value> {
         debug!("downloading match data from football-api");
         let config = ClientConfig::builder()
             .with_root_certificates(self.cert_store.clone())
@@ -81,10 +217,14 @@ impl FootballApi {
             .connector(awc::Connector::new().rustls_0_23(Arc::new(config)))
             .finish();
         let request = client
-            .get(&self.url)
+            .get(url)
             .insert_header(("X-RapidAPI-Host", "api-football-v2.p.rapidapi.com"))
             .insert_header(("X-RapidAPI-Key", self.api_key.as_str()));
-        let mut res = request.send().await.unwrap();
+        // the errors of awc are not Send, they cannot be wrapped as context
+        let mut res = request
+            .send()
+            .await
+            .map_err(|e| anyhow::anyhow!("football-api request failed: {}", e))?;
         res.json::<Value>()
             .await
             .context("not a valid json reponse body")
@@ -126,3 +266,89 @@ async fn to_data_model(json: Value) -> Result<HashMap<String, Vec<Value>>> {
     }
     Ok(fixtures)
 }
+
+/// The timeline of the first fixture of a `fixtures?id=` response
+fn to_timeline(json: &Value) -> Result<Option<FixtureTimeline>> {
+    let response = json["response"]
+        .as_array()
+        .with_context(|| format!("response: {}", json))?;
+    let Some(fixture) = response.first() else {
+        return Ok(None);
+    };
+    let fixture = simd_json::serde::from_refowned_value::<ApiFixture>(fixture)
+        .with_context(|| format!("invalid fixture: {}", fixture))?;
+
+    let timestamp = |seconds: i64| {
+        DateTime::from_timestamp(seconds, 0).with_context(|| format!("invalid time {}", seconds))
+    };
+    let events = fixture
+        .events
+        .into_iter()
+        .map(|event| FixtureEvent {
+            elapsed: event.time.elapsed,
+            extra: event.time.extra,
+            team: event.team.name.unwrap_or_default(),
+            player: event.player.name,
+            kind: event.kind,
+            detail: event.detail,
+        })
+        .collect();
+
+    Ok(Some(FixtureTimeline {
+        kickoff: timestamp(fixture.fixture.timestamp)?,
+        first_half: fixture.fixture.periods.first.map(timestamp).transpose()?,
+        second_half: fixture.fixture.periods.second.map(timestamp).transpose()?,
+        events,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(json: &str) -> Value {
+        let mut bytes = json.as_bytes().to_vec();
+        simd_json::to_owned_value(&mut bytes).unwrap()
+    }
+
+    #[test]
+    fn timeline() {
+        let example = std::fs::read_to_string(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/../test_data/football_response_example.json"
+        ))
+        .unwrap();
+        let timeline = to_timeline(&parse(&example)).unwrap().unwrap();
+        assert_eq!(1662901200, timeline.kickoff.timestamp());
+        assert!(timeline.first_half.is_none());
+        assert!(timeline.events.is_empty());
+        assert!(to_timeline(&parse(r#"{"response": []}"#))
+            .unwrap()
+            .is_none());
+
+        let played = parse(
+            r#"{"response": [{
+                "fixture": {"timestamp": 1662901200, "periods": {"first": 1662901260, "second": 1662905100}},
+                "events": [
+                    {"time": {"elapsed": 45, "extra": 2}, "team": {"name": "Chelsea W"},
+                     "player": {"name": "S. Kerr"}, "type": "Goal", "detail": "Normal Goal"},
+                    {"time": {"elapsed": 60, "extra": null}, "team": {"name": "West Ham W"},
+                     "player": {"name": null}, "type": "Card", "detail": "Yellow Card"}
+                ]
+            }]}"#,
+        );
+        let timeline = to_timeline(&played).unwrap().unwrap();
+        let stoppage = &timeline.events[0];
+        assert_eq!("45+2'", stoppage.minute());
+        assert_eq!(
+            timeline.first_half.unwrap() + TimeDelta::minutes(47),
+            stoppage.time(&timeline)
+        );
+        let card = &timeline.events[1];
+        assert_eq!(None, card.player);
+        assert_eq!(
+            timeline.second_half.unwrap() + TimeDelta::minutes(15),
+            card.time(&timeline)
+        );
+    }
+}
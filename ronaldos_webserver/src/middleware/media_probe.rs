@@ -0,0 +1,72 @@
+//! Container and codec parameters of recordings, read by the native prober
+//! in media_probe.cpp. Probing reads the start of the media, so it has to
+//! run on a blocking thread.
+use anyhow::ensure;
+use std::{
+    ffi::{c_char, CStr, CString},
+    os::unix::ffi::OsStrExt,
+    path::Path,
+};
+
+#[repr(C)]
+struct RawMediaInfo {
+    duration: f64,
+    bit_rate: i64,
+    width: i32,
+    height: i32,
+    video_codec: [c_char; 32],
+    audio_codec: [c_char; 32],
+}
+
+extern "C" {
+    fn media_probe(input: *const c_char, info: *mut RawMediaInfo) -> i32;
+}
+
+/// What the container of a source tells about it, None where it does not.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MediaInfo {
+    /// seconds, unknown for live playlists
+    pub duration: Option<f64>,
+    /// bits per second
+    pub bitrate: Option<u64>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub video_codec: Option<String>,
+    pub audio_codec: Option<String>,
+}
+
+/// Probes the playlist or MP4 `input`.
+pub fn probe(input: &Path) -> anyhow::Result<MediaInfo> {
+    let input_c = CString::new(input.as_os_str().as_bytes())?;
+    let mut raw = RawMediaInfo {
+        duration: 0.0,
+        bit_rate: 0,
+        width: 0,
+        height: 0,
+        video_codec: [0; 32],
+        audio_codec: [0; 32],
+    };
+    let status = unsafe { media_probe(input_c.as_ptr(), &mut raw) };
+    ensure!(
+        status == 0,
+        "could not probe {} ({})",
+        input.to_string_lossy(),
+        status
+    );
+
+    let positive = |value: i64| u64::try_from(value).ok().filter(|v| *v > 0);
+    Ok(MediaInfo {
+        duration: (raw.duration > 0.0).then_some(raw.duration),
+        bitrate: positive(raw.bit_rate),
+        width: positive(raw.width.into()).map(|w| w as u32),
+        height: positive(raw.height.into()).map(|h| h as u32),
+        video_codec: codec_name(&raw.video_codec),
+        audio_codec: codec_name(&raw.audio_codec),
+    })
+}
+
+fn codec_name(name: &[c_char; 32]) -> Option<String> {
+    // the native side always terminates the name
+    let name = unsafe { CStr::from_ptr(name.as_ptr()) };
+    Some(name.to_string_lossy().into_owned()).filter(|n| !n.is_empty())
+}
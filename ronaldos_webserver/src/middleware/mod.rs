@@ -1,8 +1,20 @@
+mod clipper;
 mod football_info;
+mod media_probe;
+mod recorder;
+mod recording_scheduler;
+mod rtmp;
 pub mod screen_grabber;
 mod session_manager;
+mod srt;
+mod stream_remuxer;
 mod stream_store;
+mod thumbnailer;
 
 pub use football_info::*;
+pub use recorder::*;
+pub use recording_scheduler::*;
+pub use rtmp::RtmpServer;
 pub use session_manager::*;
+pub use srt::SrtIngest;
 pub use stream_store::*;
@@ -0,0 +1,285 @@
+use super::screen_grabber::{Recording, ScreenGrabber};
+use super::LocalStreamStore;
+use anyhow::{bail, Context};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    ffi::OsStr,
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+use tokio::sync::RwLock;
+use tracing::info;
+use uuid::Uuid;
+
+/// Owns the [ScreenGrabber] and keeps track of the recordings it is making.
+/// Recordings are identified by the uuid of the stream they are registered
+/// as in the [LocalStreamStore].
+pub struct Recorder {
+    grabber: ScreenGrabber,
+    stream_store: Arc<RwLock<LocalStreamStore>>,
+    active: Mutex<HashMap<Uuid, ActiveRecording>>,
+    /// one recording is stopped at a time, so none is finished while its
+    /// grabber is still being stopped
+    stopping: tokio::sync::Mutex<()>,
+}
+
+struct ActiveRecording {
+    /// taken once the grabber is stopped, the recording stays listed until
+    /// its stream is finished
+    recording: Option<Recording>,
+    playlist: PathBuf,
+    description: String,
+    fixture_id: Option<u64>,
+    started: DateTime<Utc>,
+}
+
+/// Progress of a recording as reported by [Recorder::list].
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct RecordingInfo {
+    pub uuid: Uuid,
+    pub description: String,
+    pub fixture_id: Option<u64>,
+    pub started: DateTime<Utc>,
+    pub duration_secs: i64,
+    /// number of segments written, counted over all renditions
+    pub segments: usize,
+    /// size of all playlists and segments written so far
+    pub bytes: u64,
+    /// true when the grabber stopped by itself, e.g. the device got lost, or
+    /// the recording failed to stop
+    pub finished: bool,
+}
+
+impl Recorder {
+    pub fn new(grabber: ScreenGrabber, stream_store: Arc<RwLock<LocalStreamStore>>) -> Self {
+        Recorder {
+            grabber,
+            stream_store,
+            active: Mutex::new(HashMap::new()),
+            stopping: tokio::sync::Mutex::new(()),
+        }
+    }
+
+    /// Starts a recording and registers it as live stream. Recordings of a
+    /// fixture are named after its date and id, others after the time they
+    /// started.
+    pub async fn start(
+        &self,
+        description: String,
+        date: DateTime<Utc>,
+        fixture_id: Option<u64>,
+    ) -> anyhow::Result<Uuid> {
+        let started = Utc::now();
+        let stem = match fixture_id {
+            Some(id) => format!("{}_{}", date.format("%Y%m%d"), id),
+            None => started.format("%Y%m%d_%H%M%S").to_string(),
+        };
+        let recording = self.grabber.start(&stem)?;
+
+        let registered = self
+            .stream_store
+            .read()
+            .await
+            .register_recording(&recording, description.clone(), date, fixture_id)
+            .await;
+        let uuid = match registered {
+            Ok(uuid) => uuid,
+            Err(e) => {
+                stop(recording).await?;
+                return Err(e).context("could not register recording");
+            }
+        };
+
+        info!("started recording {} as {}", description, uuid);
+        self.active.lock().unwrap().insert(
+            uuid,
+            ActiveRecording {
+                playlist: recording.playlist().to_path_buf(),
+                recording: Some(recording),
+                description,
+                fixture_id,
+                started,
+            },
+        );
+        Ok(uuid)
+    }
+
+    /// Stops the recording and turns its stream into VOD, returns false if
+    /// it is not known. The stream is finished even if the grabber fails to
+    /// stop. A recording whose stream fails to finish stays listed, and
+    /// stopping it again retries.
+    pub async fn stop(&self, uuid: &Uuid) -> anyhow::Result<bool> {
+        let _stopping = self.stopping.lock().await;
+        let recording = match self.active.lock().unwrap().get_mut(uuid) {
+            Some(active) => active.recording.take(),
+            None => return Ok(false),
+        };
+
+        info!("stopping recording {}", uuid);
+        let stopped = match recording {
+            Some(recording) => stop(recording).await,
+            None => Ok(()),
+        };
+        let finished = LocalStreamStore::finish_stream(&self.stream_store, uuid).await;
+        if finished.is_ok() {
+            self.active.lock().unwrap().remove(uuid);
+        }
+        match (stopped, finished) {
+            (Err(stopped), Err(finished)) => bail!("{:#}; {:#}", stopped, finished),
+            (stopped, finished) => stopped.and(finished).map(|()| true),
+        }
+    }
+
+    /// true while the recording is known and the grabber is still running
+    pub fn is_recording(&self, uuid: &Uuid) -> bool {
+        self.active
+            .lock()
+            .unwrap()
+            .get(uuid)
+            .and_then(|active| active.recording.as_ref())
+            .is_some_and(|recording| !recording.is_finished())
+    }
+
+    /// All recordings that have not been stopped yet, oldest first.
+    pub fn list(&self) -> Vec<RecordingInfo> {
+        let now = Utc::now();
+        let mut list = self
+            .active
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(uuid, active)| {
+                let (segments, bytes) = active
+                    .playlist
+                    .parent()
+                    .map(directory_stats)
+                    .unwrap_or_default();
+                RecordingInfo {
+                    uuid: *uuid,
+                    description: active.description.clone(),
+                    fixture_id: active.fixture_id,
+                    started: active.started,
+                    duration_secs: (now - active.started).num_seconds(),
+                    segments,
+                    bytes,
+                    finished: active.recording.as_ref().is_none_or(Recording::is_finished),
+                }
+            })
+            .collect::<Vec<_>>();
+        list.sort_by_key(|info| info.started);
+        list
+    }
+}
+
+/// Stopping joins the capture thread, which may take a moment.
+async fn stop(recording: Recording) -> anyhow::Result<()> {
+    tokio::task::spawn_blocking(move || recording.stop()).await?
+}
+
+/// Returns the number of .ts segments and the total size of the files in
+/// `dir`.
+fn directory_stats(dir: &Path) -> (usize, u64) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return (0, 0);
+    };
+
+    entries
+        .filter_map(Result::ok)
+        .filter_map(|entry| Some((entry.path(), entry.metadata().ok()?)))
+        .filter(|(_, meta)| meta.is_file())
+        .fold((0, 0), |(segments, bytes), (path, meta)| {
+            let is_segment = path.extension() == Some(OsStr::new("ts"));
+            (segments + usize::from(is_segment), bytes + meta.len())
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::middleware::{data_types::MetaFile, screen_grabber::*, stream_store::test_store};
+    use tempdir::TempDir;
+
+    #[test]
+    fn stats() {
+        let temp = TempDir::new("recorder").unwrap();
+        assert_eq!((0, 0), directory_stats(temp.path()));
+
+        fs::write(temp.path().join("match.m3u8"), b"#EXTM3U\n").unwrap();
+        fs::write(temp.path().join("match_720p_00000.ts"), [0u8; 188]).unwrap();
+        fs::write(temp.path().join("match_720p_00001.ts"), [0u8; 376]).unwrap();
+        fs::create_dir(temp.path().join("nested.ts")).unwrap();
+
+        assert_eq!((2, 8 + 188 + 376), directory_stats(temp.path()));
+        assert_eq!((0, 0), directory_stats(&temp.path().join("missing")));
+    }
+
+    fn active(recording: Option<Recording>, playlist: PathBuf) -> ActiveRecording {
+        ActiveRecording {
+            recording,
+            playlist,
+            description: "match".into(),
+            fixture_id: None,
+            started: Utc::now(),
+        }
+    }
+
+    #[tokio::test]
+    async fn stop_failed_grabber() {
+        let temp = TempDir::new("recorder").unwrap();
+        fs::create_dir(temp.path().join("match")).unwrap();
+        let playlist = temp.path().join("match").join("match.m3u8");
+        fs::write(
+            &playlist,
+            "#EXTM3U\n#EXT-X-TARGETDURATION:2\n#EXTINF:2.000000,\nmatch_00000.ts\n",
+        )
+        .unwrap();
+        let meta = MetaFile {
+            live: Some(true),
+            ..MetaFile::new("match", vec![PathBuf::from("match.m3u8")])
+        };
+        let meta_path = temp.path().join("match").join("match.stream");
+        let store = test_store(temp.path(), &[("match/match.stream", &meta)]);
+        let grabber = ScreenGrabber::new(
+            CaptureSource::TestPattern,
+            temp.path().to_path_buf(),
+            CaptureSettings::default(),
+        )
+        .unwrap();
+        let recorder = Recorder::new(grabber, Arc::new(RwLock::new(store)));
+
+        // the stream is finished although the grabber failed
+        let recording = Recording::exited(playlist.clone(), 1);
+        recorder
+            .active
+            .lock()
+            .unwrap()
+            .insert(meta.uuid, active(Some(recording), playlist.clone()));
+        assert!(recorder.stop(&meta.uuid).await.is_err());
+        let finished: MetaFile =
+            serde_yaml::from_reader(fs::File::open(&meta_path).unwrap()).unwrap();
+        assert_eq!(Some(false), finished.live);
+        assert!(fs::read_to_string(&playlist)
+            .unwrap()
+            .ends_with("#EXT-X-ENDLIST\n"));
+        assert!(recorder.list().is_empty());
+        assert!(!recorder.stop(&meta.uuid).await.unwrap());
+
+        // a stream that fails to finish stays listed to be stopped again
+        let unknown = Uuid::new_v4();
+        let recording = Recording::exited(playlist.clone(), 0);
+        recorder
+            .active
+            .lock()
+            .unwrap()
+            .insert(unknown, active(Some(recording), playlist));
+        assert!(recorder.stop(&unknown).await.is_err());
+        assert!(!recorder.is_recording(&unknown));
+        let list = recorder.list();
+        assert_eq!(1, list.len());
+        assert!(list[0].finished);
+        assert!(recorder.stop(&unknown).await.is_err());
+    }
+}
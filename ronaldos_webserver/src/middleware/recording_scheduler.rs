@@ -0,0 +1,156 @@
+use super::{Fixture, FootballApi, Recorder};
+use chrono::{DateTime, TimeDelta, Utc};
+use std::{collections::HashSet, sync::Arc, time::Duration};
+use tokio::task::JoinHandle;
+use tracing::{info, warn};
+
+/// How often the fixture list is checked for matches that need recording.
+const CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Records fixtures of the [FootballApi] with the [Recorder]. A recording
+/// starts `lead` before kickoff, stops `length` after kickoff and is
+/// registered together with its fixture id.
+pub struct RecordingScheduler {
+    recorder: Arc<Recorder>,
+    football_api: Arc<FootballApi>,
+    lead: TimeDelta,
+    length: TimeDelta,
+}
+
+impl RecordingScheduler {
+    pub fn new(
+        recorder: Arc<Recorder>,
+        football_api: Arc<FootballApi>,
+        schedule: &ronaldos_config::Schedule,
+    ) -> Self {
+        RecordingScheduler {
+            recorder,
+            football_api,
+            lead: TimeDelta::minutes(schedule.lead_minutes.into()),
+            length: TimeDelta::minutes(schedule.match_minutes.into()),
+        }
+    }
+
+    /// Spawns the task that checks the fixture list and records one fixture
+    /// at a time. A fixture is only recorded once, even if the recording
+    /// failed.
+    pub fn run(self) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut recorded = HashSet::new();
+            let mut interval = tokio::time::interval(CHECK_INTERVAL);
+            loop {
+                interval.tick().await;
+                let fixtures = match self.football_api.fixture_list().await {
+                    Ok(fixtures) => fixtures,
+                    Err(e) => {
+                        warn!("could not load fixtures: {:#}", e);
+                        continue;
+                    }
+                };
+
+                let Some(fixture) =
+                    next_due(&fixtures, Utc::now(), self.lead, self.length, &recorded)
+                else {
+                    continue;
+                };
+
+                recorded.insert(fixture.fixture_id);
+                if let Err(e) = self.record(fixture).await {
+                    warn!(
+                        "recording of fixture {} failed: {:#}",
+                        fixture.fixture_id, e
+                    );
+                }
+            }
+        })
+    }
+
+    /// Records `fixture` until its end time, until the grabber stops by
+    /// itself or until the recording is stopped through the admin api.
+    async fn record(&self, fixture: &Fixture) -> anyhow::Result<()> {
+        let end = fixture.kickoff() + self.length;
+        let uuid = self
+            .recorder
+            .start(
+                fixture.title(),
+                fixture.kickoff(),
+                Some(fixture.fixture_id.into()),
+            )
+            .await?;
+        info!("recording {} until {}", fixture.title(), end);
+
+        while self.recorder.is_recording(&uuid) {
+            let Ok(remaining) = (end - Utc::now()).to_std() else {
+                break;
+            };
+            tokio::time::sleep(remaining.min(CHECK_INTERVAL)).await;
+        }
+
+        info!("finished recording {}", fixture.title());
+        self.recorder.stop(&uuid).await.map(|_| ())
+    }
+}
+
+/// Returns the fixture that should be recording at `now` and has not been
+/// recorded yet.
+fn next_due<'a>(
+    fixtures: &'a [Fixture],
+    now: DateTime<Utc>,
+    lead: TimeDelta,
+    length: TimeDelta,
+    recorded: &HashSet<u32>,
+) -> Option<&'a Fixture> {
+    fixtures
+        .iter()
+        .filter(|f| !recorded.contains(&f.fixture_id))
+        .find(|f| f.kickoff() - lead <= now && now < f.kickoff() + length)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fixture(fixture_id: u32, kickoff: DateTime<Utc>) -> Fixture {
+        serde_yaml::from_str(&format!(
+            "{{fixture_id: {}, score: '', home: PSV, away: Ajax, venue: Philips Stadion, timestamp: {}}}",
+            fixture_id,
+            kickoff.timestamp()
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn due_fixtures() {
+        let now = Utc::now();
+        let lead = TimeDelta::minutes(10);
+        let length = TimeDelta::minutes(120);
+        let fixtures = vec![
+            fixture(1, now - TimeDelta::days(7)),
+            fixture(2, now + TimeDelta::minutes(5)),
+            fixture(3, now + TimeDelta::days(7)),
+        ];
+
+        let mut recorded = HashSet::new();
+        let due = next_due(&fixtures, now, lead, length, &recorded).unwrap();
+        assert_eq!(2, due.fixture_id);
+        assert_eq!("PSV - Ajax", due.title());
+
+        recorded.insert(2);
+        assert!(next_due(&fixtures, now, lead, length, &recorded).is_none());
+    }
+
+    #[test]
+    fn due_window() {
+        let kickoff = Utc::now();
+        let lead = TimeDelta::minutes(10);
+        let length = TimeDelta::minutes(120);
+        let fixtures = vec![fixture(1, kickoff)];
+        let recorded = HashSet::new();
+        let due_at = |now| next_due(&fixtures, now, lead, length, &recorded).is_some();
+
+        assert!(!due_at(kickoff - TimeDelta::minutes(11)));
+        assert!(due_at(kickoff - lead));
+        assert!(due_at(kickoff + TimeDelta::minutes(119)));
+        assert!(!due_at(kickoff + length));
+    }
+}
@@ -0,0 +1,218 @@
+//! The subset of AMF0 used by RTMP commands.
+use std::io;
+
+const NUMBER: u8 = 0x00;
+const BOOLEAN: u8 = 0x01;
+const STRING: u8 = 0x02;
+const OBJECT: u8 = 0x03;
+const NULL: u8 = 0x05;
+const UNDEFINED: u8 = 0x06;
+const ECMA_ARRAY: u8 = 0x08;
+const OBJECT_END: u8 = 0x09;
+const STRICT_ARRAY: u8 = 0x0A;
+const LONG_STRING: u8 = 0x0C;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Amf0Value {
+    Number(f64),
+    Boolean(bool),
+    String(String),
+    /// objects and ECMA arrays, in the order they were encoded
+    Object(Vec<(String, Amf0Value)>),
+    Array(Vec<Amf0Value>),
+    Null,
+    Undefined,
+}
+
+impl Amf0Value {
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Amf0Value::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn object<'a>(properties: impl IntoIterator<Item = (&'a str, Amf0Value)>) -> Self {
+        Amf0Value::Object(
+            properties
+                .into_iter()
+                .map(|(key, value)| (key.to_string(), value))
+                .collect(),
+        )
+    }
+
+    pub fn string(s: &str) -> Self {
+        Amf0Value::String(s.to_string())
+    }
+
+    /// Decodes one value and advances `data` past it.
+    pub fn decode(data: &mut &[u8]) -> io::Result<Self> {
+        let marker = take::<1>(data)?[0];
+        Ok(match marker {
+            NUMBER => Amf0Value::Number(f64::from_be_bytes(take(data)?)),
+            BOOLEAN => Amf0Value::Boolean(take::<1>(data)?[0] != 0),
+            STRING => Amf0Value::String(decode_string(data)?),
+            LONG_STRING => {
+                let len = u32::from_be_bytes(take(data)?) as usize;
+                Amf0Value::String(utf8(take_slice(data, len)?)?)
+            }
+            OBJECT => Amf0Value::Object(decode_properties(data)?),
+            ECMA_ARRAY => {
+                // the count is only a hint, the end marker terminates the list
+                take::<4>(data)?;
+                Amf0Value::Object(decode_properties(data)?)
+            }
+            STRICT_ARRAY => {
+                let count = u32::from_be_bytes(take(data)?);
+                let values = (0..count)
+                    .map(|_| Amf0Value::decode(data))
+                    .collect::<io::Result<_>>()?;
+                Amf0Value::Array(values)
+            }
+            NULL => Amf0Value::Null,
+            UNDEFINED => Amf0Value::Undefined,
+            marker => return Err(invalid(format!("unsupported amf0 marker {}", marker))),
+        })
+    }
+
+    /// Decodes values until `data` is exhausted.
+    pub fn decode_all(mut data: &[u8]) -> io::Result<Vec<Self>> {
+        let mut values = Vec::new();
+        while !data.is_empty() {
+            values.push(Amf0Value::decode(&mut data)?);
+        }
+        Ok(values)
+    }
+
+    pub fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            Amf0Value::Number(n) => {
+                out.push(NUMBER);
+                out.extend(n.to_be_bytes());
+            }
+            Amf0Value::Boolean(b) => out.extend([BOOLEAN, u8::from(*b)]),
+            Amf0Value::String(s) if s.len() > u16::MAX as usize => {
+                out.push(LONG_STRING);
+                out.extend((s.len() as u32).to_be_bytes());
+                out.extend(s.as_bytes());
+            }
+            Amf0Value::String(s) => {
+                out.push(STRING);
+                encode_string(s, out);
+            }
+            Amf0Value::Object(properties) => {
+                out.push(OBJECT);
+                for (key, value) in properties {
+                    encode_string(key, out);
+                    value.encode(out);
+                }
+                out.extend([0, 0, OBJECT_END]);
+            }
+            Amf0Value::Array(values) => {
+                out.push(STRICT_ARRAY);
+                out.extend((values.len() as u32).to_be_bytes());
+                for value in values {
+                    value.encode(out);
+                }
+            }
+            Amf0Value::Null => out.push(NULL),
+            Amf0Value::Undefined => out.push(UNDEFINED),
+        }
+    }
+}
+
+/// Encodes a sequence of values, e.g. the name, transaction id and
+/// arguments of a command.
+pub fn encode_all(values: &[Amf0Value]) -> Vec<u8> {
+    let mut out = Vec::new();
+    for value in values {
+        value.encode(&mut out);
+    }
+    out
+}
+
+fn decode_properties(data: &mut &[u8]) -> io::Result<Vec<(String, Amf0Value)>> {
+    let mut properties = Vec::new();
+    loop {
+        let key = decode_string(data)?;
+        if key.is_empty() && data.first() == Some(&OBJECT_END) {
+            *data = &data[1..];
+            return Ok(properties);
+        }
+        properties.push((key, Amf0Value::decode(data)?));
+    }
+}
+
+fn decode_string(data: &mut &[u8]) -> io::Result<String> {
+    let len = u16::from_be_bytes(take(data)?) as usize;
+    utf8(take_slice(data, len)?)
+}
+
+fn encode_string(s: &str, out: &mut Vec<u8>) {
+    out.extend((s.len() as u16).to_be_bytes());
+    out.extend(s.as_bytes());
+}
+
+fn take<const N: usize>(data: &mut &[u8]) -> io::Result<[u8; N]> {
+    let bytes = take_slice(data, N)?;
+    Ok(bytes.try_into().expect("slice has length N"))
+}
+
+fn take_slice<'a>(data: &mut &'a [u8], len: usize) -> io::Result<&'a [u8]> {
+    if data.len() < len {
+        return Err(invalid("truncated amf0 value".to_string()));
+    }
+    let (head, tail) = data.split_at(len);
+    *data = tail;
+    Ok(head)
+}
+
+fn utf8(bytes: &[u8]) -> io::Result<String> {
+    String::from_utf8(bytes.to_vec()).map_err(|e| invalid(e.to_string()))
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let values = vec![
+            Amf0Value::string("connect"),
+            Amf0Value::Number(1.0),
+            Amf0Value::object([
+                ("app", Amf0Value::string("show")),
+                ("fpad", Amf0Value::Boolean(false)),
+                ("audioCodecs", Amf0Value::Number(3575.0)),
+            ]),
+            Amf0Value::Null,
+            Amf0Value::Undefined,
+            Amf0Value::Array(vec![Amf0Value::Number(2.0)]),
+        ];
+
+        let encoded = encode_all(&values);
+        assert_eq!(values, Amf0Value::decode_all(&encoded).unwrap());
+    }
+
+    #[test]
+    fn ecma_array_and_errors() {
+        // onMetaData as sent by ffmpeg, with a count that does not match
+        let mut data = vec![ECMA_ARRAY, 0, 0, 0, 9];
+        data.extend([0, 5]);
+        data.extend(b"width");
+        data.push(NUMBER);
+        data.extend(1920f64.to_be_bytes());
+        data.extend([0, 0, OBJECT_END]);
+        assert_eq!(
+            vec![Amf0Value::object([("width", Amf0Value::Number(1920.0))])],
+            Amf0Value::decode_all(&data).unwrap()
+        );
+
+        assert!(Amf0Value::decode_all(&[STRING, 0, 10, b'a']).is_err());
+        assert!(Amf0Value::decode_all(&[0x11]).is_err());
+    }
+}
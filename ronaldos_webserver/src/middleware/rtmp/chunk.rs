@@ -0,0 +1,369 @@
+//! RTMP chunk stream: messages are split into chunks that are interleaved on
+//! the connection. Headers are compressed against the previous chunk of the
+//! same chunk stream.
+use std::{collections::HashMap, io};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// Chunk size both sides start with.
+pub const DEFAULT_CHUNK_SIZE: usize = 128;
+const EXTENDED_TIMESTAMP: u32 = 0xFF_FFFF;
+/// Largest AMF command accepted before the publisher is authenticated.
+const UNAUTHENTICATED_COMMAND_SIZE: usize = 16 * 1024;
+/// Largest message of any other type accepted before the publisher is
+/// authenticated.
+const UNAUTHENTICATED_MESSAGE_SIZE: usize = 4 * 1024;
+/// Bytes of partially read messages buffered over all chunk streams.
+const MAX_BUFFERED: usize = 32 * 1024 * 1024;
+
+pub mod message_type {
+    pub const SET_CHUNK_SIZE: u8 = 1;
+    pub const ACKNOWLEDGEMENT: u8 = 3;
+    pub const WINDOW_ACK_SIZE: u8 = 5;
+    pub const SET_PEER_BANDWIDTH: u8 = 6;
+    pub const AUDIO: u8 = 8;
+    pub const VIDEO: u8 = 9;
+    pub const DATA_AMF0: u8 = 18;
+    pub const COMMAND_AMF0: u8 = 20;
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Message {
+    pub type_id: u8,
+    pub stream_id: u32,
+    /// milliseconds
+    pub timestamp: u32,
+    pub payload: Vec<u8>,
+}
+
+/// Header state of a chunk stream, later chunks only send what changed.
+#[derive(Default)]
+struct ChunkStream {
+    timestamp: u32,
+    delta: u32,
+    length: usize,
+    type_id: u8,
+    stream_id: u32,
+    extended: bool,
+    payload: Vec<u8>,
+}
+
+/// Reassembles messages from the chunks read from the connection.
+pub struct ChunkReader {
+    chunk_size: usize,
+    streams: HashMap<u32, ChunkStream>,
+    bytes_read: u64,
+    /// bytes of the partially read messages of all chunk streams
+    buffered: usize,
+    /// messages are limited to a few KiB until the publisher is known
+    authenticated: bool,
+}
+
+impl Default for ChunkReader {
+    fn default() -> Self {
+        ChunkReader {
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            streams: HashMap::new(),
+            bytes_read: 0,
+            buffered: 0,
+            authenticated: false,
+        }
+    }
+}
+
+impl ChunkReader {
+    pub fn set_chunk_size(&mut self, size: usize) {
+        self.chunk_size = size.max(1);
+    }
+
+    /// Accepts messages of any size, once the publisher is authenticated.
+    pub fn authenticate(&mut self) {
+        self.authenticated = true;
+    }
+
+    /// total number of bytes read from the connection, used for
+    /// acknowledgements
+    pub fn bytes_read(&self) -> u64 {
+        self.bytes_read
+    }
+
+    pub async fn read_message<R: AsyncRead + Unpin>(&mut self, r: &mut R) -> io::Result<Message> {
+        loop {
+            if let Some(message) = self.read_chunk(r).await? {
+                return Ok(message);
+            }
+        }
+    }
+
+    async fn read_chunk<R: AsyncRead + Unpin>(&mut self, r: &mut R) -> io::Result<Option<Message>> {
+        let first = self.read_u8(r).await?;
+        let fmt = first >> 6;
+        let csid = match first & 0x3F {
+            0 => 64 + u32::from(self.read_u8(r).await?),
+            1 => {
+                let low = u32::from(self.read_u8(r).await?);
+                let high = u32::from(self.read_u8(r).await?);
+                64 + low + high * 256
+            }
+            csid => u32::from(csid),
+        };
+
+        let mut header = [0u8; 11];
+        let header_len = [11, 7, 3, 0][fmt as usize];
+        self.read_exact(r, &mut header[..header_len]).await?;
+
+        let stream = self.streams.entry(csid).or_default();
+        let starts_message = stream.payload.is_empty();
+        if fmt < 3 {
+            let timestamp = u24(&header[0..3]);
+            stream.extended = timestamp == EXTENDED_TIMESTAMP;
+            if fmt == 0 {
+                stream.timestamp = timestamp;
+                stream.delta = 0;
+            } else {
+                stream.delta = timestamp;
+            }
+        }
+        if fmt < 2 {
+            stream.length = u24(&header[3..6]) as usize;
+            stream.type_id = header[6];
+        }
+        if fmt == 0 {
+            stream.stream_id = u32::from_le_bytes(header[7..11].try_into().unwrap());
+        }
+        let limit = match (self.authenticated, stream.type_id) {
+            (true, _) => usize::MAX,
+            (false, message_type::COMMAND_AMF0) => UNAUTHENTICATED_COMMAND_SIZE,
+            (false, _) => UNAUTHENTICATED_MESSAGE_SIZE,
+        };
+        if stream.length > limit {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "message of {} bytes on chunk stream {} before authentication",
+                    stream.length, csid
+                ),
+            ));
+        }
+
+        let extended = stream.extended;
+        if extended {
+            let mut bytes = [0u8; 4];
+            self.read_exact(r, &mut bytes).await?;
+            let value = u32::from_be_bytes(bytes);
+            let stream = self.streams.get_mut(&csid).unwrap();
+            match fmt {
+                0 => stream.timestamp = value,
+                1 | 2 => stream.delta = value,
+                // repeats the value of the previous header
+                _ => {}
+            }
+        }
+
+        let chunk_size = self.chunk_size;
+        let stream = self.streams.get_mut(&csid).unwrap();
+        if starts_message && fmt != 0 {
+            stream.timestamp = stream.timestamp.wrapping_add(stream.delta);
+        }
+
+        // a new header may shorten a message that is partially read
+        let remaining = stream
+            .length
+            .checked_sub(stream.payload.len())
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("chunk stream {} shorter than its payload", csid),
+                )
+            })?;
+        let start = stream.payload.len();
+        let end = start + remaining.min(chunk_size);
+        if end < stream.length && self.buffered - start + end > MAX_BUFFERED {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("more than {} bytes of partial messages", MAX_BUFFERED),
+            ));
+        }
+        let mut payload = std::mem::take(&mut stream.payload);
+        self.buffered -= start;
+        payload.resize(end, 0);
+        self.read_exact(r, &mut payload[start..]).await?;
+
+        let stream = self.streams.get_mut(&csid).unwrap();
+        if payload.len() < stream.length {
+            self.buffered += payload.len();
+            stream.payload = payload;
+            return Ok(None);
+        }
+
+        Ok(Some(Message {
+            type_id: stream.type_id,
+            stream_id: stream.stream_id,
+            timestamp: stream.timestamp,
+            payload,
+        }))
+    }
+
+    async fn read_u8<R: AsyncRead + Unpin>(&mut self, r: &mut R) -> io::Result<u8> {
+        let mut byte = [0u8; 1];
+        self.read_exact(r, &mut byte).await?;
+        Ok(byte[0])
+    }
+
+    async fn read_exact<R: AsyncRead + Unpin>(
+        &mut self,
+        r: &mut R,
+        buf: &mut [u8],
+    ) -> io::Result<()> {
+        r.read_exact(buf).await?;
+        self.bytes_read += buf.len() as u64;
+        Ok(())
+    }
+}
+
+/// Appends `message` to `out` as chunks of at most `chunk_size` bytes. The
+/// first chunk carries a full header, the others only the chunk stream id.
+pub fn write_message(out: &mut Vec<u8>, csid: u8, message: &Message, chunk_size: usize) {
+    debug_assert!((2..64).contains(&csid));
+    let extended = message.timestamp >= EXTENDED_TIMESTAMP;
+    let timestamp = message.timestamp.min(EXTENDED_TIMESTAMP);
+
+    out.push(csid);
+    out.extend(&timestamp.to_be_bytes()[1..]);
+    out.extend(&(message.payload.len() as u32).to_be_bytes()[1..]);
+    out.push(message.type_id);
+    out.extend(message.stream_id.to_le_bytes());
+    if extended {
+        out.extend(message.timestamp.to_be_bytes());
+    }
+
+    for (i, chunk) in message.payload.chunks(chunk_size.max(1)).enumerate() {
+        if i > 0 {
+            out.push(0xC0 | csid);
+            if extended {
+                out.extend(message.timestamp.to_be_bytes());
+            }
+        }
+        out.extend(chunk);
+    }
+}
+
+fn u24(bytes: &[u8]) -> u32 {
+    u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(type_id: u8, timestamp: u32, len: usize) -> Message {
+        Message {
+            type_id,
+            stream_id: 1,
+            timestamp,
+            payload: (0..len).map(|i| i as u8).collect(),
+        }
+    }
+
+    #[tokio::test]
+    async fn write_and_read() {
+        let video = message(message_type::VIDEO, 40, 300);
+        let audio = message(message_type::AUDIO, 0x0100_0000, 20);
+        let mut data = Vec::new();
+        write_message(&mut data, 6, &video, DEFAULT_CHUNK_SIZE);
+        write_message(&mut data, 4, &audio, DEFAULT_CHUNK_SIZE);
+
+        let mut reader = ChunkReader::default();
+        let mut input = data.as_slice();
+        assert_eq!(video, reader.read_message(&mut input).await.unwrap());
+        assert_eq!(audio, reader.read_message(&mut input).await.unwrap());
+        assert_eq!(data.len() as u64, reader.bytes_read());
+        assert!(reader.read_message(&mut input).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn compressed_headers() {
+        let mut data = Vec::new();
+        // fmt 0: timestamp 1000, 4 bytes of video on stream 1
+        data.extend([0x06, 0x00, 0x03, 0xE8, 0, 0, 4, 9, 1, 0, 0, 0, 1, 2, 3, 4]);
+        // fmt 2: delta 40
+        data.extend([0x86, 0, 0, 40, 5, 6, 7, 8]);
+        // fmt 3: repeats the delta
+        data.extend([0xC6, 9, 10, 11, 12]);
+        // fmt 1: delta 20, 2 bytes of audio
+        data.extend([0x46, 0, 0, 20, 0, 0, 2, 8, 13, 14]);
+
+        let mut reader = ChunkReader::default();
+        let mut input = data.as_slice();
+        let mut timestamps = Vec::new();
+        for _ in 0..4 {
+            let message = reader.read_message(&mut input).await.unwrap();
+            assert_eq!(1, message.stream_id);
+            timestamps.push((message.type_id, message.timestamp, message.payload));
+        }
+        assert_eq!(
+            vec![
+                (9, 1000, vec![1, 2, 3, 4]),
+                (9, 1040, vec![5, 6, 7, 8]),
+                (9, 1080, vec![9, 10, 11, 12]),
+                (8, 1100, vec![13, 14]),
+            ],
+            timestamps
+        );
+    }
+
+    #[tokio::test]
+    async fn shortened_message() {
+        let mut data = Vec::new();
+        // fmt 0: 200 bytes of video, the first chunk carries 128 of them
+        data.extend([0x06, 0, 0, 0, 0, 0, 200, 9, 1, 0, 0, 0]);
+        data.extend([0; DEFAULT_CHUNK_SIZE]);
+        // fmt 1: the message is only 4 bytes long now
+        data.extend([0x46, 0, 0, 0, 0, 0, 4, 9, 1, 2, 3, 4]);
+
+        let mut reader = ChunkReader::default();
+        let error = reader.read_message(&mut data.as_slice()).await.unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, error.kind());
+    }
+
+    #[tokio::test]
+    async fn unauthenticated_limits() {
+        let mut data = Vec::new();
+        write_message(
+            &mut data,
+            3,
+            &message(message_type::COMMAND_AMF0, 0, 8000),
+            128,
+        );
+        write_message(&mut data, 6, &message(message_type::VIDEO, 0, 8000), 128);
+
+        let mut reader = ChunkReader::default();
+        let mut input = data.as_slice();
+        assert!(reader.read_message(&mut input).await.is_ok());
+        let error = reader.read_message(&mut input).await.unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, error.kind());
+
+        let mut reader = ChunkReader::default();
+        reader.authenticate();
+        let mut input = data.as_slice();
+        assert!(reader.read_message(&mut input).await.is_ok());
+        assert!(reader.read_message(&mut input).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn buffered_limit() {
+        const CHUNK_SIZE: usize = 8 * 1024 * 1024;
+        let mut data = Vec::new();
+        // first halves of messages of 16 MiB on separate chunk streams
+        for csid in 2..7 {
+            data.extend([csid, 0, 0, 0, 0xFF, 0xFF, 0xFF, 9, 1, 0, 0, 0]);
+            data.resize(data.len() + CHUNK_SIZE, 0);
+        }
+
+        let mut reader = ChunkReader::default();
+        reader.authenticate();
+        reader.set_chunk_size(CHUNK_SIZE);
+        let error = reader.read_message(&mut data.as_slice()).await.unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, error.kind());
+        assert_eq!(MAX_BUFFERED, reader.buffered);
+    }
+}
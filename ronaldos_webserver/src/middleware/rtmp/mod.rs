@@ -0,0 +1,569 @@
+//! RTMP ingest for external encoders such as OBS or ffmpeg. Published
+//! streams are remuxed into HLS in the stream directory and registered as
+//! live streams for as long as the encoder is connected.
+mod amf0;
+mod chunk;
+
+use self::amf0::{encode_all, Amf0Value};
+use self::chunk::{message_type, write_message, ChunkReader, Message};
+use super::stream_remuxer::{ingest_playlist, remux};
+use super::LocalStreamStore;
+use anyhow::{anyhow, bail, Context};
+use chrono::Utc;
+use std::{collections::HashMap, io, net::SocketAddr, path::PathBuf, sync::Arc};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpListener,
+    sync::{mpsc, RwLock},
+    task::JoinHandle,
+};
+use tracing::{debug, info, warn};
+use uuid::Uuid;
+
+const RTMP_VERSION: u8 = 3;
+const HANDSHAKE_SIZE: usize = 1536;
+/// bytes the encoder may send before it expects an acknowledgement
+const WINDOW_SIZE: u32 = 2_500_000;
+const OUT_CHUNK_SIZE: usize = 4096;
+/// message stream returned by createStream, media is published on it
+const PUBLISH_STREAM_ID: f64 = 1.0;
+/// FLV tags buffered between the connection and the remuxer
+const INGEST_QUEUE: usize = 256;
+
+// chunk streams of the messages sent to the encoder
+const CONTROL_CSID: u8 = 2;
+const COMMAND_CSID: u8 = 3;
+const STATUS_CSID: u8 = 5;
+
+/// Accepts RTMP publishers that know one of the configured stream keys.
+pub struct RtmpServer {
+    /// stream key and the name its streams are registered under
+    keys: HashMap<String, String>,
+    root: PathBuf,
+    segment_duration: i32,
+    stream_store: Arc<RwLock<LocalStreamStore>>,
+}
+
+impl RtmpServer {
+    pub fn new(
+        config: &ronaldos_config::Rtmp,
+        root: PathBuf,
+        stream_store: Arc<RwLock<LocalStreamStore>>,
+    ) -> anyhow::Result<Self> {
+        Ok(RtmpServer {
+            keys: config
+                .keys
+                .iter()
+                .map(|k| (k.key.clone(), k.name.clone()))
+                .collect(),
+            root,
+            segment_duration: config.segment_duration.try_into()?,
+            stream_store,
+        })
+    }
+
+    /// Binds `address` and serves every connection on its own task.
+    pub async fn run(self, address: SocketAddr) -> anyhow::Result<JoinHandle<()>> {
+        let listener = TcpListener::bind(address)
+            .await
+            .with_context(|| format!("could not bind rtmp server to {}", address))?;
+        info!("starting rtmp server on {:?}", address);
+
+        let server = Arc::new(self);
+        Ok(tokio::spawn(async move {
+            loop {
+                let (socket, peer) = match listener.accept().await {
+                    Ok(connection) => connection,
+                    Err(e) => {
+                        warn!("could not accept rtmp connection: {}", e);
+                        continue;
+                    }
+                };
+
+                let server = server.clone();
+                tokio::spawn(async move {
+                    debug!("rtmp connection from {}", peer);
+                    if let Err(e) = server.serve(socket).await {
+                        warn!("rtmp connection {} failed: {:#}", peer, e);
+                    }
+                });
+            }
+        }))
+    }
+
+    async fn serve<S: AsyncRead + AsyncWrite + Unpin>(&self, mut socket: S) -> anyhow::Result<()> {
+        handshake(&mut socket).await?;
+        let mut connection = Connection::new(socket);
+        let mut ingest = None;
+        let result = self.handle_messages(&mut connection, &mut ingest).await;
+
+        if let Some(ingest) = ingest {
+            ingest.finish(&self.stream_store).await?;
+        }
+        result
+    }
+
+    async fn handle_messages<S: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        connection: &mut Connection<S>,
+        ingest: &mut Option<Ingest>,
+    ) -> anyhow::Result<()> {
+        loop {
+            let message = match connection.read().await {
+                Ok(message) => message,
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+                Err(e) => return Err(e.into()),
+            };
+
+            match message.type_id {
+                message_type::SET_CHUNK_SIZE => {
+                    let size = be_u32(&message.payload)? & 0x7FFF_FFFF;
+                    connection.reader.set_chunk_size(size as usize);
+                }
+                message_type::WINDOW_ACK_SIZE => {
+                    connection.ack_window = be_u32(&message.payload)?;
+                }
+                message_type::AUDIO | message_type::VIDEO => {
+                    if let Some(ingest) = ingest {
+                        ingest.send(&message, &message.payload).await?;
+                    }
+                }
+                message_type::DATA_AMF0 => {
+                    if let Some(ingest) = ingest {
+                        ingest.send(&message, script_data(&message.payload)).await?;
+                    }
+                }
+                message_type::COMMAND_AMF0 => {
+                    let keep_open = self.command(connection, &message, ingest).await?;
+                    if !keep_open {
+                        return Ok(());
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Handles a command of the encoder, returns false when the encoder is
+    /// done publishing.
+    async fn command<S: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        connection: &mut Connection<S>,
+        message: &Message,
+        ingest: &mut Option<Ingest>,
+    ) -> anyhow::Result<bool> {
+        let values = Amf0Value::decode_all(&message.payload)?;
+        let name = values
+            .first()
+            .and_then(Amf0Value::as_str)
+            .unwrap_or_default();
+        let transaction = values.get(1).cloned().unwrap_or(Amf0Value::Number(0.0));
+        debug!("rtmp command {}", name);
+
+        match name {
+            "connect" => connection.accept_connect(transaction).await?,
+            "createStream" => {
+                let stream_id = Amf0Value::Number(PUBLISH_STREAM_ID);
+                connection.send_result(transaction, stream_id).await?;
+            }
+            "releaseStream" | "FCPublish" | "FCUnpublish" => {
+                connection
+                    .send_result(transaction, Amf0Value::Undefined)
+                    .await?
+            }
+            "publish" => {
+                // encoders may append query parameters to the key
+                let key = values
+                    .get(3)
+                    .and_then(Amf0Value::as_str)
+                    .and_then(|key| key.split('?').next())
+                    .unwrap_or_default();
+                let Some(name) = self.keys.get(key) else {
+                    connection
+                        .send_status(message.stream_id, "error", "NetStream.Publish.BadName")
+                        .await?;
+                    bail!("refused publish with unknown stream key");
+                };
+                if ingest.is_some() {
+                    bail!("already publishing on this connection");
+                }
+                connection.reader.authenticate();
+
+                *ingest = Some(self.start_ingest(name).await?);
+                connection
+                    .send_status(message.stream_id, "status", "NetStream.Publish.Start")
+                    .await?;
+            }
+            "deleteStream" => return Ok(false),
+            _ => {}
+        }
+        Ok(true)
+    }
+
+    /// Registers a live stream and starts remuxing into it.
+    async fn start_ingest(&self, name: &str) -> anyhow::Result<Ingest> {
+        let started = Utc::now();
+        let playlist = ingest_playlist(&self.root, name, started)?;
+
+        let relative = playlist.strip_prefix(&self.root)?.to_path_buf();
+        let uuid = self
+            .stream_store
+            .read()
+            .await
+            .register(name.to_string(), vec![relative], started, None)
+            .await?;
+
+        let (sender, receiver) = mpsc::channel(INGEST_QUEUE);
+        sender.send(flv_header()).await?;
+        let segment_duration = self.segment_duration;
+        let remuxer = tokio::task::spawn_blocking(move || {
+            remux(c"flv", receiver, &playlist, segment_duration)
+        });
+
+        info!("{} started publishing as {}", name, uuid);
+        Ok(Ingest {
+            uuid,
+            sender,
+            remuxer,
+        })
+    }
+}
+
+/// A stream that is being published and remuxed.
+struct Ingest {
+    uuid: Uuid,
+    sender: mpsc::Sender<Vec<u8>>,
+    remuxer: JoinHandle<anyhow::Result<()>>,
+}
+
+impl Ingest {
+    async fn send(&self, message: &Message, data: &[u8]) -> anyhow::Result<()> {
+        let tag = flv_tag(message.type_id, message.timestamp, data);
+        self.sender
+            .send(tag)
+            .await
+            .map_err(|_| anyhow!("remuxer of {} stopped", self.uuid))
+    }
+
+    /// Ends the input of the remuxer and turns the stream into VOD once the
+    /// remuxer wrote the last segment.
+    async fn finish(self, stream_store: &RwLock<LocalStreamStore>) -> anyhow::Result<()> {
+        drop(self.sender);
+        let remuxed = self.remuxer.await?;
+        let finished = LocalStreamStore::finish_stream(stream_store, &self.uuid).await;
+        info!("{} stopped publishing", self.uuid);
+        remuxed.and(finished)
+    }
+}
+
+/// The RTMP connection after the handshake.
+struct Connection<S> {
+    socket: S,
+    reader: ChunkReader,
+    /// size of the chunks sent to the encoder
+    chunk_size: usize,
+    ack_window: u32,
+    acknowledged: u64,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Connection<S> {
+    fn new(socket: S) -> Self {
+        Connection {
+            socket,
+            reader: ChunkReader::default(),
+            chunk_size: chunk::DEFAULT_CHUNK_SIZE,
+            ack_window: 0,
+            acknowledged: 0,
+        }
+    }
+
+    /// Reads the next message and acknowledges the received bytes once the
+    /// window of the encoder is full.
+    async fn read(&mut self) -> io::Result<Message> {
+        let message = self.reader.read_message(&mut self.socket).await?;
+        let read = self.reader.bytes_read();
+        if self.ack_window > 0 && read - self.acknowledged >= u64::from(self.ack_window) {
+            self.acknowledged = read;
+            let sequence = (read as u32).to_be_bytes().to_vec();
+            self.send_control(message_type::ACKNOWLEDGEMENT, sequence)
+                .await?;
+        }
+        Ok(message)
+    }
+
+    async fn send(&mut self, csid: u8, message: Message) -> io::Result<()> {
+        let mut out = Vec::new();
+        write_message(&mut out, csid, &message, self.chunk_size);
+        self.socket.write_all(&out).await?;
+        self.socket.flush().await
+    }
+
+    async fn send_control(&mut self, type_id: u8, payload: Vec<u8>) -> io::Result<()> {
+        let message = Message {
+            type_id,
+            stream_id: 0,
+            timestamp: 0,
+            payload,
+        };
+        self.send(CONTROL_CSID, message).await
+    }
+
+    async fn send_command(
+        &mut self,
+        csid: u8,
+        stream_id: u32,
+        values: &[Amf0Value],
+    ) -> io::Result<()> {
+        let message = Message {
+            type_id: message_type::COMMAND_AMF0,
+            stream_id,
+            timestamp: 0,
+            payload: encode_all(values),
+        };
+        self.send(csid, message).await
+    }
+
+    async fn send_result(&mut self, transaction: Amf0Value, value: Amf0Value) -> io::Result<()> {
+        let values = [
+            Amf0Value::string("_result"),
+            transaction,
+            Amf0Value::Null,
+            value,
+        ];
+        self.send_command(COMMAND_CSID, 0, &values).await
+    }
+
+    async fn send_status(&mut self, stream_id: u32, level: &str, code: &str) -> io::Result<()> {
+        let values = [
+            Amf0Value::string("onStatus"),
+            Amf0Value::Number(0.0),
+            Amf0Value::Null,
+            Amf0Value::object([
+                ("level", Amf0Value::string(level)),
+                ("code", Amf0Value::string(code)),
+                ("description", Amf0Value::string(code)),
+            ]),
+        ];
+        self.send_command(STATUS_CSID, stream_id, &values).await
+    }
+
+    async fn accept_connect(&mut self, transaction: Amf0Value) -> io::Result<()> {
+        self.send_control(
+            message_type::WINDOW_ACK_SIZE,
+            WINDOW_SIZE.to_be_bytes().to_vec(),
+        )
+        .await?;
+        // dynamic limit type
+        let mut bandwidth = WINDOW_SIZE.to_be_bytes().to_vec();
+        bandwidth.push(2);
+        self.send_control(message_type::SET_PEER_BANDWIDTH, bandwidth)
+            .await?;
+        let chunk_size = (OUT_CHUNK_SIZE as u32).to_be_bytes().to_vec();
+        self.send_control(message_type::SET_CHUNK_SIZE, chunk_size)
+            .await?;
+        self.chunk_size = OUT_CHUNK_SIZE;
+
+        let values = [
+            Amf0Value::string("_result"),
+            transaction,
+            Amf0Value::object([
+                ("fmsVer", Amf0Value::string("FMS/3,0,1,123")),
+                ("capabilities", Amf0Value::Number(31.0)),
+            ]),
+            Amf0Value::object([
+                ("level", Amf0Value::string("status")),
+                ("code", Amf0Value::string("NetConnection.Connect.Success")),
+                ("description", Amf0Value::string("Connection succeeded.")),
+                ("objectEncoding", Amf0Value::Number(0.0)),
+            ]),
+        ];
+        self.send_command(COMMAND_CSID, 0, &values).await
+    }
+}
+
+/// Simple handshake without digest: S1 is arbitrary data and S2 echoes C1.
+async fn handshake<S: AsyncRead + AsyncWrite + Unpin>(socket: &mut S) -> io::Result<()> {
+    let mut c0c1 = vec![0u8; 1 + HANDSHAKE_SIZE];
+    socket.read_exact(&mut c0c1).await?;
+    if c0c1[0] != RTMP_VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unsupported rtmp version {}", c0c1[0]),
+        ));
+    }
+
+    let mut s0s1s2 = Vec::with_capacity(1 + 2 * HANDSHAKE_SIZE);
+    s0s1s2.push(RTMP_VERSION);
+    // time and zero fields, followed by filler
+    s0s1s2.extend([0u8; 8]);
+    s0s1s2.extend((8..HANDSHAKE_SIZE).map(|i| i as u8));
+    s0s1s2.extend(&c0c1[1..]);
+    socket.write_all(&s0s1s2).await?;
+    socket.flush().await?;
+
+    let mut c2 = vec![0u8; HANDSHAKE_SIZE];
+    socket.read_exact(&mut c2).await?;
+    Ok(())
+}
+
+/// Encoders wrap the metadata in a @setDataFrame call, FLV files hold the
+/// onMetaData part only.
+fn script_data(payload: &[u8]) -> &[u8] {
+    let mut rest = payload;
+    match Amf0Value::decode(&mut rest) {
+        Ok(Amf0Value::String(name)) if name == "@setDataFrame" => rest,
+        _ => payload,
+    }
+}
+
+fn be_u32(payload: &[u8]) -> io::Result<u32> {
+    payload
+        .get(..4)
+        .map(|bytes| u32::from_be_bytes(bytes.try_into().unwrap()))
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "truncated control message"))
+}
+
+/// FLV file header announcing audio and video, followed by the size of the
+/// (non existing) previous tag.
+fn flv_header() -> Vec<u8> {
+    vec![b'F', b'L', b'V', 1, 0x05, 0, 0, 0, 9, 0, 0, 0, 0]
+}
+
+/// RTMP audio, video and data messages carry FLV tag bodies, only the tag
+/// header and trailing size have to be added.
+fn flv_tag(tag_type: u8, timestamp: u32, data: &[u8]) -> Vec<u8> {
+    let mut tag = Vec::with_capacity(15 + data.len());
+    tag.push(tag_type);
+    tag.extend(&(data.len() as u32).to_be_bytes()[1..]);
+    tag.extend(&timestamp.to_be_bytes()[1..]);
+    tag.push((timestamp >> 24) as u8);
+    tag.extend([0, 0, 0]);
+    tag.extend(data);
+    tag.extend((11 + data.len() as u32).to_be_bytes());
+    tag
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{fs, str::FromStr};
+    use tempdir::TempDir;
+    use tokio::io::DuplexStream;
+
+    fn command(values: &[Amf0Value]) -> Message {
+        Message {
+            type_id: message_type::COMMAND_AMF0,
+            stream_id: 0,
+            timestamp: 0,
+            payload: encode_all(values),
+        }
+    }
+
+    async fn send(client: &mut DuplexStream, message: Message) {
+        let mut out = Vec::new();
+        write_message(&mut out, COMMAND_CSID, &message, chunk::DEFAULT_CHUNK_SIZE);
+        client.write_all(&out).await.unwrap();
+    }
+
+    /// reads messages until a command arrives and returns its values
+    async fn receive_command(
+        client: &mut DuplexStream,
+        reader: &mut ChunkReader,
+    ) -> Vec<Amf0Value> {
+        loop {
+            let message = reader.read_message(client).await.unwrap();
+            match message.type_id {
+                message_type::SET_CHUNK_SIZE => {
+                    reader.set_chunk_size(be_u32(&message.payload).unwrap() as usize)
+                }
+                message_type::COMMAND_AMF0 => {
+                    return Amf0Value::decode_all(&message.payload).unwrap()
+                }
+                _ => {}
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn refuses_unknown_keys() {
+        let temp = TempDir::new("rtmp").unwrap();
+        let store =
+            LocalStreamStore::new(temp.path().into(), PathBuf::from_str("/streams").unwrap());
+        let config = ronaldos_config::Rtmp {
+            keys: vec![ronaldos_config::StreamKey {
+                key: "secret".to_string(),
+                name: "roki".to_string(),
+            }],
+            ..Default::default()
+        };
+        let server =
+            RtmpServer::new(&config, temp.path().into(), Arc::new(RwLock::new(store))).unwrap();
+
+        let (mut client, socket) = tokio::io::duplex(64 * 1024);
+        let session = tokio::spawn(async move { server.serve(socket).await });
+
+        let c1 = (0..HANDSHAKE_SIZE)
+            .map(|i| (i * 7) as u8)
+            .collect::<Vec<_>>();
+        client.write_all(&[RTMP_VERSION]).await.unwrap();
+        client.write_all(&c1).await.unwrap();
+        let mut s0s1s2 = vec![0u8; 1 + 2 * HANDSHAKE_SIZE];
+        client.read_exact(&mut s0s1s2).await.unwrap();
+        assert_eq!(RTMP_VERSION, s0s1s2[0]);
+        assert_eq!(c1, &s0s1s2[1 + HANDSHAKE_SIZE..]);
+        client
+            .write_all(&s0s1s2[1..1 + HANDSHAKE_SIZE])
+            .await
+            .unwrap();
+
+        let mut reader = ChunkReader::default();
+        let connect = command(&[
+            Amf0Value::string("connect"),
+            Amf0Value::Number(1.0),
+            Amf0Value::object([("app", Amf0Value::string("show"))]),
+        ]);
+        send(&mut client, connect).await;
+        let result = receive_command(&mut client, &mut reader).await;
+        assert_eq!(Some("_result"), result[0].as_str());
+        assert_eq!(Amf0Value::Number(1.0), result[1]);
+
+        let create_stream = command(&[
+            Amf0Value::string("createStream"),
+            Amf0Value::Number(2.0),
+            Amf0Value::Null,
+        ]);
+        send(&mut client, create_stream).await;
+        let result = receive_command(&mut client, &mut reader).await;
+        assert_eq!(Amf0Value::Number(PUBLISH_STREAM_ID), result[3]);
+
+        let publish = command(&[
+            Amf0Value::string("publish"),
+            Amf0Value::Number(3.0),
+            Amf0Value::Null,
+            Amf0Value::string("wrong?key=1"),
+            Amf0Value::string("live"),
+        ]);
+        send(&mut client, publish).await;
+        let status = receive_command(&mut client, &mut reader).await;
+        assert_eq!(Some("onStatus"), status[0].as_str());
+        assert!(format!("{:?}", status[3]).contains("NetStream.Publish.BadName"));
+
+        assert!(session.await.unwrap().is_err());
+        // nothing was registered
+        assert_eq!(0, fs::read_dir(temp.path()).unwrap().count());
+    }
+
+    #[test]
+    fn flv_tags() {
+        assert_eq!(
+            vec![9, 0, 0, 2, 0x34, 0x56, 0x78, 0x12, 0, 0, 0, 0xAA, 0xBB, 0, 0, 0, 13],
+            flv_tag(message_type::VIDEO, 0x1234_5678, &[0xAA, 0xBB])
+        );
+
+        let mut payload = encode_all(&[Amf0Value::string("@setDataFrame")]);
+        let metadata = encode_all(&[Amf0Value::string("onMetaData"), Amf0Value::Null]);
+        payload.extend(&metadata);
+        assert_eq!(metadata, script_data(&payload));
+        assert_eq!(metadata, script_data(&metadata));
+    }
+}
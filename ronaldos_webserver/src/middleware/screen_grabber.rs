@@ -1,38 +1,252 @@
-use anyhow::bail;
+use anyhow::{bail, ensure, Context};
 use std::{
-    ffi::{CStr, CString},
+    ffi::{c_char, CString},
     fs,
     os::unix::ffi::OsStrExt,
-    path::PathBuf,
-    sync::Once,
+    path::{Path, PathBuf},
+    sync::{Arc, Once},
+    thread::JoinHandle,
 };
 use tracing::{info, instrument, warn};
 
 static M3U8_EXT: &str = "m3u8";
 static INIT_FFMPEG: Once = Once::new();
 
+/// Opaque handle to a `Grabber` instance living on the C++ side.
+#[repr(C)]
+struct RawGrabber {
+    _private: [u8; 0],
+}
+
+/// Mirrors `SourceKind` in capture_source.hpp.
+#[repr(i32)]
+#[derive(Debug, Clone, Copy)]
+enum SourceKind {
+    Device = 0,
+    TestPattern = 1,
+    File = 2,
+}
+
+/// A single quality level of the HLS bitrate ladder. Mirrors `Rendition` in
+/// screen_grabber.cpp.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rendition {
+    pub width: i32,
+    pub height: i32,
+    /// target bitrate in bits per second
+    pub bit_rate: i64,
+}
+
+impl TryFrom<&ronaldos_config::Rendition> for Rendition {
+    type Error = anyhow::Error;
+
+    fn try_from(rendition: &ronaldos_config::Rendition) -> anyhow::Result<Self> {
+        Ok(Rendition {
+            width: rendition.width.try_into()?,
+            height: rendition.height.try_into()?,
+            bit_rate: rendition.bitrate.try_into()?,
+        })
+    }
+}
+
+/// Capture and encoder parameters as they cross the FFI boundary. Mirrors
+/// `CaptureSettings` in screen_grabber.cpp. Only valid as long as the
+/// [CaptureSettings] it was created from.
+#[repr(C)]
+struct RawCaptureSettings {
+    fps: i32,
+    segment_duration: i32,
+    gop_size: i32,
+    preset: *const c_char,
+    ladder: *const Rendition,
+    ladder_len: usize,
+    audio_device: *const c_char,
+    audio_bit_rate: i64,
+}
+
+/// Capture and encoder parameters of the [ScreenGrabber].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CaptureSettings {
+    pub fps: i32,
+    /// HLS segment length in seconds
+    pub segment_duration: i32,
+    /// keyframe interval in frames
+    pub gop_size: i32,
+    /// x264 preset
+    pub preset: CString,
+    /// renditions ordered from highest to lowest quality. The first one
+    /// determines the resolution the source is captured in.
+    pub ladder: Vec<Rendition>,
+    /// ALSA device recorded next to a capture card, "auto" for the card's own
+    /// sound card or empty for no audio
+    pub audio_device: CString,
+    /// AAC bits per second
+    pub audio_bitrate: i64,
+}
+
+impl CaptureSettings {
+    fn validate(&self) -> anyhow::Result<()> {
+        ensure!(!self.ladder.is_empty(), "bitrate ladder cannot be empty");
+        // variant playlists are named after the height of their rendition
+        for (i, rendition) in self.ladder.iter().enumerate() {
+            ensure!(
+                self.ladder[..i]
+                    .iter()
+                    .all(|other| other.height != rendition.height),
+                "more than one rendition with a height of {}",
+                rendition.height
+            );
+        }
+        ensure!(self.fps > 0, "fps must be positive");
+        ensure!(
+            self.segment_duration > 0,
+            "segment duration must be positive"
+        );
+        ensure!(self.gop_size > 0, "gop size must be positive");
+        ensure!(self.audio_bitrate > 0, "audio bitrate must be positive");
+        Ok(())
+    }
+
+    fn as_raw(&self) -> RawCaptureSettings {
+        RawCaptureSettings {
+            fps: self.fps,
+            segment_duration: self.segment_duration,
+            gop_size: self.gop_size,
+            preset: self.preset.as_ptr(),
+            ladder: self.ladder.as_ptr(),
+            ladder_len: self.ladder.len(),
+            audio_device: self.audio_device.as_ptr(),
+            audio_bit_rate: self.audio_bitrate,
+        }
+    }
+}
+
+impl TryFrom<&ronaldos_config::Capture> for CaptureSettings {
+    type Error = anyhow::Error;
+
+    /// The configured resolution and bitrate form the first rendition, the
+    /// configured ladder is appended to it.
+    fn try_from(capture: &ronaldos_config::Capture) -> anyhow::Result<Self> {
+        let full = ronaldos_config::Rendition {
+            width: capture.width,
+            height: capture.height,
+            bitrate: capture.bitrate,
+        };
+
+        let ladder = std::iter::once(&full)
+            .chain(capture.ladder.iter())
+            .map(Rendition::try_from)
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        let settings = CaptureSettings {
+            fps: capture.fps.try_into()?,
+            segment_duration: capture.segment_duration.try_into()?,
+            gop_size: capture.gop_size.try_into()?,
+            preset: CString::new(capture.preset.as_str())?,
+            ladder,
+            audio_device: CString::new(capture.audio_device.as_str())?,
+            audio_bitrate: capture.audio_bitrate.try_into()?,
+        };
+        settings.validate()?;
+        Ok(settings)
+    }
+}
+
+impl Default for CaptureSettings {
+    fn default() -> Self {
+        CaptureSettings::try_from(&ronaldos_config::Capture::default())
+            .expect("default capture config is valid")
+    }
+}
+
 extern "C" {
     fn screen_grabber_init();
-    fn screen_grabber_start(input: *const i8, output: *const i8) -> i32;
+    fn screen_grabber_open(
+        kind: SourceKind,
+        input: *const c_char,
+        output: *const c_char,
+        settings: *const RawCaptureSettings,
+        grabber: *mut *mut RawGrabber,
+    ) -> i32;
+    fn screen_grabber_run(grabber: *mut RawGrabber) -> i32;
+    fn screen_grabber_stop(grabber: *mut RawGrabber);
+    fn screen_grabber_free(grabber: *mut RawGrabber);
     fn screen_grabber_stop_all() -> i32;
 }
 
+/// Owns the native grabber and frees it once both the [Recording] and its
+/// capture thread let go of it.
+struct GrabberPtr(*mut RawGrabber);
+
+// The native grabber only shares an atomic stop flag between the capture
+// thread and the thread calling `screen_grabber_stop`.
+unsafe impl Send for GrabberPtr {}
+unsafe impl Sync for GrabberPtr {}
+
+impl Drop for GrabberPtr {
+    fn drop(&mut self) {
+        unsafe {
+            screen_grabber_free(self.0);
+        }
+    }
+}
+
+/// Input the [ScreenGrabber] records from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CaptureSource {
+    /// V4L2 capture card, matched against the device name it reports.
+    Device(String),
+    /// Generated color bars with a sine tone. Does not require any hardware,
+    /// which makes it useful for testing the pipeline.
+    TestPattern,
+    /// Local video file that is played back in real-time.
+    File(PathBuf),
+}
+
+impl CaptureSource {
+    fn to_ffi(&self) -> anyhow::Result<(SourceKind, CString)> {
+        Ok(match self {
+            CaptureSource::Device(name) => (SourceKind::Device, CString::new(name.as_str())?),
+            CaptureSource::TestPattern => (SourceKind::TestPattern, CString::default()),
+            CaptureSource::File(path) => {
+                (SourceKind::File, CString::new(path.as_os_str().as_bytes())?)
+            }
+        })
+    }
+}
+
 pub struct ScreenGrabber {
-    capture_name: String,
+    source: CaptureSource,
     root: PathBuf,
+    settings: CaptureSettings,
 }
 
 impl ScreenGrabber {
-    pub fn new(capture_name: String, root: PathBuf) -> anyhow::Result<Self> {
+    pub fn new(
+        source: CaptureSource,
+        root: PathBuf,
+        settings: CaptureSettings,
+    ) -> anyhow::Result<Self> {
+        settings.validate()?;
         INIT_FFMPEG.call_once(|| unsafe {
             screen_grabber_init();
         });
 
-        Ok(Self { capture_name, root })
+        Ok(Self {
+            source,
+            root,
+            settings,
+        })
     }
 
+    /// Opens the capture device and starts recording on a dedicated thread.
+    /// Every rendition of the ladder is encoded in the same pass and written
+    /// as a variant playlist next to a master playlist that references them
+    /// all. The recording keeps running until [Recording::stop] is called or
+    /// the returned handle is dropped.
     #[instrument(skip_all, fields(filestem = file_stem.as_ref()))]
-    pub fn start(&self, file_stem: impl AsRef<str>) -> anyhow::Result<()> {
+    pub fn start(&self, file_stem: impl AsRef<str>) -> anyhow::Result<Recording> {
         let segment_folder = self.root.join(file_stem.as_ref());
         fs::create_dir_all(&segment_folder)?;
 
@@ -41,14 +255,29 @@ impl ScreenGrabber {
             .with_extension(M3U8_EXT);
         info!("starting new playlist at {}", playlist.to_string_lossy());
 
-        unsafe {
-            let capture_card = CString::new(self.capture_name.clone())?;
-            let clist = CString::new(playlist.as_os_str().as_bytes())?;
-            if screen_grabber_start(capture_card.as_ptr(), clist.as_ptr()) != 0 {
-                bail!("error occurred starting screen grab");
-            }
+        let (kind, input) = self.source.to_ffi()?;
+        let clist = CString::new(playlist.as_os_str().as_bytes())?;
+        let settings = self.settings.as_raw();
+        let mut raw = std::ptr::null_mut();
+        let status = unsafe {
+            screen_grabber_open(kind, input.as_ptr(), clist.as_ptr(), &settings, &mut raw)
+        };
+        if status != 0 || raw.is_null() {
+            bail!("error occurred starting screen grab ({})", status);
         }
-        Ok(())
+
+        let grabber = Arc::new(GrabberPtr(raw));
+        let thread_grabber = grabber.clone();
+        let worker = std::thread::Builder::new()
+            .name(format!("grab-{}", file_stem.as_ref()))
+            .spawn(move || unsafe { screen_grabber_run(thread_grabber.0) })
+            .context("could not spawn capture thread")?;
+
+        Ok(Recording {
+            playlist,
+            grabber,
+            worker: Some(worker),
+        })
     }
 }
 
@@ -60,12 +289,111 @@ impl Drop for ScreenGrabber {
     }
 }
 
+/// Handle to a recording that is captured on a background thread.
+pub struct Recording {
+    playlist: PathBuf,
+    grabber: Arc<GrabberPtr>,
+    worker: Option<JoinHandle<i32>>,
+}
+
+impl Recording {
+    /// Path of the HLS master playlist this recording writes to.
+    pub fn playlist(&self) -> &Path {
+        &self.playlist
+    }
+
+    /// Returns true when the capture thread exited, either because it was
+    /// stopped or because an error occurred.
+    pub fn is_finished(&self) -> bool {
+        self.worker.as_ref().is_none_or(JoinHandle::is_finished)
+    }
+
+    /// Stops the recording and waits until the encoder is flushed and the
+    /// playlist is finalized with `#EXT-X-ENDLIST`. Returns an error when the
+    /// capture thread exited with a non-zero status.
+    pub fn stop(mut self) -> anyhow::Result<()> {
+        self.finish()
+    }
+
+    fn finish(&mut self) -> anyhow::Result<()> {
+        let Some(worker) = self.worker.take() else {
+            return Ok(());
+        };
+
+        // recordings made up by tests have no native grabber
+        if !self.grabber.0.is_null() {
+            unsafe {
+                screen_grabber_stop(self.grabber.0);
+            }
+        }
+
+        let status = worker
+            .join()
+            .map_err(|_| anyhow::anyhow!("capture thread panicked"))?;
+        if status != 0 {
+            bail!(
+                "recording {} exited with status {}",
+                self.playlist.to_string_lossy(),
+                status
+            );
+        }
+
+        info!("stopped recording {}", self.playlist.to_string_lossy());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+impl Recording {
+    /// A recording whose capture thread exited with `status`.
+    pub(crate) fn exited(playlist: PathBuf, status: i32) -> Self {
+        Recording {
+            playlist,
+            grabber: Arc::new(GrabberPtr(std::ptr::null_mut())),
+            worker: Some(std::thread::spawn(move || status)),
+        }
+    }
+}
+
+/// Records `seconds` of the test pattern at 320x180 and 25 fps, with a
+/// keyframe every second, below `root`. Returns the master playlist.
+#[cfg(test)]
+pub(crate) fn record_test_pattern(root: &Path, file_stem: &str, seconds: u64) -> PathBuf {
+    let settings = CaptureSettings {
+        fps: 25,
+        segment_duration: 1,
+        gop_size: 25,
+        ladder: vec![Rendition {
+            width: 320,
+            height: 180,
+            bit_rate: 300_000,
+        }],
+        ..Default::default()
+    };
+    let grabber =
+        ScreenGrabber::new(CaptureSource::TestPattern, root.to_path_buf(), settings).unwrap();
+    let recording = grabber.start(file_stem).unwrap();
+    std::thread::sleep(std::time::Duration::from_secs(seconds));
+    let master = recording.playlist().to_path_buf();
+    recording.stop().unwrap();
+    master
+}
+
+impl Drop for Recording {
+    fn drop(&mut self) {
+        if let Err(e) = self.finish() {
+            warn!("{:#}", e);
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use tracing::Level;
 
-    use super::ScreenGrabber;
-    use std::{path::PathBuf, str::FromStr};
+    use super::{CaptureSettings, CaptureSource, Rendition, ScreenGrabber};
+    use std::{fs, time::Duration};
+    use tempdir::TempDir;
 
     pub fn with_tracing<T>(f: impl FnOnce() -> T) -> T {
         let subscriber = tracing_subscriber::fmt()
@@ -76,15 +404,97 @@ mod tests {
         tracing::subscriber::with_default(subscriber, f)
     }
 
+    #[test]
+    fn settings_from_config() {
+        let capture: ronaldos_config::Capture =
+            serde_yaml::from_str("fps: 30\nwidth: 1280\nheight: 720\nbitrate: 2000000\nladder: []")
+                .unwrap();
+        let settings = CaptureSettings::try_from(&capture).unwrap();
+        assert_eq!(30, settings.fps);
+        assert_eq!(10, settings.segment_duration);
+        assert_eq!(
+            vec![Rendition {
+                width: 1280,
+                height: 720,
+                bit_rate: 2_000_000
+            }],
+            settings.ladder
+        );
+        assert_eq!(c"auto", settings.audio_device.as_c_str());
+        assert_eq!(128_000, settings.audio_bitrate);
+
+        let capture: ronaldos_config::Capture = serde_yaml::from_str(
+            "width: 1280\nheight: 720\nladder: [{width: 960, height: 720, bitrate: 1000000}]",
+        )
+        .unwrap();
+        assert!(CaptureSettings::try_from(&capture).is_err());
+    }
+
     #[test]
     fn no_devices() {
         with_tracing(|| {
+            let temp = TempDir::new("grabber").unwrap();
+            let grabber = ScreenGrabber::new(
+                CaptureSource::Device("Non Existing Capture Card".to_string()),
+                temp.path().to_path_buf(),
+                CaptureSettings::default(),
+            )
+            .unwrap();
+            assert!(grabber.start("sven").is_err());
+        });
+    }
+
+    #[test]
+    fn test_pattern() {
+        with_tracing(|| {
+            let temp = TempDir::new("grabber").unwrap();
+            let settings = CaptureSettings {
+                fps: 25,
+                segment_duration: 1,
+                gop_size: 25,
+                ladder: vec![
+                    Rendition {
+                        width: 640,
+                        height: 360,
+                        bit_rate: 800_000,
+                    },
+                    Rendition {
+                        width: 320,
+                        height: 180,
+                        bit_rate: 300_000,
+                    },
+                ],
+                ..Default::default()
+            };
             let grabber = ScreenGrabber::new(
-                "Game Capture HD60 S+".to_string(),
-                PathBuf::from_str("/tmp/test").unwrap(),
+                CaptureSource::TestPattern,
+                temp.path().to_path_buf(),
+                settings,
             )
             .unwrap();
-            grabber.start("sven").unwrap();
+            let recording = grabber.start("pattern").unwrap();
+            std::thread::sleep(Duration::from_secs(3));
+            assert!(!recording.is_finished());
+
+            let master = recording.playlist().to_path_buf();
+            recording.stop().unwrap();
+
+            let content = fs::read_to_string(&master).unwrap();
+            assert_eq!(2, content.matches("#EXT-X-STREAM-INF").count());
+            assert!(content.contains("pattern_360p.m3u8"));
+            assert!(content.contains("pattern_180p.m3u8"));
+            // the sine tone of the test pattern is muxed as AAC
+            assert!(content.contains("mp4a.40.2"));
+
+            let variant =
+                fs::read_to_string(temp.path().join("pattern/pattern_180p.m3u8")).unwrap();
+            assert!(variant.contains("#EXT-X-ENDLIST"));
+            let segments = fs::read_dir(master.parent().unwrap())
+                .unwrap()
+                .filter_map(Result::ok)
+                .filter(|e| e.path().extension().is_some_and(|ext| ext == "ts"))
+                .count();
+            assert!(segments >= 2);
         });
     }
 }
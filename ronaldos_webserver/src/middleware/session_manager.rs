@@ -1,9 +1,9 @@
 use actix_web::{http, HttpRequest};
 use base64::engine::general_purpose::URL_SAFE;
 use base64::Engine;
-use tracing::debug;
 use ronaldos_config::Login;
 use std::ops::Add;
+use tracing::debug;
 
 const SESSION_ID_KEY: &str = "Session_id";
 
@@ -13,11 +13,21 @@ pub enum PermissionResult {
     AuthenticationNeeded,
     Ok,
 }
+/// Paths that do not need a session. The admin api checks its own bearer
+/// token.
 fn allow_list(path: &str) -> bool {
     path == "/favicon.ico"
         || path == "/login.html"
         || path == "/dologin"
         || path.to_string().starts_with("/.well-known")
+        || path.starts_with("/admin/")
+}
+
+/// Uploads of encoders to the stream scope carry an ingest token instead of
+/// a session, see [crate::services::stream_service::Uploader].
+fn is_upload(request: &HttpRequest) -> bool {
+    matches!(*request.method(), http::Method::PUT | http::Method::DELETE)
+        && request.path().starts_with("/streams/")
 }
 
 #[derive(Debug, Clone)]
@@ -43,7 +53,7 @@ impl SessionMananger {
     /// * None if request is allowed
     /// * Some(response) containing a response why the request is not allowed
     pub fn has_permission(&self, request: &HttpRequest) -> PermissionResult {
-        if allow_list(request.path()) {
+        if allow_list(request.path()) || is_upload(request) {
             return PermissionResult::Ok;
         }
 
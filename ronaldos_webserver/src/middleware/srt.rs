@@ -0,0 +1,220 @@
+//! SRT ingest. Encoders send MPEG-TS over SRT, either by calling one of our
+//! listening endpoints or by listening themselves while we call them. Every
+//! connection is remuxed into HLS in the stream directory and registered as
+//! a live stream until the encoder disconnects.
+use super::stream_remuxer::{ingest_playlist, Remuxer};
+use super::LocalStreamStore;
+use anyhow::ensure;
+use chrono::Utc;
+use ronaldos_config::{SrtEndpoint, SrtMode};
+use std::{path::PathBuf, sync::Arc, time::Duration};
+use tokio::{sync::RwLock, task::JoinHandle};
+use tracing::{debug, info, warn};
+
+/// pause before listening or calling again after a failed connection
+const RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Ingests the streams of one SRT endpoint, one connection at a time.
+pub struct SrtIngest {
+    endpoint: SrtEndpoint,
+    root: PathBuf,
+    segment_duration: i32,
+    stream_store: Arc<RwLock<LocalStreamStore>>,
+}
+
+impl SrtIngest {
+    pub fn new(
+        config: &ronaldos_config::Srt,
+        endpoint: &SrtEndpoint,
+        root: PathBuf,
+        stream_store: Arc<RwLock<LocalStreamStore>>,
+    ) -> anyhow::Result<Self> {
+        // libsrt refuses other lengths only once a peer connects
+        let passphrase = endpoint.passphrase.chars().count();
+        ensure!(
+            passphrase == 0 || (10..=79).contains(&passphrase),
+            "passphrase of srt endpoint {} must have 10 to 79 characters",
+            endpoint.name
+        );
+
+        Ok(SrtIngest {
+            endpoint: endpoint.clone(),
+            root,
+            segment_duration: config.segment_duration.try_into()?,
+            stream_store,
+        })
+    }
+
+    /// Ingests connections until the application stops.
+    pub fn run(self) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            info!(
+                "srt endpoint {} on {} ({:?})",
+                self.endpoint.name, self.endpoint.address, self.endpoint.mode
+            );
+            loop {
+                if let Err(e) = self.ingest().await {
+                    warn!("srt endpoint {}: {:#}", self.endpoint.name, e);
+                    tokio::time::sleep(RETRY_INTERVAL).await;
+                }
+            }
+        })
+    }
+
+    /// Waits for a connection, registers it as live stream and remuxes it
+    /// until the encoder disconnects.
+    async fn ingest(&self) -> anyhow::Result<()> {
+        let url = self.url();
+        let options = self.options();
+        debug!("opening {}", url);
+        let remuxer =
+            tokio::task::spawn_blocking(move || Remuxer::open_url(c"mpegts", &url, &options))
+                .await??;
+
+        let started = Utc::now();
+        let playlist = ingest_playlist(&self.root, &self.endpoint.name, started)?;
+        let relative = playlist.strip_prefix(&self.root)?.to_path_buf();
+        let uuid = self
+            .stream_store
+            .read()
+            .await
+            .register(self.endpoint.name.clone(), vec![relative], started, None)
+            .await?;
+        info!("{} connected as {}", self.endpoint.name, uuid);
+
+        let segment_duration = self.segment_duration;
+        let remuxed =
+            tokio::task::spawn_blocking(move || remuxer.run(&playlist, segment_duration)).await?;
+        let finished = LocalStreamStore::finish_stream(&self.stream_store, &uuid).await;
+        info!("{} disconnected", uuid);
+        remuxed.and(finished)
+    }
+
+    fn url(&self) -> String {
+        format!("srt://{}", self.endpoint.address)
+    }
+
+    fn options(&self) -> Vec<(&'static str, String)> {
+        let mode = match self.endpoint.mode {
+            SrtMode::Listener => "listener",
+            SrtMode::Caller => "caller",
+        };
+        let mut options = vec![
+            ("mode", mode.to_string()),
+            // microseconds
+            (
+                "latency",
+                (u64::from(self.endpoint.latency_ms) * 1000).to_string(),
+            ),
+        ];
+        if !self.endpoint.passphrase.is_empty() {
+            options.push(("passphrase", self.endpoint.passphrase.clone()));
+        }
+        options
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{fs, net::UdpSocket, process::Command, str::FromStr};
+    use tempdir::TempDir;
+
+    fn endpoint(address: &str, passphrase: &str) -> SrtEndpoint {
+        SrtEndpoint {
+            name: "camera".to_string(),
+            address: address.to_string(),
+            mode: SrtMode::Listener,
+            passphrase: passphrase.to_string(),
+            latency_ms: 120,
+        }
+    }
+
+    fn ingest(temp: &TempDir, endpoint: &SrtEndpoint) -> anyhow::Result<SrtIngest> {
+        let store =
+            LocalStreamStore::new(temp.path().into(), PathBuf::from_str("/streams").unwrap());
+        SrtIngest::new(
+            &ronaldos_config::Srt::default(),
+            endpoint,
+            temp.path().into(),
+            Arc::new(RwLock::new(store)),
+        )
+    }
+
+    #[test]
+    fn options() {
+        let temp = TempDir::new("srt").unwrap();
+        assert!(ingest(&temp, &endpoint("0.0.0.0:9000", "short")).is_err());
+
+        let mut caller = endpoint("encoder.local:9000", "correct horse battery");
+        caller.mode = SrtMode::Caller;
+        let srt = ingest(&temp, &caller).unwrap();
+        assert_eq!("srt://encoder.local:9000", srt.url());
+        assert_eq!(
+            vec![
+                ("mode", "caller".to_string()),
+                ("latency", "120000".to_string()),
+                ("passphrase", "correct horse battery".to_string()),
+            ],
+            srt.options()
+        );
+    }
+
+    /// Needs ffmpeg built with libsrt: pushes a short file over a loopback
+    /// SRT connection and expects a finished stream.
+    #[tokio::test]
+    #[ignore]
+    async fn loopback() {
+        let temp = TempDir::new("srt").unwrap();
+        let source = temp.path().join("source.ts");
+        let status = Command::new("ffmpeg")
+            .args(["-loglevel", "error", "-f", "lavfi", "-i"])
+            .arg("testsrc=duration=6:size=320x240:rate=25")
+            .args(["-f", "lavfi", "-i", "sine=duration=6"])
+            .args(["-c:v", "libx264", "-g", "25", "-c:a", "aac", "-f", "mpegts"])
+            .arg(&source)
+            .status()
+            .unwrap();
+        assert!(status.success());
+
+        let port = UdpSocket::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let passphrase = "loopback-passphrase";
+        let srt = ingest(&temp, &endpoint(&format!("127.0.0.1:{}", port), passphrase)).unwrap();
+        let ingest = tokio::spawn(async move { srt.ingest().await });
+        // give the listener time to bind
+        tokio::time::sleep(Duration::from_millis(500)).await;
+
+        let url = format!(
+            "srt://127.0.0.1:{}?mode=caller&passphrase={}",
+            port, passphrase
+        );
+        let encoder = tokio::task::spawn_blocking(move || {
+            Command::new("ffmpeg")
+                .args(["-loglevel", "error", "-re", "-i"])
+                .arg(&source)
+                .args(["-c", "copy", "-f", "mpegts"])
+                .arg(url)
+                .status()
+        });
+        assert!(encoder.await.unwrap().unwrap().success());
+        ingest.await.unwrap().unwrap();
+
+        let meta = fs::read_dir(temp.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .find(|path| path.extension().is_some_and(|e| e == "stream"))
+            .unwrap();
+        let meta: serde_yaml::Value =
+            serde_yaml::from_str(&fs::read_to_string(meta).unwrap()).unwrap();
+        assert_eq!(Some(false), meta["live"].as_bool());
+        let source = meta["sources"][0].as_str().unwrap();
+        assert!(source.starts_with("camera_"));
+        let playlist = fs::read_to_string(temp.path().join(source)).unwrap();
+        assert!(playlist.contains("#EXT-X-PLAYLIST-TYPE:VOD"));
+        assert!(playlist.ends_with("#EXT-X-ENDLIST\n"));
+    }
+}
@@ -0,0 +1,230 @@
+//! Remuxes ingested streams into HLS without transcoding. The container data
+//! is either read by the native remuxer in stream_remuxer.cpp from a URL, or
+//! handed to it through a channel.
+use anyhow::ensure;
+use chrono::{DateTime, Utc};
+use std::{
+    ffi::{c_char, c_void, CStr, CString},
+    fs, io,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+    ptr,
+};
+use tokio::sync::mpsc::Receiver;
+
+type RemuxRead = unsafe extern "C" fn(opaque: *mut c_void, buffer: *mut u8, size: i32) -> i32;
+
+#[repr(C)]
+struct RawRemuxer {
+    _private: [u8; 0],
+}
+
+extern "C" {
+    fn stream_remuxer_open(
+        format: *const c_char,
+        url: *const c_char,
+        keys: *const *const c_char,
+        values: *const *const c_char,
+        options_len: usize,
+        read: Option<RemuxRead>,
+        opaque: *mut c_void,
+        remuxer: *mut *mut RawRemuxer,
+    ) -> i32;
+    fn stream_remuxer_output(
+        remuxer: *mut RawRemuxer,
+        output: *const c_char,
+        segment_duration: i32,
+    ) -> i32;
+    fn stream_remuxer_run(remuxer: *mut RawRemuxer) -> i32;
+    fn stream_remuxer_free(remuxer: *mut RawRemuxer);
+}
+
+/// An opened input whose streams are known. Opening blocks until the input
+/// delivered enough data to probe it, so it has to happen on a blocking
+/// thread, as does `run`.
+pub struct Remuxer {
+    raw: *mut RawRemuxer,
+    /// read by the native remuxer for channel inputs, boxed so its address
+    /// stays stable
+    _reader: Option<Box<ChannelReader>>,
+}
+
+// the native remuxer is only used by one thread at a time
+unsafe impl Send for Remuxer {}
+
+impl Remuxer {
+    /// Opens `url`, `options` are passed to the protocol, e.g. the SRT
+    /// `mode` or `passphrase`.
+    pub fn open_url(format: &CStr, url: &str, options: &[(&str, String)]) -> anyhow::Result<Self> {
+        let url = CString::new(url)?;
+        let keys = options
+            .iter()
+            .map(|(key, _)| CString::new(*key))
+            .collect::<Result<Vec<_>, _>>()?;
+        let values = options
+            .iter()
+            .map(|(_, value)| CString::new(value.as_str()))
+            .collect::<Result<Vec<_>, _>>()?;
+        let key_ptrs: Vec<_> = keys.iter().map(|k| k.as_ptr()).collect();
+        let value_ptrs: Vec<_> = values.iter().map(|v| v.as_ptr()).collect();
+
+        let mut raw = ptr::null_mut();
+        let status = unsafe {
+            stream_remuxer_open(
+                format.as_ptr(),
+                url.as_ptr(),
+                key_ptrs.as_ptr(),
+                value_ptrs.as_ptr(),
+                options.len(),
+                None,
+                ptr::null_mut(),
+                &mut raw,
+            )
+        };
+        ensure!(status == 0, "could not open {:?} ({})", url, status);
+        Ok(Remuxer { raw, _reader: None })
+    }
+
+    /// Opens the `format` stream received over `input`. The input ends when
+    /// the sending side is dropped.
+    pub fn open_channel(format: &CStr, input: Receiver<Vec<u8>>) -> anyhow::Result<Self> {
+        let mut reader = Box::new(ChannelReader {
+            receiver: input,
+            pending: Vec::new(),
+            offset: 0,
+        });
+
+        let mut raw = ptr::null_mut();
+        let status = unsafe {
+            stream_remuxer_open(
+                format.as_ptr(),
+                ptr::null(),
+                ptr::null(),
+                ptr::null(),
+                0,
+                Some(read_channel),
+                reader.as_mut() as *mut ChannelReader as *mut c_void,
+                &mut raw,
+            )
+        };
+        ensure!(
+            status == 0,
+            "could not open {:?} input ({})",
+            format,
+            status
+        );
+        Ok(Remuxer {
+            raw,
+            _reader: Some(reader),
+        })
+    }
+
+    /// Remuxes the input into the HLS playlist `playlist` until it ends.
+    pub fn run(self, playlist: &Path, segment_duration: i32) -> anyhow::Result<()> {
+        let output = CString::new(playlist.as_os_str().as_bytes())?;
+        let status = unsafe { stream_remuxer_output(self.raw, output.as_ptr(), segment_duration) };
+        ensure!(
+            status == 0,
+            "could not create {} ({})",
+            playlist.to_string_lossy(),
+            status
+        );
+
+        let status = unsafe { stream_remuxer_run(self.raw) };
+        ensure!(
+            status == 0,
+            "remuxing to {} failed ({})",
+            playlist.to_string_lossy(),
+            status
+        );
+        Ok(())
+    }
+}
+
+impl Drop for Remuxer {
+    fn drop(&mut self) {
+        unsafe { stream_remuxer_free(self.raw) };
+    }
+}
+
+/// Blocking reader over the chunks sent by the ingest task.
+struct ChannelReader {
+    receiver: Receiver<Vec<u8>>,
+    pending: Vec<u8>,
+    offset: usize,
+}
+
+impl ChannelReader {
+    /// returns 0 once the sender is dropped and everything is read
+    fn read(&mut self, buffer: &mut [u8]) -> usize {
+        while self.offset >= self.pending.len() {
+            match self.receiver.blocking_recv() {
+                Some(chunk) => {
+                    self.pending = chunk;
+                    self.offset = 0;
+                }
+                None => return 0,
+            }
+        }
+
+        let len = buffer.len().min(self.pending.len() - self.offset);
+        buffer[..len].copy_from_slice(&self.pending[self.offset..self.offset + len]);
+        self.offset += len;
+        len
+    }
+}
+
+unsafe extern "C" fn read_channel(opaque: *mut c_void, buffer: *mut u8, size: i32) -> i32 {
+    let reader = &mut *(opaque as *mut ChannelReader);
+    let buffer = std::slice::from_raw_parts_mut(buffer, size.max(0) as usize);
+    reader.read(buffer) as i32
+}
+
+/// Creates the directory of a stream ingested as `name` and returns the
+/// path of its playlist, `root/<name>_<time>/<name>_<time>.m3u8`.
+pub fn ingest_playlist(root: &Path, name: &str, started: DateTime<Utc>) -> io::Result<PathBuf> {
+    let stem = format!("{}_{}", name, started.format("%Y%m%d_%H%M%S"));
+    let directory = root.join(&stem);
+    fs::create_dir_all(&directory)?;
+    Ok(directory.join(stem).with_extension("m3u8"))
+}
+
+/// Remuxes the `format` stream received over `input` into the HLS playlist
+/// `playlist`. Blocks until the sending side is dropped, so it has to run on
+/// a blocking thread.
+pub fn remux(
+    format: &CStr,
+    input: Receiver<Vec<u8>>,
+    playlist: &Path,
+    segment_duration: i32,
+) -> anyhow::Result<()> {
+    Remuxer::open_channel(format, input)?.run(playlist, segment_duration)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn channel_reader() {
+        let (sender, receiver) = tokio::sync::mpsc::channel(4);
+        sender.blocking_send(vec![1, 2, 3]).unwrap();
+        sender.blocking_send(vec![]).unwrap();
+        sender.blocking_send(vec![4]).unwrap();
+        drop(sender);
+
+        let mut reader = ChannelReader {
+            receiver,
+            pending: Vec::new(),
+            offset: 0,
+        };
+        let mut buffer = [0u8; 2];
+        assert_eq!(2, reader.read(&mut buffer));
+        assert_eq!([1, 2], buffer);
+        assert_eq!(1, reader.read(&mut buffer));
+        assert_eq!(3, buffer[0]);
+        assert_eq!(1, reader.read(&mut buffer));
+        assert_eq!(4, buffer[0]);
+        assert_eq!(0, reader.read(&mut buffer));
+    }
+}
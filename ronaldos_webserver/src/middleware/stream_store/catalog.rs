@@ -0,0 +1,397 @@
+//! SQLite index of the `.stream` files. The files stay the source of truth,
+//! the catalog mirrors them so streams can be filtered, sorted and paged
+//! without walking the whole stream map.
+use super::data_types::Stream;
+use anyhow::{Context, Result};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::{DateTime, Utc};
+use rusqlite::{params, params_from_iter, types::Value, Connection};
+use serde::{de, Deserialize, Deserializer};
+use std::{
+    fmt, fs,
+    path::Path,
+    str::FromStr,
+    sync::{Mutex, MutexGuard},
+};
+use uuid::Uuid;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS streams (
+        uuid TEXT PRIMARY KEY NOT NULL,
+        path TEXT NOT NULL,
+        description TEXT NOT NULL,
+        date INTEGER NOT NULL,
+        live INTEGER NOT NULL,
+        fixture_id INTEGER
+    );
+    CREATE INDEX IF NOT EXISTS streams_date ON streams (date, uuid);
+    CREATE INDEX IF NOT EXISTS streams_fixture_id ON streams (fixture_id);
+";
+
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    #[default]
+    Newest,
+    Oldest,
+}
+
+/// Filters of a catalog query, all of them optional. Without a limit every
+/// match is returned.
+#[derive(Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(default)]
+pub struct StreamQuery {
+    /// streams at or after this date
+    pub from: Option<DateTime<Utc>>,
+    /// streams before this date
+    pub to: Option<DateTime<Utc>>,
+    pub fixture_id: Option<u64>,
+    pub live: Option<bool>,
+    /// case insensitive text in the description
+    #[serde(rename = "q")]
+    pub search: Option<String>,
+    pub sort: SortOrder,
+    pub limit: Option<u32>,
+    /// continues after the last stream of the previous page
+    pub cursor: Option<Cursor>,
+}
+
+/// Position after the last stream of a page. Pages continue from the stream
+/// itself rather than from an offset, so streams that are added or removed
+/// meanwhile do not shift the following pages.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Cursor {
+    date: i64,
+    uuid: Uuid,
+}
+
+impl fmt::Display for Cursor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let raw = format!("{}/{}", self.date, self.uuid);
+        f.write_str(&URL_SAFE_NO_PAD.encode(raw))
+    }
+}
+
+impl FromStr for Cursor {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        const INVALID: &str = "invalid cursor";
+        let raw = URL_SAFE_NO_PAD.decode(s).map_err(|_| INVALID)?;
+        let raw = String::from_utf8(raw).map_err(|_| INVALID)?;
+        let (date, uuid) = raw.split_once('/').ok_or(INVALID)?;
+        Ok(Cursor {
+            date: date.parse().map_err(|_| INVALID)?,
+            uuid: uuid.parse().map_err(|_| INVALID)?,
+        })
+    }
+}
+
+impl<'de> Deserialize<'de> for Cursor {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let cursor = String::deserialize(deserializer)?;
+        cursor.parse().map_err(de::Error::custom)
+    }
+}
+
+/// One page of a query, `total` counts the matches on all pages. `next` is
+/// set when there are more pages.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Page<T> {
+    pub total: u64,
+    pub items: Vec<T>,
+    pub next: Option<Cursor>,
+}
+
+pub struct Catalog {
+    // a connection can be sent but not shared between threads
+    connection: Mutex<Connection>,
+}
+
+impl Catalog {
+    /// Opens or creates the catalog at `path`.
+    pub fn open(path: &Path) -> Result<Self> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let connection = Connection::open(path)
+            .with_context(|| format!("could not open catalog {}", path.to_string_lossy()))?;
+        Self::with_connection(connection)
+    }
+
+    /// A catalog that lives as long as the store, e.g. for tests.
+    pub fn in_memory() -> Result<Self> {
+        Self::with_connection(Connection::open_in_memory()?)
+    }
+
+    fn with_connection(connection: Connection) -> Result<Self> {
+        connection
+            .execute_batch(SCHEMA)
+            .context("could not create catalog schema")?;
+        Ok(Catalog {
+            connection: Mutex::new(connection),
+        })
+    }
+
+    fn connection(&self) -> MutexGuard<'_, Connection> {
+        // a panic halfway a statement leaves sqlite consistent
+        self.connection
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Inserts or replaces the rows of `streams` in a single transaction.
+    pub fn upsert<'a>(
+        &self,
+        streams: impl IntoIterator<Item = (&'a Path, &'a Stream)>,
+    ) -> Result<()> {
+        let mut connection = self.connection();
+        let transaction = connection.transaction()?;
+        {
+            let mut statement = transaction.prepare_cached(
+                "INSERT OR REPLACE INTO streams (uuid, path, description, date, live, fixture_id)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            )?;
+            for (path, stream) in streams {
+                statement.execute(params![
+                    stream.uuid.to_string(),
+                    path.to_string_lossy(),
+                    stream.description,
+                    stream.date.timestamp(),
+                    stream.live.unwrap_or(false),
+                    stream.fixture_id,
+                ])?;
+            }
+        }
+        transaction.commit()?;
+        Ok(())
+    }
+
+    pub fn remove(&self, uuid: &Uuid) -> Result<()> {
+        self.connection()
+            .execute("DELETE FROM streams WHERE uuid = ?1", [uuid.to_string()])?;
+        Ok(())
+    }
+
+    /// Drops the rows of streams that are not in `known`, e.g. of `.stream`
+    /// files that were removed while the server was down.
+    pub fn retain<'a>(&self, known: impl IntoIterator<Item = &'a Uuid>) -> Result<usize> {
+        let mut connection = self.connection();
+        let transaction = connection.transaction()?;
+        transaction
+            .execute_batch("CREATE TEMP TABLE IF NOT EXISTS known (uuid TEXT PRIMARY KEY)")?;
+        transaction.execute("DELETE FROM temp.known", [])?;
+        {
+            let mut statement =
+                transaction.prepare("INSERT OR IGNORE INTO temp.known VALUES (?1)")?;
+            for uuid in known {
+                statement.execute([uuid.to_string()])?;
+            }
+        }
+        let removed = transaction.execute(
+            "DELETE FROM streams WHERE uuid NOT IN (SELECT uuid FROM temp.known)",
+            [],
+        )?;
+        transaction.commit()?;
+        Ok(removed)
+    }
+
+    pub fn query(&self, query: &StreamQuery) -> Result<Page<Uuid>> {
+        let mut conditions = Vec::new();
+        let mut values = Vec::new();
+        if let Some(from) = query.from {
+            conditions.push("date >= ?");
+            values.push(Value::Integer(from.timestamp()));
+        }
+        if let Some(to) = query.to {
+            conditions.push("date < ?");
+            values.push(Value::Integer(to.timestamp()));
+        }
+        if let Some(fixture_id) = query.fixture_id {
+            conditions.push("fixture_id = ?");
+            values.push(Value::Integer(fixture_id.try_into()?));
+        }
+        if let Some(live) = query.live {
+            conditions.push("live = ?");
+            values.push(Value::Integer(live.into()));
+        }
+        if let Some(search) = query.search.as_deref().filter(|s| !s.is_empty()) {
+            conditions.push("description LIKE ? ESCAPE '\\'");
+            values.push(Value::Text(format!("%{}%", escape_like(search))));
+        }
+
+        let connection = self.connection();
+        let total: u64 = connection.query_row(
+            &format!("SELECT COUNT(*) FROM streams {}", where_clause(&conditions)),
+            params_from_iter(&values),
+            |row| row.get(0),
+        )?;
+
+        // uuid orders streams of the same date, so every row has a unique
+        // position for the cursor
+        let (order, after_cursor) = match query.sort {
+            SortOrder::Newest => ("DESC", "(date, uuid) < (?, ?)"),
+            SortOrder::Oldest => ("ASC", "(date, uuid) > (?, ?)"),
+        };
+        if let Some(cursor) = query.cursor {
+            conditions.push(after_cursor);
+            values.push(Value::Integer(cursor.date));
+            values.push(Value::Text(cursor.uuid.to_string()));
+        }
+        let sql = format!(
+            "SELECT uuid, date FROM streams {} ORDER BY date {order}, uuid {order} LIMIT ?",
+            where_clause(&conditions),
+            order = order
+        );
+        // one more row tells whether there is a next page
+        values.push(Value::Integer(query.limit.map_or(-1, |l| i64::from(l) + 1)));
+
+        let mut statement = connection.prepare(&sql)?;
+        let mut rows = statement
+            .query_map(params_from_iter(&values), |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)?))
+            })?
+            .map(|row| {
+                let (uuid, date) = row?;
+                Ok(Cursor {
+                    date,
+                    uuid: Uuid::parse_str(&uuid)?,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let next = match query.limit {
+            Some(limit) if rows.len() > limit as usize => {
+                rows.truncate(limit as usize);
+                rows.last().copied()
+            }
+            _ => None,
+        };
+        Ok(Page {
+            total,
+            items: rows.into_iter().map(|row| row.uuid).collect(),
+            next,
+        })
+    }
+}
+
+fn where_clause(conditions: &[&str]) -> String {
+    if conditions.is_empty() {
+        return String::new();
+    }
+    format!("WHERE {}", conditions.join(" AND "))
+}
+
+fn escape_like(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if matches!(c, '%' | '_' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use std::path::PathBuf;
+
+    fn stream(description: &str, day: u32, live: bool, fixture_id: Option<u64>) -> Stream {
+        Stream {
+            date: Utc.with_ymd_and_hms(2024, 5, day, 12, 0, 0).unwrap(),
+            live: Some(live),
+            fixture_id,
+            ..Stream::new(description, Vec::new())
+        }
+    }
+
+    #[test]
+    fn queries() {
+        let catalog = Catalog::in_memory().unwrap();
+        let streams = [
+            stream("Ajax - PSV", 1, false, Some(7)),
+            stream("Feyenoord - Ajax", 2, false, Some(8)),
+            stream("training 100%", 3, true, None),
+            stream("rondo", 4, false, None),
+        ];
+        let path = PathBuf::from("/videos/x.stream");
+        catalog
+            .upsert(streams.iter().map(|s| (path.as_path(), s)))
+            .unwrap();
+        let uuids =
+            |indices: &[usize]| indices.iter().map(|&i| streams[i].uuid).collect::<Vec<_>>();
+
+        let all = catalog.query(&StreamQuery::default()).unwrap();
+        assert_eq!(4, all.total);
+        assert_eq!(uuids(&[3, 2, 1, 0]), all.items);
+        assert_eq!(None, all.next);
+
+        let mut query = StreamQuery {
+            sort: SortOrder::Oldest,
+            limit: Some(2),
+            ..Default::default()
+        };
+        let first = catalog.query(&query).unwrap();
+        assert_eq!(uuids(&[0, 1]), first.items);
+        // pages do not shift when older streams disappear
+        catalog.remove(&streams[0].uuid).unwrap();
+        query.cursor = first.next;
+        let second = catalog.query(&query).unwrap();
+        assert_eq!(3, second.total);
+        assert_eq!(uuids(&[2, 3]), second.items);
+        assert_eq!(None, second.next);
+        catalog.upsert([(path.as_path(), &streams[0])]).unwrap();
+
+        let cursor = first.next.unwrap();
+        assert_eq!(Ok(cursor), cursor.to_string().parse());
+        assert!("bm9wZQ".parse::<Cursor>().is_err());
+
+        let search = |search: &str| {
+            catalog
+                .query(&StreamQuery {
+                    search: Some(search.to_string()),
+                    ..Default::default()
+                })
+                .unwrap()
+                .items
+        };
+        assert_eq!(uuids(&[1, 0]), search("ajax"));
+        assert_eq!(uuids(&[2]), search("100%"));
+        assert_eq!(uuids(&[]), search("1_0"));
+
+        let filtered = catalog
+            .query(&StreamQuery {
+                from: Some(streams[1].date),
+                to: Some(streams[3].date),
+                live: Some(false),
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!(uuids(&[1]), filtered.items);
+        let fixture = StreamQuery {
+            fixture_id: Some(7),
+            ..Default::default()
+        };
+        assert_eq!(uuids(&[0]), catalog.query(&fixture).unwrap().items);
+
+        // replaced rows are updated, unknown rows dropped
+        let mut finished = streams[2].clone();
+        finished.live = Some(false);
+        catalog.upsert([(path.as_path(), &finished)]).unwrap();
+        catalog.remove(&streams[3].uuid).unwrap();
+        assert_eq!(
+            1,
+            catalog
+                .retain([&streams[0].uuid, &streams[2].uuid])
+                .unwrap()
+        );
+        let live = StreamQuery {
+            live: Some(false),
+            ..Default::default()
+        };
+        assert_eq!(uuids(&[2, 0]), catalog.query(&live).unwrap().items);
+    }
+}
@@ -0,0 +1,316 @@
+//! Clips cut from the recordings of streams. A clip is a stream of its own
+//! in a directory below `clips/` in the root. It links to the stream it was
+//! cut from, shares its fixture and keeps the markers within its range.
+use super::{data_types::*, images::video_source, vod, LocalStreamStore};
+use crate::middleware::clipper;
+use anyhow::ensure;
+use chrono::{DateTime, TimeDelta, Utc};
+use serde::Deserialize;
+use std::{fs, path::PathBuf};
+use tokio::sync::RwLock;
+use tracing::info;
+use uuid::Uuid;
+
+const CLIPS_DIR: &str = "clips";
+const CLIP_PLAYLIST: &str = "clip.m3u8";
+
+/// Range of a stream to cut, as requested through the admin api.
+#[derive(Deserialize, Debug)]
+pub struct ClipRequest {
+    /// seconds from the start of the recording, like the offsets of markers
+    pub start: f64,
+    pub end: f64,
+    pub description: Option<String>,
+    /// encodes the video again to start at the exact frame, instead of the
+    /// keyframe before `start`
+    #[serde(default)]
+    pub accurate: bool,
+}
+
+/// A clip to cut, see [LocalStreamStore::clip].
+pub(super) struct ClipJob {
+    request: ClipRequest,
+    parent: MetaFile,
+    /// wall clock time of the start of the parent recording
+    parent_start: DateTime<Utc>,
+    input: PathBuf,
+    uuid: Uuid,
+    directory: PathBuf,
+}
+
+impl LocalStreamStore {
+    /// Cuts `request` from the stream `parent` and registers the clip,
+    /// returns its uuid. The store is not locked while cutting.
+    pub async fn clip(
+        instance: &RwLock<LocalStreamStore>,
+        parent: &Uuid,
+        request: ClipRequest,
+    ) -> Result<Uuid, RegisterError> {
+        let job = instance.read().await.clip_job(parent, request)?;
+        let (job, clip_start) = tokio::task::spawn_blocking(move || {
+            let clip_start = job.run();
+            (job, clip_start)
+        })
+        .await
+        .map_err(|e| RegisterError::ClipFailed(e.into()))?;
+        let clip_start = clip_start.map_err(RegisterError::ClipFailed)?;
+        instance.write().await.add_clip(job, clip_start)
+    }
+
+    /// Checks `request` against the stream `parent`, which needs a local
+    /// source with video.
+    pub(super) fn clip_job(
+        &self,
+        parent: &Uuid,
+        request: ClipRequest,
+    ) -> Result<ClipJob, RegisterError> {
+        if !self.stream_map.contains_key(parent) {
+            return Err(RegisterError::UnknownStream(*parent));
+        }
+        let duration = self.duration(parent).unwrap_or(f64::INFINITY);
+        if !(request.start.is_finite() && request.end.is_finite())
+            || request.start < 0.0
+            || request.end <= request.start
+            || request.start >= duration
+        {
+            return Err(RegisterError::InvalidRange(request.start, request.end));
+        }
+
+        let meta_path = self.meta_file_path(parent);
+        let meta: MetaFile = serde_yaml::from_reader(fs::File::open(&meta_path)?)?;
+        let input = video_source(&meta)
+            .and_then(|source| self.source_path(&meta_path, source))
+            .map(|source| self.root.join(source))
+            .ok_or(RegisterError::NoRecording(*parent))?;
+        let uuid = Uuid::new_v4();
+        Ok(ClipJob {
+            parent_start: self.recording_start(&meta_path, &meta),
+            parent: meta,
+            request,
+            input,
+            uuid,
+            directory: self.root.join(CLIPS_DIR).join(uuid.to_string()),
+        })
+    }
+
+    /// Registers the clip written by `job`, which starts `clip_start`
+    /// seconds into its parent.
+    pub(super) fn add_clip(
+        &mut self,
+        job: ClipJob,
+        clip_start: f64,
+    ) -> Result<Uuid, RegisterError> {
+        let playlist = job.directory.join(CLIP_PLAYLIST);
+        let date = job.parent_start + TimeDelta::milliseconds((clip_start * 1000.0) as i64);
+        let markers = job
+            .parent
+            .markers
+            .iter()
+            .filter(|marker| (clip_start..job.request.end).contains(&marker.offset))
+            .map(|marker| Marker {
+                offset: marker.offset - clip_start,
+                auto: false,
+                ..marker.clone()
+            })
+            .collect();
+
+        let mut meta = MetaFile {
+            uuid: job.uuid,
+            sources: vec![PathBuf::from(CLIP_PLAYLIST)],
+            description: job
+                .request
+                .description
+                .unwrap_or_else(|| format!("Clip of {}", job.parent.description)),
+            date,
+            live: Some(false),
+            fixture_id: job.parent.fixture_id,
+            pinned: None,
+            thumbnail: None,
+            previews: None,
+            markers,
+            chapters: None,
+            parent: Some(job.parent.uuid),
+        };
+        let path = job.directory.join(format!("{}.{}", job.uuid, STREAM_EXT));
+        // dates the clip, its markers count from there
+        vod::write_markers(&playlist, date, &[]).map_err(RegisterError::ClipFailed)?;
+        if !meta.markers.is_empty() {
+            self.export_markers_of(&path, &mut meta);
+        }
+        self.write_meta_file(&path, &meta)?;
+        info!(
+            "clipped {} from {}s of {}",
+            job.uuid, clip_start, job.parent.uuid
+        );
+
+        self.insert(path, meta)?;
+        Ok(job.uuid)
+    }
+}
+
+impl ClipJob {
+    /// Writes the clip, returns the second of the parent it starts at.
+    /// Nothing is left behind on failure.
+    fn run(&self) -> anyhow::Result<f64> {
+        let playlist = self.directory.join(CLIP_PLAYLIST);
+        let written = fs::create_dir_all(&self.directory)
+            .map_err(anyhow::Error::from)
+            .and_then(|()| {
+                clipper::clip(
+                    &self.input,
+                    &playlist,
+                    self.request.start,
+                    self.request.end,
+                    self.request.accurate,
+                )
+            })
+            .and_then(|clip_start| {
+                let summary = vod::summarize_playlist(&playlist)?;
+                ensure!(summary.segments > 0, "the clip has no segments");
+                Ok(clip_start)
+            });
+        if written.is_err() {
+            let _ = fs::remove_dir_all(&self.directory);
+        }
+        written
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::middleware::screen_grabber::record_test_pattern;
+    use crate::middleware::stream_store::test_store;
+    use std::path::Path;
+    use tempdir::TempDir;
+
+    const PLAYLIST: &str = "#EXTM3U
+#EXT-X-VERSION:3
+#EXT-X-TARGETDURATION:4
+#EXT-X-PROGRAM-DATE-TIME:2024-09-11T12:58:00.000Z
+#EXTINF:4.000000,
+match_00000.ts
+#EXT-X-ENDLIST
+";
+
+    fn request(start: f64, end: f64) -> ClipRequest {
+        ClipRequest {
+            start,
+            end,
+            description: None,
+            accurate: false,
+        }
+    }
+
+    fn marker(offset: f64, title: &str) -> Marker {
+        Marker {
+            offset,
+            kind: MarkerKind::Goal,
+            title: title.into(),
+            auto: true,
+        }
+    }
+
+    #[test]
+    fn clip_of_match() {
+        let temp = TempDir::new("clips").unwrap();
+        fs::write(temp.path().join("match.m3u8"), PLAYLIST).unwrap();
+        let parent = MetaFile {
+            fixture_id: Some(7),
+            markers: vec![marker(30.0, "first"), marker(200.0, "second")],
+            ..MetaFile::new("match", vec![PathBuf::from("match.m3u8")])
+        };
+        let mut store = test_store(temp.path(), &[("match.stream", &parent)]);
+
+        let invalid = |start, end| {
+            matches!(
+                store.clip_job(&parent.uuid, request(start, end)),
+                Err(RegisterError::InvalidRange(..))
+            )
+        };
+        assert!(invalid(-1.0, 10.0));
+        assert!(invalid(10.0, 10.0));
+        assert!(invalid(0.0, f64::NAN));
+        assert!(matches!(
+            store.clip_job(&Uuid::new_v4(), request(0.0, 10.0)),
+            Err(RegisterError::UnknownStream(_))
+        ));
+
+        let job = store.clip_job(&parent.uuid, request(25.0, 60.0)).unwrap();
+        assert_eq!(temp.path().join("match.m3u8"), job.input);
+        let directory = job.directory.clone();
+        assert!(directory.starts_with(temp.path().join(CLIPS_DIR)));
+
+        // what the clipper writes, from the keyframe at 24 seconds
+        fs::create_dir_all(&directory).unwrap();
+        let clip_playlist =
+            PLAYLIST.replace("#EXT-X-PROGRAM-DATE-TIME:2024-09-11T12:58:00.000Z\n", "");
+        fs::write(directory.join(CLIP_PLAYLIST), clip_playlist).unwrap();
+        let uuid = store.add_clip(job, 24.0).unwrap();
+
+        let clip = &store.stream_map[&uuid];
+        assert_eq!(Some(parent.uuid), clip.parent);
+        assert_eq!(Some(7), clip.fixture_id);
+        assert_eq!("Clip of match", clip.description);
+        assert_eq!("2024-09-11T12:58:24+00:00", clip.date.to_rfc3339());
+        assert_eq!(1, clip.markers.len());
+        assert_eq!(6.0, clip.markers[0].offset);
+        assert!(!clip.markers[0].auto);
+        let relative = directory.strip_prefix(temp.path()).unwrap();
+        assert_eq!(
+            Some(
+                Path::new("/streams")
+                    .join(relative)
+                    .join("clip_chapters.vtt")
+            ),
+            clip.chapters
+        );
+        let written = fs::read_to_string(directory.join(CLIP_PLAYLIST)).unwrap();
+        assert!(written.contains("#EXT-X-PROGRAM-DATE-TIME:2024-09-11T12:58:24.000Z"));
+        assert!(written.contains("START-DATE=\"2024-09-11T12:58:30.000Z\""));
+    }
+
+    #[test]
+    fn cut_recording() {
+        let temp = TempDir::new("clips").unwrap();
+        let master = record_test_pattern(temp.path(), "pattern", 4);
+        let parent = MetaFile::new("pattern", vec![PathBuf::from("pattern.m3u8")]);
+        let store = test_store(temp.path(), &[("pattern/pattern.stream", &parent)]);
+        // segments of a second, each starting at a keyframe
+        let variant = fs::read_to_string(master.with_file_name("pattern_180p.m3u8")).unwrap();
+        let keyframes = variant
+            .lines()
+            .filter_map(|line| line.strip_prefix("#EXTINF:"))
+            .map(|extinf| extinf.split(',').next().unwrap().parse::<f64>().unwrap())
+            .scan(0.0, |end, duration| {
+                let start = *end;
+                *end += duration;
+                Some(start)
+            })
+            .collect::<Vec<_>>();
+
+        for accurate in [false, true] {
+            let request = ClipRequest {
+                accurate,
+                ..request(1.5, 3.0)
+            };
+            let job = store.clip_job(&parent.uuid, request).unwrap();
+            let clip_start = job.run().unwrap();
+            if accurate {
+                assert_eq!(1.5, clip_start);
+            } else {
+                assert!(clip_start < 1.5);
+                assert!(
+                    keyframes.iter().any(|at| (at - clip_start).abs() < 0.1),
+                    "{} is not a keyframe of {:?}",
+                    clip_start,
+                    keyframes
+                );
+            }
+
+            let clip = fs::read_to_string(job.directory.join(CLIP_PLAYLIST)).unwrap();
+            assert!(clip.contains("#EXTINF"));
+            assert!(clip.ends_with("#EXT-X-ENDLIST\n"));
+        }
+    }
+}
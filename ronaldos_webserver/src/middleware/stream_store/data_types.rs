@@ -1,6 +1,9 @@
 use chrono::{serde::ts_seconds, DateTime, Utc};
 use serde::{Deserialize, Serialize};
-use std::{ffi::OsStr, path::PathBuf};
+use std::{
+    ffi::OsStr,
+    path::{Path, PathBuf},
+};
 use thiserror::Error;
 use uuid::Uuid;
 
@@ -8,10 +11,10 @@ pub type MetaFile = StreamMeta<PathBuf>;
 pub type Stream = StreamMeta<Source>;
 pub const STREAM_EXT: &str = "stream";
 
-#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct StreamMeta<T>
 where
-    T: Clone + PartialEq + Eq,
+    T: Clone + PartialEq,
 {
     pub uuid: Uuid,
     pub sources: Vec<T>,
@@ -20,47 +23,221 @@ where
     pub date: DateTime<Utc>,
     pub live: Option<bool>,
     pub fixture_id: Option<u64>,
+    /// pinned streams are kept by the retention policy
+    pub pinned: Option<bool>,
+    /// poster frame, a JPEG next to the first local source
+    pub thumbnail: Option<PathBuf>,
+    /// WebVTT file indexing a sprite sheet of preview tiles
+    pub previews: Option<PathBuf>,
+    /// moments of the match, ordered by their offset
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub markers: Vec<Marker>,
+    /// WebVTT chapters track of the markers
+    pub chapters: Option<PathBuf>,
+    /// the stream a clip was cut from
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parent: Option<Uuid>,
 }
 
-impl From<StreamMeta<PathBuf>> for StreamMeta<Source> {
-    fn from(meta: StreamMeta<PathBuf>) -> Self {
-        let mut sources = Vec::new();
-        for path in meta.sources {
-            let typ = match path.extension().and_then(OsStr::to_str) {
-                Some("m3u8" | "m3u") => "application/x-mpegURL",
-                Some("dash" | "mpd") => "application/dash+xml",
-                Some("mp4") => "video/mp4",
-                x => panic!("cannot map {:?} to MIME type", x),
-            };
-            sources.push(Source {
-                typ: typ.into(),
-                url: path,
-            });
-        }
+impl TryFrom<StreamMeta<PathBuf>> for StreamMeta<Source> {
+    type Error = UnknownMimeType;
+
+    fn try_from(meta: StreamMeta<PathBuf>) -> Result<Self, Self::Error> {
+        let sources = meta
+            .sources
+            .into_iter()
+            .map(Source::try_from)
+            .collect::<Result<_, _>>()?;
 
-        Stream {
+        Ok(Stream {
             uuid: meta.uuid,
             sources,
             live: meta.live,
             description: meta.description,
             date: meta.date,
             fixture_id: meta.fixture_id,
+            pinned: meta.pinned,
+            thumbnail: meta.thumbnail,
+            previews: meta.previews,
+            markers: meta.markers,
+            chapters: meta.chapters,
+            parent: meta.parent,
+        })
+    }
+}
+
+#[cfg(test)]
+impl<T: Clone + PartialEq> StreamMeta<T> {
+    /// A stream of `sources` that is no longer live, dated now. Tests set
+    /// the fields they care about on top of it.
+    pub fn new(description: &str, sources: Vec<T>) -> Self {
+        StreamMeta {
+            uuid: Uuid::new_v4(),
+            sources,
+            description: description.to_string(),
+            date: Utc::now(),
+            live: Some(false),
+            fixture_id: None,
+            pinned: None,
+            thumbnail: None,
+            previews: None,
+            markers: Vec::new(),
+            chapters: None,
+            parent: None,
         }
     }
 }
 
-#[derive(Serialize, Debug, Deserialize, Clone, PartialEq, Eq)]
+#[derive(Serialize, Debug, Deserialize, Clone, PartialEq)]
 pub struct Source {
     pub url: PathBuf,
     pub typ: String,
+    /// details of local sources, once they are probed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub media: Option<MediaDetails>,
+    /// why the source cannot be played or probed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl TryFrom<PathBuf> for Source {
+    type Error = UnknownMimeType;
+
+    fn try_from(url: PathBuf) -> Result<Self, Self::Error> {
+        let Some(typ) = mime_type(&url) else {
+            return Err(UnknownMimeType(url));
+        };
+        Ok(Source {
+            url,
+            typ: typ.into(),
+            media: None,
+            error: None,
+        })
+    }
+}
+
+/// MIME type of a source or segment, derived from the extension of `path`.
+pub fn mime_type(path: &Path) -> Option<&'static str> {
+    let typ = match path.extension().and_then(OsStr::to_str)? {
+        "m3u8" | "m3u" => "application/x-mpegURL",
+        "dash" | "mpd" => "application/dash+xml",
+        "mp4" | "m4v" => "video/mp4",
+        "mkv" => "video/x-matroska",
+        "webm" => "video/webm",
+        "mov" => "video/quicktime",
+        "ts" => "video/mp2t",
+        "mp3" => "audio/mpeg",
+        "aac" => "audio/aac",
+        "m4a" => "audio/mp4",
+        _ => return None,
+    };
+    Some(typ)
+}
+
+/// A moment of a recorded match.
+#[derive(Serialize, Debug, Deserialize, Clone, PartialEq)]
+pub struct Marker {
+    /// seconds from the start of the recording
+    pub offset: f64,
+    pub kind: MarkerKind,
+    pub title: String,
+    /// derived from the events of the fixture, edited markers are not
+    /// replaced anymore
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub auto: bool,
+}
+
+#[derive(Serialize, Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum MarkerKind {
+    KickOff,
+    Goal,
+    YellowCard,
+    RedCard,
+    HalfTime,
+    SecondHalf,
+    Custom,
+}
+
+impl MarkerKind {
+    /// the serialized name
+    pub fn name(&self) -> &'static str {
+        match self {
+            MarkerKind::KickOff => "kick_off",
+            MarkerKind::Goal => "goal",
+            MarkerKind::YellowCard => "yellow_card",
+            MarkerKind::RedCard => "red_card",
+            MarkerKind::HalfTime => "half_time",
+            MarkerKind::SecondHalf => "second_half",
+            MarkerKind::Custom => "custom",
+        }
+    }
+}
+
+/// Probed details of a source, fields are missing where they are unknown.
+#[derive(Serialize, Debug, Deserialize, Clone, Default, PartialEq)]
+pub struct MediaDetails {
+    /// seconds, of the segments written so far for live playlists
+    pub duration: Option<f64>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub video_codec: Option<String>,
+    pub audio_codec: Option<String>,
+    /// bits per second
+    pub bitrate: Option<u64>,
+    /// segments of HLS playlists, of the first variant for master playlists
+    pub segments: Option<usize>,
+    /// the recording is complete, live playlists have not ended yet
+    pub ended: bool,
+}
+
+/// A source whose MIME type cannot be derived from its extension, see
+/// [mime_type].
+#[derive(Debug, Error)]
+#[error("cannot map {} to a MIME type", .0.to_string_lossy())]
+pub struct UnknownMimeType(pub PathBuf);
+
+impl From<UnknownMimeType> for RegisterError {
+    fn from(e: UnknownMimeType) -> Self {
+        RegisterError::UnsupportedSource(e.0)
+    }
 }
 
 #[derive(Debug, Error)]
 pub enum RegisterError {
     #[error("no source url specified")]
     SourceArgumentEmpty,
+    #[error("{0} is not located in the stream directory")]
+    OutsideRoot(PathBuf),
+    #[error("{0} is not a supported source")]
+    UnsupportedSource(PathBuf),
+    #[error("{0} does not exist")]
+    MissingSource(PathBuf),
+    #[error("{0} is not a valid playlist")]
+    InvalidPlaylist(PathBuf),
+    #[error("marker \"{0}\" needs an offset of zero or more seconds")]
+    InvalidMarker(String),
+    #[error("{0} has no local recording with video")]
+    NoRecording(Uuid),
+    #[error("{0}s to {1}s is not a range of the recording")]
+    InvalidRange(f64, f64),
+    #[error("could not cut the clip: {0:#}")]
+    ClipFailed(anyhow::Error),
+    #[error("{0} is not a registered stream")]
+    UnknownStream(Uuid),
     #[error(transparent)]
     ParseError(#[from] serde_yaml::Error),
     #[error(transparent)]
     IoError(#[from] std::io::Error),
 }
+
+/// Reasons a file of the stream directory is not served.
+#[derive(Debug, Error)]
+pub enum ResolveError {
+    #[error("{0} is not a valid path")]
+    InvalidPath(PathBuf),
+    #[error("{0} is not part of a stream")]
+    NotAStreamFile(PathBuf),
+    #[error(transparent)]
+    IoError(#[from] std::io::Error),
+}
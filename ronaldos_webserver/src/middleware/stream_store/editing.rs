@@ -0,0 +1,602 @@
+//! Creating, editing and deleting streams on behalf of the admin api. Unlike
+//! [LocalStreamStore::register], which announces streams that are about to
+//! be written, the sources given here have to exist already.
+use super::{
+    belongs_to, data_types::*, is_local, is_remote, markers::edited_markers, LocalStreamStore,
+};
+use chrono::{DateTime, Utc};
+use hashbrown::HashMap;
+use serde::{Deserialize, Deserializer};
+use std::{
+    ffi::OsStr,
+    fs, io,
+    path::{Path, PathBuf},
+};
+use tokio::sync::RwLock;
+use tracing::{debug, info, warn};
+use uuid::Uuid;
+
+/// Fields of a stream set through the admin api. On updates, fields that
+/// are missing keep their value.
+#[derive(Deserialize, Debug, Default)]
+pub struct StreamEdit {
+    pub description: Option<String>,
+    pub date: Option<DateTime<Utc>>,
+    /// `null` removes the fixture
+    #[serde(default, deserialize_with = "present")]
+    pub fixture_id: Option<Option<u64>>,
+    pub live: Option<bool>,
+    /// keeps the stream regardless of the retention policy
+    pub pinned: Option<bool>,
+    pub sources: Option<Vec<PathBuf>>,
+    /// replaces the markers, which are no longer derived from the fixture
+    pub markers: Option<Vec<Marker>>,
+}
+
+/// distinguishes a missing field from one that is explicitly `null`
+fn present<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Option<u64>>, D::Error> {
+    Option::deserialize(deserializer).map(Some)
+}
+
+impl LocalStreamStore {
+    /// Writes a new `.stream` file for `edit`, streams are not live unless
+    /// stated otherwise.
+    pub fn create_stream(&mut self, edit: StreamEdit) -> Result<Uuid, RegisterError> {
+        let uuid = Uuid::new_v4();
+        let path = self.root.join(format!("{}.{}", uuid, STREAM_EXT));
+        let sources = edit.sources.unwrap_or_default();
+        self.validate_sources(&path, &sources)?;
+        let markers = edit.markers.map(edited_markers).transpose()?;
+
+        let mut meta = MetaFile {
+            uuid,
+            sources,
+            description: edit.description.unwrap_or_default(),
+            date: edit.date.unwrap_or_else(Utc::now),
+            live: Some(edit.live.unwrap_or(false)),
+            fixture_id: edit.fixture_id.flatten(),
+            pinned: edit.pinned,
+            thumbnail: None,
+            previews: None,
+            markers: markers.unwrap_or_default(),
+            chapters: None,
+            parent: None,
+        };
+        if !meta.markers.is_empty() {
+            self.export_markers_of(&path, &mut meta);
+        }
+        self.write_meta_file(&path, &meta)?;
+        info!("created {}", path.to_string_lossy());
+
+        self.insert(path, meta)?;
+        Ok(uuid)
+    }
+
+    /// Applies `edit` to the stream `uuid` and returns the updated stream.
+    pub fn update_stream(
+        &mut self,
+        uuid: &Uuid,
+        edit: StreamEdit,
+    ) -> Result<Stream, RegisterError> {
+        let path = self.known_meta_file(uuid)?;
+        let mut meta: MetaFile = serde_yaml::from_reader(fs::File::open(&path)?)?;
+        // the chapters follow the first local source
+        let export = edit.markers.is_some() || (edit.sources.is_some() && meta.chapters.is_some());
+
+        if let Some(sources) = edit.sources {
+            self.validate_sources(&path, &sources)?;
+            meta.sources = sources;
+            // generated again from the new sources
+            meta.thumbnail = None;
+            meta.previews = None;
+        }
+        if let Some(description) = edit.description {
+            meta.description = description;
+        }
+        if let Some(date) = edit.date {
+            meta.date = date;
+        }
+        if let Some(fixture_id) = edit.fixture_id {
+            meta.fixture_id = fixture_id;
+        }
+        if let Some(live) = edit.live {
+            meta.live = Some(live);
+        }
+        if let Some(pinned) = edit.pinned {
+            meta.pinned = Some(pinned);
+        }
+        if let Some(markers) = edit.markers {
+            meta.markers = edited_markers(markers)?;
+        }
+        if export {
+            self.export_markers_of(&path, &mut meta);
+        }
+
+        self.write_meta_file(&path, &meta)?;
+        info!("updated {}", path.to_string_lossy());
+        self.insert(path, meta)?;
+        Ok(self.stream_map[uuid].clone())
+    }
+
+    /// Deletes the `.stream` file of `uuid`. With `remove_media` the local
+    /// sources go as well, including the segments next to them. The store is
+    /// locked only to drop the stream, its media is removed afterwards.
+    pub async fn delete_stream(
+        instance: &RwLock<LocalStreamStore>,
+        uuid: &Uuid,
+        remove_media: bool,
+    ) -> Result<(), RegisterError> {
+        let media = instance
+            .write()
+            .await
+            .drop_stream(uuid, remove_media)
+            .await?;
+        if let Some(media) = media {
+            media.remove().await?;
+        }
+        Ok(())
+    }
+
+    /// Deletes the `.stream` file of `uuid` and forgets the stream. With
+    /// `remove_media`, returns the media to remove once the store is
+    /// unlocked.
+    pub(super) async fn drop_stream(
+        &mut self,
+        uuid: &Uuid,
+        remove_media: bool,
+    ) -> Result<Option<StreamMedia>, RegisterError> {
+        let path = self.known_meta_file(uuid)?;
+        let media = if remove_media {
+            let meta: MetaFile = serde_yaml::from_reader(fs::File::open(&path)?)?;
+            Some(StreamMedia {
+                uuid: *uuid,
+                sources: self.media_sources(&path, &meta),
+                index: self.media_index(),
+            })
+        } else {
+            None
+        };
+
+        match fs::remove_file(&path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+        self.removed(std::slice::from_ref(&path)).await;
+        info!("deleted {}", path.to_string_lossy());
+        Ok(media)
+    }
+
+    /// Exports the markers of `meta`, failures leave the edit in place.
+    pub(super) fn export_markers_of(&self, meta_path: &Path, meta: &mut MetaFile) {
+        if let Err(e) = self.export_markers(meta_path, meta) {
+            warn!("could not export the markers of {}: {:#}", meta.uuid, e);
+        }
+    }
+
+    fn known_meta_file(&self, uuid: &Uuid) -> Result<PathBuf, RegisterError> {
+        if !self.stream_map.contains_key(uuid) {
+            return Err(RegisterError::UnknownStream(*uuid));
+        }
+        Ok(self.meta_file_path(uuid))
+    }
+
+    pub(super) fn insert(
+        &mut self,
+        path: PathBuf,
+        mut meta: MetaFile,
+    ) -> Result<(), UnknownMimeType> {
+        self.patch_sources(&path, &mut meta);
+        let mut stream = Stream::try_from(meta)?;
+        let uuid = stream.uuid;
+        self.request_media(uuid);
+        self.keep_probes(&mut stream);
+        self.stream_map.insert(uuid, stream);
+        self.index([(path.as_path(), &uuid)]);
+        self.uuid_lookup.insert(path, uuid);
+        Ok(())
+    }
+
+    /// Sources need a known extension. Local sources are relative to the
+    /// `.stream` file at `meta_path`, have to exist below the root, and
+    /// playlists have to be playlists whose segments exist.
+    fn validate_sources(&self, meta_path: &Path, sources: &[PathBuf]) -> Result<(), RegisterError> {
+        if sources.is_empty() {
+            return Err(RegisterError::SourceArgumentEmpty);
+        }
+
+        for source in sources {
+            if mime_type(source).is_none() {
+                return Err(RegisterError::UnsupportedSource(source.clone()));
+            }
+            // remote sources are left to the player
+            if is_remote(source) {
+                continue;
+            }
+
+            let path = self
+                .source_path(meta_path, source)
+                .and_then(|source| self.local_path(&source))
+                .ok_or_else(|| RegisterError::OutsideRoot(source.clone()))?;
+            if !path.is_file() {
+                return Err(RegisterError::MissingSource(source.clone()));
+            }
+            let extension = source.extension().and_then(OsStr::to_str);
+            if matches!(extension, Some("m3u8" | "m3u")) && !valid_playlist(&path)? {
+                return Err(RegisterError::InvalidPlaylist(source.clone()));
+            }
+        }
+        Ok(())
+    }
+
+    /// Removes the local sources of `meta`, see [MediaIndex::media].
+    /// Local sources of `meta`, relative to the root.
+    pub(super) fn media_sources(&self, meta_path: &Path, meta: &MetaFile) -> Vec<PathBuf> {
+        meta.sources
+            .iter()
+            .filter(|source| !is_remote(source))
+            .filter_map(|source| self.source_path(meta_path, source))
+            .collect()
+    }
+
+    /// What [MediaIndex::media] needs to know of the streams, so media can
+    /// be found and removed without holding the store.
+    pub(super) fn media_index(&self) -> MediaIndex {
+        let sources = self
+            .stream_map
+            .values()
+            .map(|stream| {
+                let sources = stream
+                    .sources
+                    .iter()
+                    .filter_map(|source| source.url.strip_prefix(&self.request_base).ok())
+                    .map(Path::to_path_buf)
+                    .collect();
+                (stream.uuid, sources)
+            })
+            .collect();
+        let meta_files = self
+            .uuid_lookup
+            .iter()
+            .map(|(path, uuid)| (*uuid, path.clone()))
+            .collect();
+        MediaIndex {
+            root: self.root.clone(),
+            sources,
+            meta_files,
+        }
+    }
+}
+
+/// Media of a stream that was dropped, see [LocalStreamStore::drop_stream].
+pub(super) struct StreamMedia {
+    uuid: Uuid,
+    sources: Vec<PathBuf>,
+    index: MediaIndex,
+}
+
+impl StreamMedia {
+    /// Removes the media on a blocking thread, see [MediaIndex::remove_media].
+    pub(super) async fn remove(self) -> io::Result<()> {
+        tokio::task::spawn_blocking(move || self.index.remove_media(&self.uuid, &self.sources))
+            .await
+            .map_err(io::Error::other)?
+    }
+}
+
+/// Local sources and `.stream` files of all streams, see
+/// [LocalStreamStore::media_index].
+pub(super) struct MediaIndex {
+    root: PathBuf,
+    /// local sources of every stream, relative to the root
+    sources: HashMap<Uuid, Vec<PathBuf>>,
+    meta_files: HashMap<Uuid, PathBuf>,
+}
+
+impl MediaIndex {
+    pub(super) fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// local sources of the stream `uuid`, relative to the root
+    pub(super) fn sources(&self, uuid: &Uuid) -> &[PathBuf] {
+        self.sources.get(uuid).map_or(&[], Vec::as_slice)
+    }
+
+    /// Removes the [MediaIndex::media] of the stream `uuid`. Directories
+    /// left empty go as well.
+    pub(super) fn remove_media(&self, uuid: &Uuid, sources: &[PathBuf]) -> io::Result<()> {
+        let media = self.media(uuid, sources)?;
+        for path in &media {
+            match fs::remove_file(path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => debug!("removed {}", path.to_string_lossy()),
+            }
+        }
+
+        // deepest first, so parents are empty once their children are gone
+        let mut directories = media
+            .iter()
+            .flat_map(|path| {
+                path.ancestors()
+                    .skip(1)
+                    .take_while(|directory| *directory != self.root)
+            })
+            .filter(|directory| directory.starts_with(&self.root))
+            .collect::<Vec<_>>();
+        directories.sort_by_key(|directory| std::cmp::Reverse(directory.components().count()));
+        directories.dedup();
+        for directory in directories {
+            let empty = fs::read_dir(directory).is_ok_and(|mut entries| entries.next().is_none());
+            if empty && fs::remove_dir(directory).is_ok() {
+                info!("removed {}", directory.to_string_lossy());
+            }
+        }
+        info!("removed {} file(s) of {}", media.len(), uuid);
+        Ok(())
+    }
+
+    /// Files holding the local `sources` of the stream `uuid`, which are
+    /// relative to the root, and its `.stream` file. Sources in a directory
+    /// of their own claim every file below it, unless another stream uses
+    /// the directory too. Otherwise only the files next to a source that
+    /// belong to it, see [belongs_to]. `.stream` files of other streams,
+    /// known or not, are never part of it.
+    pub(super) fn media(&self, uuid: &Uuid, sources: &[PathBuf]) -> io::Result<Vec<PathBuf>> {
+        let others = self
+            .sources
+            .iter()
+            .filter(|(other, _)| *other != uuid)
+            .flat_map(|(_, sources)| sources)
+            .collect::<Vec<_>>();
+
+        let mut media = Vec::new();
+        for source in sources.iter().filter(|source| is_local(source)) {
+            match source.parent() {
+                Some(directory)
+                    if directory != Path::new("")
+                        && !others.iter().any(|other| other.starts_with(directory)) =>
+                {
+                    media.extend(
+                        files_below(&self.root.join(directory))?
+                            .into_iter()
+                            .filter(|file| file.extension() != Some(OsStr::new(STREAM_EXT))),
+                    )
+                }
+                _ => media.extend(self.files_of(source, &others)?),
+            }
+        }
+        if let Some(meta_path) = self.meta_files.get(uuid).filter(|path| path.is_file()) {
+            media.push(meta_path.clone());
+        }
+        media.sort();
+        media.dedup();
+        Ok(media)
+    }
+
+    /// Files next to `source` that belong to it, for sources in the root or
+    /// in a directory shared with other streams. Files that the source of
+    /// another stream claims with a longer or equal stem are left alone.
+    fn files_of(&self, source: &Path, others: &[&PathBuf]) -> io::Result<Vec<PathBuf>> {
+        let directory = source.parent().unwrap_or(Path::new(""));
+        let Some(name) = source.file_name() else {
+            return Ok(Vec::new());
+        };
+        let stem_len = |source: &Path| source.file_stem().map_or(0, OsStr::len);
+        let neighbours = others
+            .iter()
+            .filter(|other| other.parent().unwrap_or(Path::new("")) == directory)
+            .filter(|other| stem_len(other) >= stem_len(source))
+            .filter_map(|other| other.file_name())
+            .collect::<Vec<_>>();
+
+        let entries = match fs::read_dir(self.root.join(directory)) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            entries => entries?,
+        };
+        let mut files = Vec::new();
+        for entry in entries {
+            let path = entry?.path();
+            let Some(file) = path.file_name() else {
+                continue;
+            };
+            let belongs = |name: &OsStr| belongs_to(Path::new(name), Path::new(file));
+            if path.is_file()
+                && path.extension() != Some(OsStr::new(STREAM_EXT))
+                && belongs(name)
+                && !neighbours.iter().any(|other| belongs(other))
+            {
+                files.push(path);
+            }
+        }
+        Ok(files)
+    }
+}
+
+/// Every file below `directory`, symlinks are not followed.
+fn files_below(directory: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    let mut directories = vec![directory.to_path_buf()];
+    while let Some(directory) = directories.pop() {
+        let entries = match fs::read_dir(&directory) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            entries => entries?,
+        };
+        for entry in entries {
+            let entry = entry?;
+            match entry.file_type()?.is_dir() {
+                true => directories.push(entry.path()),
+                false => files.push(entry.path()),
+            }
+        }
+    }
+    Ok(files)
+}
+
+/// A playlist starts with `#EXTM3U` and every local URI it references exists.
+fn valid_playlist(path: &Path) -> io::Result<bool> {
+    let content = fs::read_to_string(path)?;
+    if !content
+        .trim_start_matches('\u{feff}')
+        .starts_with("#EXTM3U")
+    {
+        return Ok(false);
+    }
+
+    let directory = path.parent().unwrap_or(Path::new(""));
+    Ok(content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#') && !line.contains("://"))
+        .map(|uri| uri.split('?').next().unwrap_or(uri))
+        .all(|uri| directory.join(uri).is_file()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+    use tempdir::TempDir;
+
+    const PLAYLIST: &str = "#EXTM3U\n#EXT-X-TARGETDURATION:4\n#EXTINF:4.0,\nmatch_00000.ts\n";
+
+    fn store(temp: &TempDir) -> LocalStreamStore {
+        fs::create_dir_all(temp.path().join("match")).unwrap();
+        fs::write(temp.path().join("match/match.m3u8"), PLAYLIST).unwrap();
+        fs::write(temp.path().join("match/match_00000.ts"), "ts").unwrap();
+        fs::write(temp.path().join("broken.m3u8"), "<html>").unwrap();
+        fs::write(temp.path().join("full.mp4"), "mp4").unwrap();
+        fs::write(temp.path().join("full.jpg"), "jpg").unwrap();
+        LocalStreamStore::new(temp.path().into(), PathBuf::from_str("/streams").unwrap())
+    }
+
+    fn sources(sources: &[&str]) -> StreamEdit {
+        StreamEdit {
+            sources: Some(sources.iter().map(PathBuf::from).collect()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn validation() {
+        let temp = TempDir::new("editing").unwrap();
+        let mut store = store(&temp);
+
+        let refused =
+            |store: &mut LocalStreamStore, source: &[&str]| store.create_stream(sources(source));
+        assert!(matches!(
+            refused(&mut store, &[]),
+            Err(RegisterError::SourceArgumentEmpty)
+        ));
+        assert!(matches!(
+            refused(&mut store, &["full.jpg"]),
+            Err(RegisterError::UnsupportedSource(_))
+        ));
+        assert!(matches!(
+            refused(&mut store, &["missing.mp4"]),
+            Err(RegisterError::MissingSource(_))
+        ));
+        assert!(matches!(
+            refused(&mut store, &["../full.mp4"]),
+            Err(RegisterError::OutsideRoot(_))
+        ));
+        assert!(matches!(
+            refused(&mut store, &["broken.m3u8"]),
+            Err(RegisterError::InvalidPlaylist(_))
+        ));
+        fs::remove_file(temp.path().join("match/match_00000.ts")).unwrap();
+        assert!(matches!(
+            refused(&mut store, &["match/match.m3u8"]),
+            Err(RegisterError::InvalidPlaylist(_))
+        ));
+        assert_eq!(0, store.stream_map.len());
+        assert_eq!(0, stream_count(&temp));
+    }
+
+    #[tokio::test]
+    async fn create_update_delete() {
+        let temp = TempDir::new("editing").unwrap();
+        let mut store = RwLock::new(store(&temp));
+
+        let uuid = store
+            .get_mut()
+            .create_stream(StreamEdit {
+                description: Some("roki - ajax".to_string()),
+                fixture_id: Some(Some(42)),
+                ..sources(&["match/match.m3u8", "https://example.com/live.m3u8"])
+            })
+            .unwrap();
+        let stream = &store.get_mut().stream_map[&uuid];
+        assert_eq!(Some(false), stream.live);
+        assert_eq!(Some(42), stream.fixture_id);
+        assert_eq!(
+            PathBuf::from("/streams/match/match.m3u8"),
+            stream.sources[0].url
+        );
+
+        let edit: StreamEdit = serde_yaml::from_str(
+            r#"{"description": "roki - psv", "fixture_id": null, "live": true}"#,
+        )
+        .unwrap();
+        let updated = store.get_mut().update_stream(&uuid, edit).unwrap();
+        assert_eq!("roki - psv", updated.description);
+        assert_eq!(None, updated.fixture_id);
+        assert_eq!(Some(true), updated.live);
+        assert_eq!(1, stream_count(&temp));
+        let meta: MetaFile =
+            serde_yaml::from_reader(fs::File::open(store.get_mut().meta_file_path(&uuid)).unwrap())
+                .unwrap();
+        assert_eq!("roki - psv", meta.description);
+        assert_eq!(PathBuf::from("match/match.m3u8"), meta.sources[0]);
+
+        assert!(matches!(
+            store.get_mut().update_stream(&uuid, sources(&["full.jpg"])),
+            Err(RegisterError::UnsupportedSource(_))
+        ));
+
+        let other = store
+            .get_mut()
+            .create_stream(sources(&["full.mp4"]))
+            .unwrap();
+        LocalStreamStore::delete_stream(&store, &other, false)
+            .await
+            .unwrap();
+        assert!(temp.path().join("full.mp4").exists());
+        // .stream files the store refused to load stay, nested media goes
+        fs::write(temp.path().join("match/rejected.stream"), "sources: 1").unwrap();
+        fs::create_dir_all(temp.path().join("match/720p")).unwrap();
+        fs::write(temp.path().join("match/720p/match_00000.ts"), "ts").unwrap();
+        LocalStreamStore::delete_stream(&store, &uuid, true)
+            .await
+            .unwrap();
+        let left = |path: &str| temp.path().join(path).exists();
+        assert!(!left("match/match.m3u8") && !left("match/match_00000.ts"));
+        assert!(!left("match/720p"));
+        assert!(left("match/rejected.stream"));
+        fs::remove_file(temp.path().join("match/rejected.stream")).unwrap();
+        fs::write(temp.path().join("match/match.m3u8"), PLAYLIST).unwrap();
+        fs::write(temp.path().join("match/match_00000.ts"), "ts").unwrap();
+        let uuid = store
+            .get_mut()
+            .create_stream(sources(&["match/match.m3u8"]))
+            .unwrap();
+        LocalStreamStore::delete_stream(&store, &uuid, true)
+            .await
+            .unwrap();
+        assert!(!left("match"));
+        assert_eq!(0, stream_count(&temp));
+        assert_eq!(0, store.get_mut().stream_map.len());
+        assert!(matches!(
+            LocalStreamStore::delete_stream(&store, &uuid, true).await,
+            Err(RegisterError::UnknownStream(_))
+        ));
+    }
+
+    fn stream_count(temp: &TempDir) -> usize {
+        fs::read_dir(temp.path())
+            .unwrap()
+            .filter(|entry| {
+                entry.as_ref().unwrap().path().extension() == Some(OsStr::new(STREAM_EXT))
+            })
+            .count()
+    }
+}
@@ -0,0 +1,256 @@
+//! Poster frames and preview sprites of streams. They are generated by the
+//! media worker and written next to the first local source of a stream, so
+//! they are served and removed along with its media. Previews need the full
+//! duration and wait until a stream is no longer live.
+use super::{data_types::*, is_remote, LocalStreamStore};
+use crate::middleware::thumbnailer;
+use anyhow::Result;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+use tracing::{debug, info};
+use uuid::Uuid;
+
+/// Images to generate for a stream, each as path relative to the `.stream`
+/// file and path on disk.
+pub(super) struct ImageJob {
+    input: PathBuf,
+    thumbnail: Option<(PathBuf, PathBuf)>,
+    previews: Option<(PathBuf, PathBuf)>,
+}
+
+/// The images of an [ImageJob] that were written, relative to the `.stream`
+/// file.
+#[derive(Default)]
+pub(super) struct Images {
+    thumbnail: Option<PathBuf>,
+    previews: Option<PathBuf>,
+}
+
+impl LocalStreamStore {
+    pub(super) fn image_job(&self, uuid: &Uuid) -> Option<ImageJob> {
+        if !self.stream_map.contains_key(uuid) {
+            return None;
+        }
+        let meta_path = self.meta_file_path(uuid);
+        let file = fs::File::open(&meta_path).ok()?;
+        let meta = serde_yaml::from_reader::<_, MetaFile>(file).ok()?;
+        if !lacks_images(&meta) {
+            return None;
+        }
+
+        let source = video_source(&meta)?;
+        let stem = source.file_stem()?.to_string_lossy();
+        let image = |suffix: &str| {
+            let relative = source.with_file_name(format!("{}_{}", stem, suffix));
+            let path = self.root.join(self.source_path(&meta_path, &relative)?);
+            Some((relative, path))
+        };
+
+        Some(ImageJob {
+            input: self.root.join(self.source_path(&meta_path, source)?),
+            thumbnail: meta
+                .thumbnail
+                .is_none()
+                .then(|| image("thumbnail.jpg"))
+                .flatten(),
+            previews: (meta.previews.is_none() && meta.live != Some(true))
+                .then(|| image("previews.vtt"))
+                .flatten(),
+        })
+    }
+
+    /// Adds the written `images` to the `.stream` file of `uuid`, unless the
+    /// stream was deleted in the meantime.
+    pub(super) fn set_images(&mut self, uuid: &Uuid, images: Images) -> Result<()> {
+        if !self.stream_map.contains_key(uuid)
+            || (images.thumbnail.is_none() && images.previews.is_none())
+        {
+            return Ok(());
+        }
+
+        let meta_path = self.meta_file_path(uuid);
+        let mut meta: MetaFile = serde_yaml::from_reader(fs::File::open(&meta_path)?)?;
+        if images.thumbnail.is_some() {
+            meta.thumbnail = images.thumbnail;
+        }
+        if images.previews.is_some() {
+            meta.previews = images.previews;
+        }
+        self.write_meta_file(&meta_path, &meta)?;
+        info!("added images to {}", uuid);
+        self.insert(meta_path, meta)?;
+        Ok(())
+    }
+}
+
+/// Whether images of `meta` are missing, previews only once it is no longer
+/// live.
+pub(super) fn lacks_images<T: Clone + PartialEq>(meta: &StreamMeta<T>) -> bool {
+    meta.thumbnail.is_none() || (meta.previews.is_none() && meta.live != Some(true))
+}
+
+/// The first local source of `meta` that is a playlist or a container with
+/// video, not a manifest or audio. Files derived from the stream go next to
+/// it.
+pub(super) fn video_source(meta: &MetaFile) -> Option<&PathBuf> {
+    meta.sources.iter().find(|source| {
+        !is_remote(source)
+            && mime_type(source)
+                .is_some_and(|typ| typ.starts_with("video/") || typ == "application/x-mpegURL")
+    })
+}
+
+impl ImageJob {
+    /// Writes the images, failures only leave out the affected image.
+    pub(super) fn run(self) -> Images {
+        let mut images = Images::default();
+        if let Some((relative, path)) = self.thumbnail {
+            match replace(&path, |temp| thumbnailer::poster(&self.input, temp)) {
+                Ok(()) => images.thumbnail = Some(relative),
+                Err(e) => debug!("no thumbnail: {:#}", e),
+            }
+        }
+        if let Some((relative, path)) = self.previews {
+            match write_previews(&self.input, &path) {
+                Ok(()) => images.previews = Some(relative),
+                Err(e) => debug!("no previews: {:#}", e),
+            }
+        }
+        images
+    }
+}
+
+/// Writes the sprite sheet next to the WebVTT file `vtt` indexing it.
+fn write_previews(input: &Path, vtt: &Path) -> Result<()> {
+    let sheet = vtt.with_extension("jpg");
+    let sprite = replace(&sheet, |temp| thumbnailer::sprite(input, temp))?;
+    let name = sheet.file_name().unwrap_or_default().to_string_lossy();
+    replace(vtt, |temp| Ok(fs::write(temp, sprite.webvtt(&name))?))
+}
+
+/// Writes `path` through a temporary file, so it is never served partially
+/// written.
+pub(super) fn replace<T>(path: &Path, write: impl FnOnce(&Path) -> Result<T>) -> Result<T> {
+    let temp = path.with_extension("tmp");
+    let written = write(&temp).and_then(|value| Ok(fs::rename(&temp, path).map(|()| value)?));
+    if written.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+    written
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::middleware::screen_grabber::record_test_pattern;
+    use crate::middleware::stream_store::test_store;
+    use crate::middleware::StreamEdit;
+    use tempdir::TempDir;
+
+    #[tokio::test]
+    async fn images_next_to_source() {
+        let temp = TempDir::new("images").unwrap();
+        let directory = temp.path().join("game");
+        fs::create_dir_all(directory.join("hls")).unwrap();
+        let meta = MetaFile {
+            live: Some(true),
+            ..MetaFile::new("game", vec![PathBuf::from("hls/master.m3u8")])
+        };
+        let mut store = test_store(temp.path(), &[("game/game.stream", &meta)]);
+
+        // previews wait for the end of live streams
+        let job = store.image_job(&meta.uuid).unwrap();
+        assert_eq!(directory.join("hls/master.m3u8"), job.input);
+        assert_eq!(
+            Some((
+                PathBuf::from("hls/master_thumbnail.jpg"),
+                directory.join("hls/master_thumbnail.jpg")
+            )),
+            job.thumbnail
+        );
+        assert!(job.previews.is_none());
+
+        fs::write(directory.join("hls/master_thumbnail.jpg"), b"jpeg").unwrap();
+        let images = Images {
+            thumbnail: job.thumbnail.map(|(relative, _)| relative),
+            previews: None,
+        };
+        store.set_images(&meta.uuid, images).unwrap();
+        assert_eq!(
+            Some(PathBuf::from("/streams/game/hls/master_thumbnail.jpg")),
+            store.stream_map[&meta.uuid].thumbnail
+        );
+        assert!(store
+            .resolve(Path::new("game/hls/master_thumbnail.jpg"))
+            .is_ok());
+        assert!(store.image_job(&meta.uuid).is_none());
+
+        let edit = StreamEdit {
+            live: Some(false),
+            ..Default::default()
+        };
+        store.update_stream(&meta.uuid, edit).unwrap();
+        let job = store.image_job(&meta.uuid).unwrap();
+        assert!(job.thumbnail.is_none());
+        assert_eq!(
+            Some(directory.join("hls/master_previews.vtt")),
+            job.previews.map(|(_, path)| path)
+        );
+    }
+
+    /// width and height in the start of frame of a JPEG
+    fn jpeg_size(jpeg: &[u8]) -> Option<(u32, u32)> {
+        let mut i = 2;
+        while i + 9 < jpeg.len() {
+            let marker = jpeg[i + 1];
+            if matches!(marker, 0xC0..=0xCF) && !matches!(marker, 0xC4 | 0xC8 | 0xCC) {
+                let height = u16::from_be_bytes([jpeg[i + 5], jpeg[i + 6]]);
+                let width = u16::from_be_bytes([jpeg[i + 7], jpeg[i + 8]]);
+                return Some((width.into(), height.into()));
+            }
+            i += 2 + usize::from(u16::from_be_bytes([jpeg[i + 2], jpeg[i + 3]]));
+        }
+        None
+    }
+
+    #[test]
+    fn images_of_recording() {
+        let temp = TempDir::new("images").unwrap();
+        let master = record_test_pattern(temp.path(), "pattern", 3);
+        let directory = master.parent().unwrap();
+        let meta = MetaFile::new("pattern", vec![PathBuf::from("pattern.m3u8")]);
+        let store = test_store(temp.path(), &[("pattern/pattern.stream", &meta)]);
+
+        let images = store.image_job(&meta.uuid).unwrap().run();
+        assert_eq!(
+            Some(PathBuf::from("pattern_thumbnail.jpg")),
+            images.thumbnail
+        );
+        let poster = fs::read(directory.join("pattern_thumbnail.jpg")).unwrap();
+        assert!(poster.starts_with(&[0xFF, 0xD8]));
+        assert_eq!(Some((640, 360)), jpeg_size(&poster));
+
+        assert_eq!(Some(PathBuf::from("pattern_previews.vtt")), images.previews);
+        let sheet = fs::read(directory.join("pattern_previews.jpg")).unwrap();
+        let (width, height) = jpeg_size(&sheet).unwrap();
+        let vtt = fs::read_to_string(directory.join("pattern_previews.vtt")).unwrap();
+        let tiles = vtt
+            .lines()
+            .filter_map(|line| line.strip_prefix("pattern_previews.jpg#xywh="))
+            .map(|xywh| {
+                let xywh = xywh.split(',').map(|n| n.parse().unwrap());
+                xywh.collect::<Vec<u32>>()
+            })
+            .collect::<Vec<_>>();
+        assert!(!tiles.is_empty());
+        // the tiles are laid out on the sheet, without leaving any of it out
+        for tile in &tiles {
+            assert_eq!([160, 90], tile[2..]);
+            assert!(tile[0] + tile[2] <= width && tile[1] + tile[3] <= height);
+        }
+        assert_eq!(Some(width), tiles.iter().map(|t| t[0] + t[2]).max());
+        assert_eq!(Some(height), tiles.iter().map(|t| t[1] + t[3]).max());
+    }
+}
@@ -0,0 +1,393 @@
+//! Markers of the moments of a match on the timeline of its streams. They
+//! are derived from the events of the fixture of a stream and refreshed
+//! while it is live, markers edited through the admin api are left alone.
+//! Markers are exported as WebVTT chapters next to the first local source,
+//! see [video_source], and as EXT-X-DATERANGE tags in the local playlists of
+//! streams that are no longer live.
+use super::{
+    data_types::*,
+    images::{replace, video_source},
+    vod, LocalStreamStore,
+};
+use crate::middleware::{thumbnailer, FixtureTimeline, FootballApi};
+use anyhow::Result;
+use chrono::{DateTime, TimeDelta, Utc};
+use hashbrown::HashMap;
+use std::{fmt::Write, fs, io, path::Path, sync::Arc, time::Duration};
+use tokio::sync::RwLock;
+use tracing::{debug, info, warn};
+use uuid::Uuid;
+
+/// How often the markers of live streams are refreshed.
+const REFRESH_INTERVAL: Duration = Duration::from_secs(5 * 60);
+/// The last chapter of streams of unknown duration lasts a minute.
+const LAST_CHAPTER: f64 = 60.0;
+
+impl LocalStreamStore {
+    /// Spawns the task deriving the markers of streams with a fixture. Live
+    /// streams are refreshed every few minutes. Other streams only once per
+    /// run, when they have no markers yet or were refreshed while live, to
+    /// spare the rate limit of the api.
+    pub fn run_markers(instance: Arc<RwLock<LocalStreamStore>>, football_api: Arc<FootballApi>) {
+        tokio::spawn(async move {
+            // whether the stream was live on its last refresh
+            let mut refreshed = HashMap::<Uuid, bool>::new();
+            let mut interval = tokio::time::interval(REFRESH_INTERVAL);
+            loop {
+                interval.tick().await;
+                let jobs = instance.read().await.marker_jobs(&refreshed);
+                for (uuid, fixture_id, live) in jobs {
+                    let timeline = match football_api.fixture_timeline(fixture_id).await {
+                        Ok(timeline) => timeline,
+                        Err(e) => {
+                            warn!("no events of fixture {}: {:#}", fixture_id, e);
+                            continue;
+                        }
+                    };
+                    refreshed.insert(uuid, live);
+                    let Some(timeline) = timeline else {
+                        continue;
+                    };
+                    if let Err(e) = instance.write().await.set_auto_markers(&uuid, &timeline) {
+                        warn!("could not add markers to {}: {:#}", uuid, e);
+                    }
+                }
+            }
+        });
+    }
+
+    /// Streams due for a refresh of their derived markers, with their
+    /// fixture and whether they are live.
+    fn marker_jobs(&self, refreshed: &HashMap<Uuid, bool>) -> Vec<(Uuid, u64, bool)> {
+        self.stream_map
+            .values()
+            // clips take the markers of their parent
+            .filter(|stream| stream.parent.is_none())
+            .filter(|stream| stream.markers.iter().all(|marker| marker.auto))
+            .filter_map(|stream| {
+                let live = stream.live == Some(true);
+                let due = live
+                    || refreshed
+                        .get(&stream.uuid)
+                        .copied()
+                        .unwrap_or(stream.markers.is_empty());
+                due.then_some((stream.uuid, stream.fixture_id?, live))
+            })
+            .collect()
+    }
+
+    /// Replaces the markers of `uuid` with those derived from `timeline`,
+    /// unless they were edited in the meantime.
+    pub(super) fn set_auto_markers(
+        &mut self,
+        uuid: &Uuid,
+        timeline: &FixtureTimeline,
+    ) -> Result<()> {
+        if !self.stream_map.contains_key(uuid) {
+            return Ok(());
+        }
+        let meta_path = self.meta_file_path(uuid);
+        let mut meta: MetaFile = serde_yaml::from_reader(fs::File::open(&meta_path)?)?;
+        if meta.markers.iter().any(|marker| !marker.auto) {
+            return Ok(());
+        }
+
+        let markers = derive_markers(timeline, self.recording_start(&meta_path, &meta));
+        if markers == meta.markers {
+            return Ok(());
+        }
+        meta.markers = markers;
+        self.export_markers(&meta_path, &mut meta)?;
+        self.write_meta_file(&meta_path, &meta)?;
+        info!("{} markers on {}", meta.markers.len(), uuid);
+        self.insert(meta_path, meta)?;
+        Ok(())
+    }
+
+    /// Writes the chapters of the markers of `meta` and tags them in its
+    /// playlists, once it is no longer live. Without markers the chapters
+    /// are removed.
+    pub(super) fn export_markers(&self, meta_path: &Path, meta: &mut MetaFile) -> Result<()> {
+        let chapters = video_source(meta).and_then(|source| {
+            let stem = source.file_stem()?.to_string_lossy();
+            let relative = source.with_file_name(format!("{}_chapters.vtt", stem));
+            let path = self.root.join(self.source_path(meta_path, &relative)?);
+            Some((relative, path))
+        });
+        meta.chapters = None;
+        match chapters {
+            Some((relative, path)) if !meta.markers.is_empty() => {
+                let vtt = chapters_webvtt(&meta.markers, self.duration(&meta.uuid));
+                replace(&path, |temp| Ok(fs::write(temp, vtt)?))?;
+                meta.chapters = Some(relative);
+            }
+            Some((_, path)) => match fs::remove_file(&path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            },
+            None => {}
+        }
+
+        if meta.live != Some(true) {
+            let start = self.recording_start(meta_path, meta);
+            for playlist in self.local_playlists(meta_path, meta) {
+                vod::write_markers(&playlist, start, &meta.markers)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Wall clock time the offsets of markers count from: the start of the
+    /// first local playlist, or the date of the stream for other sources.
+    pub(super) fn recording_start(&self, meta_path: &Path, meta: &MetaFile) -> DateTime<Utc> {
+        self.local_playlists(meta_path, meta)
+            .first()
+            .and_then(|playlist| {
+                vod::recording_start(playlist)
+                    .map_err(|e| debug!("{:#}", e))
+                    .ok()
+            })
+            .unwrap_or(meta.date)
+    }
+
+    /// Probed duration of the stream `uuid`, in seconds.
+    pub(super) fn duration(&self, uuid: &Uuid) -> Option<f64> {
+        self.stream_map
+            .get(uuid)?
+            .sources
+            .iter()
+            .find_map(|source| source.media.as_ref()?.duration)
+    }
+}
+
+/// Markers of the moments of `timeline`, for a recording that started at
+/// `start`. Moments before the start are left out.
+pub(super) fn derive_markers(timeline: &FixtureTimeline, start: DateTime<Utc>) -> Vec<Marker> {
+    let mut moments = Vec::new();
+    let first_half = timeline.first_half.unwrap_or(timeline.kickoff);
+    if timeline.first_half.is_some() {
+        moments.push((first_half, MarkerKind::KickOff, "Kick-off".to_string()));
+    }
+    if let Some(second_half) = timeline.second_half {
+        // the api has no end of the first half, the break lasts 15 minutes
+        let half_time = (second_half - TimeDelta::minutes(15))
+            .max(first_half + TimeDelta::minutes(45))
+            .min(second_half);
+        moments.push((half_time, MarkerKind::HalfTime, "Half-time".to_string()));
+        moments.push((
+            second_half,
+            MarkerKind::SecondHalf,
+            "Second half".to_string(),
+        ));
+    }
+
+    for event in &timeline.events {
+        let kind = match (event.kind.as_str(), event.detail.as_str()) {
+            ("Goal", "Missed Penalty") => continue,
+            ("Goal", _) => MarkerKind::Goal,
+            ("Card", "Yellow Card") => MarkerKind::YellowCard,
+            // red cards and second yellow cards
+            ("Card", _) => MarkerKind::RedCard,
+            _ => continue,
+        };
+        let mut title = match &event.player {
+            Some(player) => format!("{} {} ({})", event.minute(), player, event.team),
+            None => format!("{} {}", event.minute(), event.team),
+        };
+        if kind == MarkerKind::Goal && event.detail != "Normal Goal" {
+            let _ = write!(title, ", {}", event.detail.to_lowercase());
+        }
+        moments.push((event.time(timeline), kind, title));
+    }
+
+    let mut markers = moments
+        .into_iter()
+        .filter_map(|(time, kind, title)| {
+            let offset = (time - start).num_milliseconds() as f64 / 1000.0;
+            (offset >= 0.0).then_some(Marker {
+                offset,
+                kind,
+                title,
+                auto: true,
+            })
+        })
+        .collect::<Vec<_>>();
+    markers.sort_by(|a, b| a.offset.total_cmp(&b.offset));
+    markers
+}
+
+/// Markers edited through the admin api, ordered by their offset.
+pub(super) fn edited_markers(mut markers: Vec<Marker>) -> Result<Vec<Marker>, RegisterError> {
+    if let Some(invalid) = markers
+        .iter()
+        .find(|marker| !marker.offset.is_finite() || marker.offset < 0.0)
+    {
+        return Err(RegisterError::InvalidMarker(invalid.title.clone()));
+    }
+    for marker in &mut markers {
+        marker.auto = false;
+    }
+    markers.sort_by(|a, b| a.offset.total_cmp(&b.offset));
+    Ok(markers)
+}
+
+/// WebVTT chapters of `markers`, each lasting until the next one. The last
+/// one lasts until the end of the stream.
+fn chapters_webvtt(markers: &[Marker], duration: Option<f64>) -> String {
+    let mut vtt = String::from("WEBVTT\n");
+    for (i, marker) in markers.iter().enumerate() {
+        let end = match markers.get(i + 1) {
+            Some(next) => next.offset,
+            None => duration
+                .filter(|duration| *duration > marker.offset)
+                .unwrap_or(marker.offset + LAST_CHAPTER),
+        };
+        // cue payloads end at blank lines and cannot hold arrows
+        let title = marker.title.replace(['\r', '\n'], " ").replace("-->", "->");
+        let _ = write!(
+            vtt,
+            "\n{} --> {}\n{}\n",
+            thumbnailer::timestamp(marker.offset),
+            thumbnailer::timestamp(end),
+            title
+        );
+    }
+    vtt
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::middleware::stream_store::test_store;
+    use crate::middleware::{FixtureEvent, StreamEdit};
+    use std::path::PathBuf;
+    use tempdir::TempDir;
+
+    const PLAYLIST: &str = "#EXTM3U
+#EXT-X-VERSION:3
+#EXT-X-TARGETDURATION:2
+#EXT-X-PROGRAM-DATE-TIME:2024-09-11T12:58:00.000+0000
+#EXTINF:2.000000,
+match_00000.ts
+#EXTINF:2.000000,
+match_00001.ts
+#EXT-X-ENDLIST
+";
+
+    fn event(elapsed: u32, kind: &str, detail: &str) -> FixtureEvent {
+        FixtureEvent {
+            elapsed,
+            extra: None,
+            team: "Chelsea W".into(),
+            player: Some("S. Kerr".into()),
+            kind: kind.into(),
+            detail: detail.into(),
+        }
+    }
+
+    #[tokio::test]
+    async fn derive_edit_and_export() {
+        let kickoff = DateTime::parse_from_rfc3339("2024-09-11T13:00:00Z")
+            .unwrap()
+            .to_utc();
+        let timeline = FixtureTimeline {
+            kickoff,
+            first_half: Some(kickoff),
+            second_half: Some(kickoff + TimeDelta::minutes(63)),
+            events: vec![
+                event(23, "Goal", "Penalty"),
+                event(30, "Goal", "Missed Penalty"),
+                event(50, "Card", "Second Yellow card"),
+                event(70, "subst", "Substitution 1"),
+            ],
+        };
+
+        let temp = TempDir::new("markers").unwrap();
+        let playlist = temp.path().join("match.m3u8");
+        fs::write(&playlist, PLAYLIST).unwrap();
+        let meta = MetaFile {
+            date: kickoff,
+            fixture_id: Some(1),
+            ..MetaFile::new("match", vec![PathBuf::from("match.m3u8")])
+        };
+        let mut store = test_store(temp.path(), &[("match.stream", &meta)]);
+        assert_eq!(
+            vec![(meta.uuid, 1, false)],
+            store.marker_jobs(&HashMap::new())
+        );
+
+        // the recording started two minutes before kick-off
+        store.set_auto_markers(&meta.uuid, &timeline).unwrap();
+        let stream = &store.stream_map[&meta.uuid];
+        let markers = stream
+            .markers
+            .iter()
+            .map(|m| (m.offset, m.kind, m.title.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec![
+                (120.0, MarkerKind::KickOff, "Kick-off"),
+                (
+                    23.0 * 60.0 + 120.0,
+                    MarkerKind::Goal,
+                    "23' S. Kerr (Chelsea W), penalty"
+                ),
+                (48.0 * 60.0 + 120.0, MarkerKind::HalfTime, "Half-time"),
+                (63.0 * 60.0 + 120.0, MarkerKind::SecondHalf, "Second half"),
+                (
+                    68.0 * 60.0 + 120.0,
+                    MarkerKind::RedCard,
+                    "50' S. Kerr (Chelsea W)"
+                ),
+            ],
+            markers
+        );
+        assert!(stream.markers.iter().all(|m| m.auto));
+        assert_eq!(
+            Some(PathBuf::from("/streams/match_chapters.vtt")),
+            stream.chapters
+        );
+        let tagged = fs::read_to_string(&playlist).unwrap();
+        assert!(tagged.contains(
+            "#EXT-X-DATERANGE:ID=\"marker-1\",CLASS=\"goal\",START-DATE=\"2024-09-11T13:23:00.000Z\""
+        ));
+        assert!(store
+            .marker_jobs(&HashMap::from([(meta.uuid, false)]))
+            .is_empty());
+
+        // edited markers replace the tags and are not derived anymore
+        let edit = StreamEdit {
+            markers: Some(vec![Marker {
+                offset: 90.5,
+                kind: MarkerKind::Custom,
+                title: "Line\nup".into(),
+                auto: true,
+            }]),
+            ..Default::default()
+        };
+        store.update_stream(&meta.uuid, edit).unwrap();
+        assert_eq!(
+            "WEBVTT\n\n00:01:30.500 --> 00:02:30.500\nLine up\n",
+            fs::read_to_string(temp.path().join("match_chapters.vtt")).unwrap()
+        );
+        let tagged = fs::read_to_string(&playlist).unwrap();
+        assert_eq!(1, tagged.matches("#EXT-X-DATERANGE").count());
+        assert_eq!(1, tagged.matches("#EXT-X-PROGRAM-DATE-TIME").count());
+        assert!(tagged.contains("START-DATE=\"2024-09-11T12:59:30.500Z\",X-TITLE=\"Line up\""));
+        store.set_auto_markers(&meta.uuid, &timeline).unwrap();
+        assert!(!store.stream_map[&meta.uuid].markers[0].auto);
+        assert!(store.marker_jobs(&HashMap::new()).is_empty());
+
+        // without markers the chapters and tags go
+        let edit = StreamEdit {
+            markers: Some(Vec::new()),
+            ..Default::default()
+        };
+        store.update_stream(&meta.uuid, edit).unwrap();
+        assert!(!temp.path().join("match_chapters.vtt").exists());
+        assert!(store.stream_map[&meta.uuid].chapters.is_none());
+        assert!(!fs::read_to_string(&playlist)
+            .unwrap()
+            .contains("#EXT-X-DATERANGE"));
+    }
+}
@@ -0,0 +1,243 @@
+//! Background work on the media of streams, one stream at a time: local
+//! sources are probed for their details and missing images are generated,
+//! see [super::images]. Streams are queued when they are (re)loaded, when
+//! they turn VOD, and periodically while they are live.
+use super::{data_types::*, images::ImageJob, vod, LocalStreamStore};
+use crate::middleware::media_probe;
+use anyhow::Result;
+use std::{
+    ffi::OsStr,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use tokio::sync::{
+    mpsc::{unbounded_channel, UnboundedSender},
+    RwLock,
+};
+use tracing::{debug, warn};
+use uuid::Uuid;
+
+/// Local sources of a stream to probe, as request url and path on disk.
+struct ProbeJob {
+    sources: Vec<(PathBuf, PathBuf)>,
+}
+
+/// Details or error of each probed source, by request url.
+type Probes = Vec<(PathBuf, Result<MediaDetails, String>)>;
+
+impl LocalStreamStore {
+    /// Spawns the task handling the streams queued with
+    /// [LocalStreamStore::request_media].
+    pub(super) fn start_media_worker(
+        instance: &Arc<RwLock<LocalStreamStore>>,
+    ) -> UnboundedSender<Uuid> {
+        let (sender, mut receiver) = unbounded_channel::<Uuid>();
+        let instance = instance.clone();
+        tokio::spawn(async move {
+            while let Some(uuid) = receiver.recv().await {
+                let (probe_job, image_job) = {
+                    let store = instance.read().await;
+                    (store.probe_job(&uuid), store.image_job(&uuid))
+                };
+                if probe_job.is_none() && image_job.is_none() {
+                    continue;
+                }
+
+                let done = tokio::task::spawn_blocking(move || {
+                    (probe_job.map(ProbeJob::run), image_job.map(ImageJob::run))
+                })
+                .await;
+                let (probes, images) = match done {
+                    Ok(done) => done,
+                    Err(e) => {
+                        warn!("handling the media of {} failed: {}", uuid, e);
+                        continue;
+                    }
+                };
+
+                let mut store = instance.write().await;
+                if let Some(images) = images {
+                    if let Err(e) = store.set_images(&uuid, images) {
+                        warn!("could not add images to {}: {:#}", uuid, e);
+                    }
+                }
+                if let Some(probes) = probes {
+                    store.set_probes(&uuid, probes);
+                }
+            }
+        });
+        sender
+    }
+
+    /// Queues `uuid` to probe its sources and generate the images it is
+    /// missing. Without a running store nothing happens.
+    pub(super) fn request_media(&self, uuid: Uuid) {
+        if let Some(jobs) = &self.media_jobs {
+            let _ = jobs.send(uuid);
+        }
+    }
+
+    /// Live streams grow, and their first segments may not have been written
+    /// when they were registered.
+    pub(super) fn request_live_media(&self) {
+        self.stream_map
+            .values()
+            .filter(|stream| stream.live == Some(true))
+            .for_each(|stream| self.request_media(stream.uuid));
+    }
+
+    /// Keeps the details of the sources a reloaded `stream` still has, until
+    /// they are probed again.
+    pub(super) fn keep_probes(&self, stream: &mut Stream) {
+        let Some(known) = self.stream_map.get(&stream.uuid) else {
+            return;
+        };
+        for source in &mut stream.sources {
+            if let Some(old) = known.sources.iter().find(|old| old.url == source.url) {
+                source.media = old.media.clone();
+                source.error = old.error.clone();
+            }
+        }
+    }
+
+    fn probe_job(&self, uuid: &Uuid) -> Option<ProbeJob> {
+        let stream = self.stream_map.get(uuid)?;
+        let sources = stream
+            .sources
+            .iter()
+            .filter_map(|source| {
+                let relative = source.url.strip_prefix(&self.request_base).ok()?;
+                Some((source.url.clone(), self.local_path(relative)?))
+            })
+            .collect::<Vec<_>>();
+        (!sources.is_empty()).then_some(ProbeJob { sources })
+    }
+
+    fn set_probes(&mut self, uuid: &Uuid, probes: Probes) {
+        let Some(stream) = self.stream_map.get_mut(uuid) else {
+            return;
+        };
+        for (url, probe) in probes {
+            let Some(source) = stream.sources.iter_mut().find(|s| s.url == url) else {
+                continue;
+            };
+            match probe {
+                Ok(media) => {
+                    source.media = Some(media);
+                    source.error = None;
+                }
+                Err(e) => {
+                    source.media = None;
+                    source.error = Some(e);
+                }
+            }
+        }
+    }
+}
+
+impl ProbeJob {
+    fn run(self) -> Probes {
+        self.sources
+            .into_iter()
+            .map(|(url, path)| {
+                // the message stays vague, it should not reveal the paths on
+                // the server
+                let probe = probe(&path).map_err(|e| {
+                    debug!("{:#}", e);
+                    if path.exists() {
+                        "source could not be probed".to_string()
+                    } else {
+                        "source does not exist".to_string()
+                    }
+                });
+                (url, probe)
+            })
+            .collect()
+    }
+}
+
+/// Probes the source at `path`. HLS playlists are summarized from their
+/// segments, so live playlists have a duration as well.
+fn probe(path: &Path) -> Result<MediaDetails> {
+    let extension = path.extension().and_then(OsStr::to_str);
+    let playlist = match extension {
+        Some("m3u8" | "m3u") => Some(vod::summarize_playlist(path)?),
+        _ => None,
+    };
+    // DASH manifests are only checked to exist
+    let info = match extension {
+        Some("dash" | "mpd") => {
+            std::fs::metadata(path)?;
+            Default::default()
+        }
+        _ => media_probe::probe(path)?,
+    };
+
+    Ok(MediaDetails {
+        duration: playlist
+            .as_ref()
+            .map(|p| p.duration)
+            .filter(|d| *d > 0.0)
+            .or(info.duration),
+        width: info.width,
+        height: info.height,
+        video_codec: info.video_codec,
+        audio_codec: info.audio_codec,
+        bitrate: info.bitrate,
+        segments: playlist.as_ref().map(|p| p.segments),
+        ended: playlist.is_none_or(|p| p.ended),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::middleware::stream_store::test_store;
+    use tempdir::TempDir;
+
+    #[test]
+    fn source_errors() {
+        let temp = TempDir::new("media").unwrap();
+        let meta = MetaFile::new(
+            "game",
+            vec![
+                PathBuf::from("game.m3u8"),
+                PathBuf::from("https://example.com/game.mkv"),
+            ],
+        );
+        let mut store = test_store(temp.path(), &[("game.stream", &meta)]);
+        let meta_path = temp.path().join("game.stream");
+
+        // remote sources are left to the player
+        let job = store.probe_job(&meta.uuid).unwrap();
+        assert_eq!(
+            vec![(
+                PathBuf::from("/streams/game.m3u8"),
+                temp.path().join("game.m3u8")
+            )],
+            job.sources
+        );
+
+        let probes = job.run();
+        assert_eq!(Err("source does not exist".to_string()), probes[0].1);
+        let media = MediaDetails {
+            segments: Some(2),
+            ended: true,
+            ..Default::default()
+        };
+        let url = PathBuf::from("/streams/game.m3u8");
+        store.set_probes(&meta.uuid, vec![(url.clone(), Ok(media.clone()))]);
+
+        // reloading keeps the details until the next probe
+        store.load(&[meta_path]).unwrap();
+        let sources = &store.stream_map[&meta.uuid].sources;
+        assert_eq!(Some(&media), sources[0].media.as_ref());
+        assert!(sources[0].error.is_none());
+        assert!(sources[1].media.is_none());
+
+        store.set_probes(&meta.uuid, probes);
+        let sources = &store.stream_map[&meta.uuid].sources;
+        assert!(sources[0].media.is_none());
+        assert_eq!(Some("source does not exist"), sources[0].error.as_deref());
+    }
+}
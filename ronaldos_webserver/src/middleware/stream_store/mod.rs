@@ -1,30 +1,53 @@
+mod catalog;
+mod clips;
 pub mod data_types;
+mod editing;
+mod images;
+mod markers;
+mod media;
+mod retention;
+mod vod;
+use self::catalog::Catalog;
+pub use self::catalog::{Page, StreamQuery};
+pub use self::clips::ClipRequest;
 use self::data_types::*;
+pub use self::editing::StreamEdit;
+use super::screen_grabber::Recording;
 use anyhow::{bail, ensure, Context, Result};
 use chrono::{DateTime, Utc};
-use hashbrown::HashMap;
-use notify::{Config, Event, EventKind, PollWatcher, RecursiveMode, Watcher};
+use hashbrown::{HashMap, HashSet};
+use notify::{PollWatcher, RecommendedWatcher, RecursiveMode, Watcher};
+use notify_debouncer_mini::{new_debouncer_opt, DebounceEventResult, DebouncedEvent, Debouncer};
+use ronaldos_config::{FileWatch, WatchMode};
 use std::{
     ffi::OsStr,
     fs::{self, OpenOptions},
-    io::{self, Write},
-    path::{Path, PathBuf},
+    path::{Component, Path, PathBuf},
     sync::Arc,
-    time::Duration,
+    time::{Duration, SystemTime},
+};
+use tokio::sync::{
+    mpsc::{channel, Receiver, Sender, UnboundedSender},
+    RwLock,
 };
-use tokio::sync::{mpsc::channel, mpsc::Receiver, RwLock};
 use tracing::{debug, error, info, instrument, trace, warn};
 use uuid::Uuid;
 
+/// How often live streams are checked for playlists that stopped updating.
+const STALE_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+/// Live playlists that have not been written for this long are finished.
+const STALE_AFTER: Duration = Duration::from_secs(10 * 60);
+
 /// Provides video streams that are persisted on the filesystem. Even given they
 /// are written real-time. At the moment there is support for the following
 /// formats:
 /// * HLS
 /// * DASH
-/// * MP4
+/// * MP4, MKV, WebM, MOV and MPEG-TS
+/// * MP3 and AAC audio
 ///
-/// > note that the file size of MP4 files cannot exceed available RAM space, in
-/// this case the program will crash.
+/// `.stream` files with sources of other types are rejected, see
+/// [data_types::mime_type].
 ///
 /// # Ajustable Bitrate
 ///
@@ -41,8 +64,17 @@ pub struct LocalStreamStore {
     /// of truth.
     stream_map: HashMap<Uuid, Stream>,
     uuid_lookup: HashMap<PathBuf, Uuid>,
-    /// This watcher object is used to exit the watcher task.
-    file_watcher: Option<PollWatcher>,
+    /// Queryable mirror of the stream map, in memory unless a file is given
+    /// with [LocalStreamStore::with_catalog]
+    catalog: Catalog,
+    /// watching stops when it is dropped
+    file_watcher: Option<FileWatcher>,
+    /// Directories scanned already. The watcher reports them whenever a file
+    /// in them is written, only new ones need a scan.
+    known_directories: HashSet<PathBuf>,
+    /// Queue of the task probing sources and generating thumbnails and
+    /// previews, see [LocalStreamStore::request_media]
+    media_jobs: Option<UnboundedSender<Uuid>>,
 }
 
 impl LocalStreamStore {
@@ -57,97 +89,172 @@ impl LocalStreamStore {
             request_base,
             stream_map: HashMap::default(),
             uuid_lookup: HashMap::default(),
+            catalog: Catalog::in_memory().expect("in memory catalog"),
             file_watcher: None,
+            known_directories: HashSet::default(),
+            media_jobs: None,
         }
     }
 
-    pub async fn run(instance: &Arc<RwLock<LocalStreamStore>>) {
+    /// Keeps the catalog in a SQLite database at `path`, it is reconciled
+    /// with the `.stream` files once they are loaded.
+    pub fn with_catalog(mut self, path: &Path) -> Result<Self> {
+        self.catalog = Catalog::open(path)?;
+        Ok(self)
+    }
+
+    pub async fn run(instance: &Arc<RwLock<LocalStreamStore>>, watch: &FileWatch) {
+        instance.write().await.media_jobs = Some(Self::start_media_worker(instance));
+
         // spawn loading task
         let loading_instance = instance.clone();
         tokio::spawn(async move {
             let mut unlocked = loading_instance.write().await;
             let root_path = unlocked.root.clone();
             unlocked.load(&[root_path; 1]).unwrap();
+            match unlocked.catalog.retain(unlocked.stream_map.keys()) {
+                Ok(removed) => debug!("dropped {} stale stream(s) from the catalog", removed),
+                Err(e) => warn!("could not reconcile catalog: {:#}", e),
+            }
+        });
+
+        // spawn task that turns abandoned live streams into VOD
+        let stale_instance = instance.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(STALE_CHECK_INTERVAL);
+            loop {
+                interval.tick().await;
+                Self::finish_stale_streams(&stale_instance, STALE_AFTER).await;
+                stale_instance.read().await.request_live_media();
+            }
         });
 
         // spawn file watcher task
-        let mut receiver = instance.write().await.start_filewatcher();
+        let mut receiver = instance.write().await.start_filewatcher(watch);
         let watch_instance = instance.clone();
         tokio::spawn(async move {
-            while let Some(event) = receiver.recv().await {
+            while let Some(events) = receiver.recv().await {
                 watch_instance
                     .write()
                     .await
-                    .handle_debounce_event(event)
+                    .handle_debounced_events(events)
                     .await;
             }
         });
     }
 
-    fn start_filewatcher(&mut self) -> Receiver<Event> {
+    /// Watches the root with inotify, or by polling when configured or when
+    /// the platform watcher cannot be set up. Changes are debounced, so a
+    /// burst of segment writes ends up as a single event per path.
+    fn start_filewatcher(&mut self, watch: &FileWatch) -> Receiver<Vec<DebouncedEvent>> {
         let (sender, receiver) = channel(32);
-        let handle_notify_receiver = move |res| {
-            let event = match res {
-                Ok(event) => event,
-                Err(e) => {
-                    error!("cannot handle notify event because {}", e);
-                    return;
-                }
-            };
-            trace!("received {:?}", &event);
+        let config = notify_debouncer_mini::Config::default()
+            .with_timeout(Duration::from_millis(watch.debounce_ms))
+            .with_notify_config(
+                notify::Config::default()
+                    .with_poll_interval(Duration::from_secs(watch.poll_interval)),
+            );
 
-            if let Err(e) = sender.blocking_send(event) {
-                warn!("channel failure to filewatcher: {}", e);
+        let native = match watch.mode {
+            WatchMode::Native => {
+                watch_root::<RecommendedWatcher>(&self.root, config.clone(), sender.clone())
+                    .map_err(|e| warn!("native file watching failed, polling instead: {}", e))
+                    .ok()
             }
+            WatchMode::Poll => None,
+        };
+        let watcher = match native {
+            Some(watcher) => FileWatcher::Native(watcher),
+            None => FileWatcher::Poll(
+                watch_root::<PollWatcher>(&self.root, config, sender)
+                    .expect("polling the stream directory"),
+            ),
         };
-
-        let poll_config = Config::default().with_poll_interval(Duration::from_secs(5));
-        let mut watcher = PollWatcher::new(handle_notify_receiver, poll_config).unwrap();
-        watcher
-            .watch(&self.root, RecursiveMode::NonRecursive)
-            .unwrap();
 
         self.file_watcher = Some(watcher);
         receiver
     }
 
-    async fn handle_debounce_event(&mut self, event: notify::event::Event) {
-        let paths = event.paths;
-        let result: Result<()> = match event.kind {
-            EventKind::Create(_) | EventKind::Modify(_) => self.load(&paths),
-            EventKind::Remove(_) => self
-                .removed(&paths)
-                .await
-                .then_some(())
-                .context("nothing removed"),
-            _ => Ok(()),
-        };
+    /// Paths that still exist are loaded, new directories are scanned as a
+    /// whole, e.g. when moved in. Paths that are gone are removed. Events are
+    /// handled in that order, so streams in moved directories are kept.
+    async fn handle_debounced_events(&mut self, events: Vec<DebouncedEvent>) {
+        let (present, gone): (Vec<PathBuf>, Vec<PathBuf>) = events
+            .into_iter()
+            .map(|event| event.path)
+            .partition(|path| path.exists());
+
+        // other files are segments and playlists being written, known
+        // directories report the same writes
+        let changed = present
+            .into_iter()
+            .filter(|path| match path.is_dir() {
+                true => !self.known_directories.contains(path),
+                false => is_meta_file(path),
+            })
+            .collect::<Vec<_>>();
+        if !changed.is_empty() {
+            if let Err(e) = self.load(&changed) {
+                warn!("failed handling event: {}", e);
+            }
+        }
 
-        if let Err(e) = result {
-            warn!("failed handling event: {}", e);
+        if !gone.is_empty() {
+            self.known_directories
+                .retain(|directory| !gone.iter().any(|path| directory.starts_with(path)));
+            if self.removed(&gone).await {
+                debug!("removed streams of {:?}", gone);
+            }
         }
     }
 
-    /// Load a .stream meta file from disk. path can be a directory or a file.
-    /// note that recursive scanning is disabled. see [LocalStreamStore::scan]
-    fn load(&mut self, paths: &[PathBuf]) -> Result<()> {
+    /// Load a .stream meta file from disk. path can be a directory or a file,
+    /// directories are scanned recursively. see [LocalStreamStore::scan]
+    pub(crate) fn load(&mut self, paths: &[PathBuf]) -> Result<()> {
         let mut lookup = Vec::new();
         let mut new_meta_files = Vec::new();
         for path in paths {
-            new_meta_files.extend(self.scan(path)?.map(|(p, meta)| {
-                lookup.push((p, meta.uuid));
-                (meta.uuid, meta.into())
+            let (directories, found) = self.scan(path)?;
+            self.known_directories.extend(directories);
+            new_meta_files.extend(found.filter_map(|(p, meta)| {
+                let mut stream = match Stream::try_from(meta) {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        warn!("rejecting {}: {}", p.to_string_lossy(), e);
+                        return None;
+                    }
+                };
+                self.request_media(stream.uuid);
+                self.keep_probes(&mut stream);
+                lookup.push((p, stream.uuid));
+                Some((stream.uuid, stream))
             }));
         }
 
-        self.uuid_lookup.extend(lookup);
         self.stream_map.extend(new_meta_files);
+        self.index(lookup.iter().map(|(path, uuid)| (path.as_path(), uuid)));
+        self.uuid_lookup.extend(lookup);
         Ok(())
     }
 
-    /// Scans for .stream files in a given path none recursively. If path is not a
-    /// sub directory of root, None is returned.
-    fn scan(&self, path: &Path) -> Result<impl Iterator<Item = (PathBuf, MetaFile)>> {
+    /// Mirrors the known streams at `paths` into the catalog. Failures are
+    /// logged only, the `.stream` files remain the source of truth.
+    fn index<'a>(&self, paths: impl IntoIterator<Item = (&'a Path, &'a Uuid)>) {
+        let streams = paths
+            .into_iter()
+            .filter_map(|(path, uuid)| Some((path, self.stream_map.get(uuid)?)));
+        if let Err(e) = self.catalog.upsert(streams) {
+            warn!("could not update catalog: {:#}", e);
+        }
+    }
+
+    /// Scans for .stream files in a given path and all directories below it,
+    /// returns the directories scanned as well. Symlinked directories are not
+    /// followed. If path is not a sub directory of root, an error is returned.
+    fn scan(
+        &self,
+        path: &Path,
+    ) -> Result<(Vec<PathBuf>, impl Iterator<Item = (PathBuf, MetaFile)>)> {
         ensure!(
             path.starts_with(&self.root),
             format!(
@@ -166,14 +273,37 @@ impl LocalStreamStore {
 
         let meta_data = fs::metadata(path)
             .with_context(|| format!("failed to get metadata for {}", path.to_string_lossy()))?;
+        let mut scanned = Vec::new();
 
-        if meta_data.is_file() {
-            push_found(path);
+        if !meta_data.is_dir() {
+            if is_meta_file(path) {
+                push_found(path);
+            }
         } else {
-            let mut dir_entry = fs::read_dir(path)
-                .with_context(|| format!("failed to read dir {}", path.to_string_lossy()))?;
-            while let Some(Ok(entry)) = dir_entry.next() {
-                push_found(&entry.path());
+            let mut directories = vec![path.to_path_buf()];
+            while let Some(directory) = directories.pop() {
+                let dir_entry = match fs::read_dir(&directory) {
+                    Ok(dir_entry) => dir_entry,
+                    Err(e) if directory == path => {
+                        return Err(e).with_context(|| {
+                            format!("failed to read dir {}", path.to_string_lossy())
+                        })
+                    }
+                    Err(e) => {
+                        warn!("failed to read dir {}: {}", directory.to_string_lossy(), e);
+                        continue;
+                    }
+                };
+                scanned.push(directory.clone());
+
+                for entry in dir_entry.flatten() {
+                    let entry_path = entry.path();
+                    match entry.file_type() {
+                        Ok(file_type) if file_type.is_dir() => directories.push(entry_path),
+                        Ok(_) if is_meta_file(&entry_path) => push_found(&entry_path),
+                        _ => {}
+                    }
+                }
             }
         }
 
@@ -183,7 +313,7 @@ impl LocalStreamStore {
             path.to_string_lossy()
         );
 
-        Ok(found.into_iter())
+        Ok((scanned, found.into_iter()))
     }
 
     fn parse_file(&self, path: &Path) -> Result<(PathBuf, MetaFile)> {
@@ -205,57 +335,137 @@ impl LocalStreamStore {
 
         let mut stream = serde_yaml::from_reader::<std::fs::File, MetaFile>(file)
             .with_context(|| format!("could not parse {}", path.to_string_lossy()))?;
-        self.patch_sources(&mut stream);
+        self.patch_sources(path, &mut stream);
 
         Ok((path.to_path_buf(), stream))
     }
 
     /// This function converts the actual paths on disk to request urls. This prevents us from
-    /// having to convert sources during a given request.
-    fn patch_sources(&self, stream: &mut MetaFile) {
-        for source in stream.sources.iter_mut().filter(|s| !s.starts_with("http")) {
-            let full = self.request_base.join(&source);
-            *source = full;
+    /// having to convert sources during a given request. Sources are relative
+    /// to the directory of the `.stream` file at `meta_path`, local sources
+    /// that would leave the root are dropped. The same goes for the images
+    /// of the stream.
+    fn patch_sources(&self, meta_path: &Path, stream: &mut MetaFile) {
+        for image in [
+            &mut stream.thumbnail,
+            &mut stream.previews,
+            &mut stream.chapters,
+        ] {
+            *image = image
+                .take()
+                .and_then(|image| self.source_path(meta_path, &image))
+                .map(|relative| self.request_base.join(relative));
         }
+
+        stream.sources = std::mem::take(&mut stream.sources)
+            .into_iter()
+            .filter_map(|source| {
+                if is_remote(&source) {
+                    return Some(source);
+                }
+                let Some(relative) = self.source_path(meta_path, &source) else {
+                    warn!(
+                        "dropping {} of {}, it is outside of the root",
+                        source.to_string_lossy(),
+                        meta_path.to_string_lossy()
+                    );
+                    return None;
+                };
+                Some(self.request_base.join(relative))
+            })
+            .collect();
+    }
+
+    /// Path relative to the root of a local `source` of the `.stream` file at
+    /// `meta_path`. None for sources that would leave the root.
+    fn source_path(&self, meta_path: &Path, source: &Path) -> Option<PathBuf> {
+        let directory = meta_path.parent()?.strip_prefix(&self.root).ok()?;
+        normalize(&directory.join(source))
     }
 
+    /// Forgets the streams of removed `.stream` files, or of all `.stream`
+    /// files below removed directories. Streams that are still known by
+    /// another path, e.g. after their directory was moved, are kept.
     pub async fn removed(&mut self, paths: &[PathBuf]) -> bool {
         let mut removed_count = 0;
-        let files = paths
-            .iter()
-            .filter(|f| f.extension() == Some(OsStr::new(STREAM_EXT)));
+        let files = self
+            .uuid_lookup
+            .keys()
+            .filter(|file| paths.iter().any(|p| file.starts_with(p)))
+            .cloned()
+            .collect::<Vec<_>>();
 
         for file in files {
-            let Some(uuid) = self.uuid_lookup.remove(file) else {
+            let Some(uuid) = self.uuid_lookup.remove(&file) else {
                 continue;
             };
+            if self.uuid_lookup.values().any(|u| *u == uuid) {
+                continue;
+            }
 
             if self.stream_map.remove(&uuid).is_some() {
                 debug!("removed {} {} from cache", file.to_string_lossy(), uuid);
                 removed_count += 1;
             }
+            if let Err(e) = self.catalog.remove(&uuid) {
+                warn!("could not update catalog: {:#}", e);
+            }
         }
         removed_count > 0
     }
 
-    /// Returns a list of all available streams ready for playback sorted by on
-    /// most recent date first. Note that even though they are available, the
-    /// actual sources might be offline for what reason.
-    ///
-    /// # Return
-    ///
-    /// vector of registered streams
-    pub fn get_available_streams(&self) -> impl Iterator<Item = &Stream> {
-        self.stream_map.values()
+    /// Streams matching `query` in the requested order, together with the
+    /// number of matches on all pages. Note that even though they are
+    /// available, the actual sources might be offline for what reason.
+    pub fn query_streams(&self, query: &StreamQuery) -> Result<Page<&Stream>> {
+        let page = self.catalog.query(query)?;
+        let items = page
+            .items
+            .iter()
+            .filter_map(|uuid| self.stream_map.get(uuid))
+            .collect();
+        Ok(Page {
+            total: page.total,
+            items,
+            next: page.next,
+        })
     }
 
-    #[instrument(skip(self, writer))]
-    pub fn get_segment(&self, file: &Path, mut writer: impl Write) -> io::Result<()> {
-        let path = self.root.join(file);
-        debug!("reading segment {}", path.to_string_lossy());
-        let mut f = fs::OpenOptions::new().read(true).open(&path)?;
-        std::io::copy(&mut f, &mut writer)?;
-        Ok(())
+    /// Resolves the segment, playlist or MP4 `file` of a request to the file
+    /// on disk. Symlinks are followed and the result has to stay inside the
+    /// root. Only sources of registered streams and files in their
+    /// directories are served, see [belongs_to].
+    #[instrument(skip(self))]
+    pub fn resolve(&self, file: &Path) -> Result<PathBuf, ResolveError> {
+        let path = self
+            .local_path(file)
+            .ok_or_else(|| ResolveError::InvalidPath(file.to_path_buf()))?;
+        let refused = || ResolveError::NotAStreamFile(file.to_path_buf());
+
+        let root = self.root.canonicalize()?;
+        let canonical = path.canonicalize()?;
+        let relative = canonical.strip_prefix(&root).map_err(|_| refused())?;
+        if !canonical.is_file() || !self.local_sources().any(|s| belongs_to(s, relative)) {
+            return Err(refused());
+        }
+
+        debug!("reading segment {}", canonical.to_string_lossy());
+        Ok(canonical)
+    }
+
+    /// sources of the registered streams stored below the root, relative to
+    /// it
+    fn local_sources(&self) -> impl Iterator<Item = &Path> {
+        self.stream_map
+            .values()
+            .flat_map(|stream| &stream.sources)
+            .filter_map(|source| source.url.strip_prefix(&self.request_base).ok())
+    }
+
+    /// Resolves `file` of a request below the root. Returns None for empty
+    /// paths and paths that could leave the root, e.g. through `..`.
+    pub fn local_path(&self, file: &Path) -> Option<PathBuf> {
+        is_local(file).then(|| self.root.join(file))
     }
 
     /// registers a new fixture
@@ -277,6 +487,12 @@ impl LocalStreamStore {
             date,
             live: Some(true),
             fixture_id,
+            pinned: None,
+            thumbnail: None,
+            previews: None,
+            markers: Vec::new(),
+            chapters: None,
+            parent: None,
         };
 
         let name = format!("{}.{}", registration.uuid, STREAM_EXT);
@@ -292,6 +508,245 @@ impl LocalStreamStore {
         info!("created {}", file_name.to_string_lossy());
         Ok(registration.uuid)
     }
+
+    /// registers a recording of the [super::screen_grabber::ScreenGrabber].
+    /// Only the master playlist is exposed as source, clients pick one of
+    /// the variants it references themselves.
+    pub async fn register_recording(
+        &self,
+        recording: &Recording,
+        description: String,
+        date: DateTime<Utc>,
+        fixture_id: Option<u64>,
+    ) -> Result<Uuid, RegisterError> {
+        let master = recording
+            .playlist()
+            .strip_prefix(&self.root)
+            .map_err(|_| RegisterError::OutsideRoot(recording.playlist().to_path_buf()))?;
+        self.register(description, vec![master.to_path_buf()], date, fixture_id)
+            .await
+    }
+
+    /// Turns a live stream into VOD: its local HLS playlists are rewritten
+    /// with measured segment durations and an ENDLIST, and the `.stream`
+    /// file is marked as no longer live. The playlists are rewritten without
+    /// holding the lock.
+    pub async fn finish_stream(instance: &RwLock<LocalStreamStore>, uuid: &Uuid) -> Result<()> {
+        let playlists = instance.read().await.live_playlists(uuid)?;
+        tokio::task::spawn_blocking(move || {
+            playlists
+                .iter()
+                .try_for_each(|playlist| vod::finalize_playlist(playlist))
+        })
+        .await??;
+        instance.write().await.mark_finished(uuid)
+    }
+
+    fn live_playlists(&self, uuid: &Uuid) -> Result<Vec<PathBuf>> {
+        let meta_path = self.meta_file_path(uuid);
+        let meta = self.read_meta_file(&meta_path)?;
+        Ok(self.local_playlists(&meta_path, &meta))
+    }
+
+    fn read_meta_file(&self, meta_path: &Path) -> Result<MetaFile> {
+        let file = fs::File::open(meta_path)
+            .with_context(|| format!("error opening {}", meta_path.to_string_lossy()))?;
+        serde_yaml::from_reader::<_, MetaFile>(file)
+            .with_context(|| format!("could not parse {}", meta_path.to_string_lossy()))
+    }
+
+    fn mark_finished(&mut self, uuid: &Uuid) -> Result<()> {
+        let meta_path = self.meta_file_path(uuid);
+        let mut meta = self.read_meta_file(&meta_path)?;
+        meta.live = Some(false);
+        if !meta.markers.is_empty() {
+            self.export_markers_of(&meta_path, &mut meta);
+        }
+        self.write_meta_file(&meta_path, &meta)?;
+
+        if let Some(stream) = self.stream_map.get_mut(uuid) {
+            stream.live = Some(false);
+        }
+        self.index([(meta_path.as_path(), uuid)]);
+        self.request_media(*uuid);
+        info!("{} is no longer live", uuid);
+        Ok(())
+    }
+
+    /// Finishes live streams whose playlists have not been written for
+    /// `stale_after`. Streams without local playlists are left alone.
+    async fn finish_stale_streams(instance: &RwLock<LocalStreamStore>, stale_after: Duration) {
+        let Some(deadline) = SystemTime::now().checked_sub(stale_after) else {
+            return;
+        };
+
+        let stale = {
+            let unlocked = instance.read().await;
+            unlocked
+                .stream_map
+                .values()
+                .filter(|stream| stream.live == Some(true))
+                .map(|stream| stream.uuid)
+                .filter(|uuid| unlocked.is_stale(uuid, deadline))
+                .collect::<Vec<_>>()
+        };
+
+        for uuid in stale {
+            debug!("{} went stale", uuid);
+            if let Err(e) = Self::finish_stream(instance, &uuid).await {
+                warn!("could not finish {}: {:#}", uuid, e);
+            }
+        }
+    }
+
+    fn is_stale(&self, uuid: &Uuid, deadline: SystemTime) -> bool {
+        let meta_path = self.meta_file_path(uuid);
+        let Ok(file) = fs::File::open(&meta_path) else {
+            return false;
+        };
+        let Ok(meta) = serde_yaml::from_reader::<_, MetaFile>(file) else {
+            return false;
+        };
+
+        let playlists = self.local_playlists(&meta_path, &meta);
+        !playlists.is_empty()
+            && playlists
+                .iter()
+                .all(|p| vod::last_modified(p).is_ok_and(|modified| modified < deadline))
+    }
+
+    /// path of the `.stream` file of `uuid`. Streams that are not loaded yet
+    /// are expected at the location [Self::register] writes them to.
+    fn meta_file_path(&self, uuid: &Uuid) -> PathBuf {
+        self.uuid_lookup
+            .iter()
+            .find_map(|(path, u)| (u == uuid).then(|| path.clone()))
+            .unwrap_or_else(|| self.root.join(format!("{}.{}", uuid, STREAM_EXT)))
+    }
+
+    /// Replaces the `.stream` file at `path` through a temporary file, so the
+    /// file watcher never parses a partially written file.
+    fn write_meta_file(&self, path: &Path, meta: &MetaFile) -> Result<(), RegisterError> {
+        let temp = path.with_extension("tmp");
+        serde_yaml::to_writer(fs::File::create(&temp)?, meta)?;
+        fs::rename(&temp, path)?;
+        Ok(())
+    }
+
+    /// HLS playlists of a stream that are stored in the stream directory
+    fn local_playlists(&self, meta_path: &Path, meta: &MetaFile) -> Vec<PathBuf> {
+        meta.sources
+            .iter()
+            .filter(|s| !is_remote(s))
+            .filter(|s| matches!(s.extension().and_then(OsStr::to_str), Some("m3u8" | "m3u")))
+            .filter_map(|s| self.source_path(meta_path, s))
+            .map(|s| self.root.join(s))
+            .collect()
+    }
+}
+
+/// Debouncer of the native or polling watcher, only held to be dropped.
+#[allow(dead_code)]
+enum FileWatcher {
+    Native(Debouncer<RecommendedWatcher>),
+    Poll(Debouncer<PollWatcher>),
+}
+
+fn watch_root<T: Watcher>(
+    root: &Path,
+    config: notify_debouncer_mini::Config,
+    sender: Sender<Vec<DebouncedEvent>>,
+) -> notify::Result<Debouncer<T>> {
+    let handle_events = move |result: DebounceEventResult| {
+        let events = match result {
+            Ok(events) => events,
+            Err(e) => {
+                error!("cannot handle notify event because {}", e);
+                return;
+            }
+        };
+        trace!("received {:?}", &events);
+
+        if let Err(e) = sender.blocking_send(events) {
+            warn!("channel failure to filewatcher: {}", e);
+        }
+    };
+
+    let mut debouncer = new_debouncer_opt::<_, T>(config, handle_events)?;
+    debouncer
+        .watcher()
+        .watch(root, RecursiveMode::Recursive)?;
+    Ok(debouncer)
+}
+
+fn is_meta_file(path: &Path) -> bool {
+    path.extension() == Some(OsStr::new(STREAM_EXT))
+}
+
+/// Lexically resolves `.` and `..` in a relative path. None for absolute
+/// paths and paths that would leave the directory they are relative to.
+fn normalize(path: &Path) -> Option<PathBuf> {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => normalized.push(name),
+            Component::CurDir => {}
+            Component::ParentDir if normalized.pop() => {}
+            _ => return None,
+        }
+    }
+    Some(normalized)
+}
+
+/// Whether `file` is a relative path that stays below the root.
+fn is_local(file: &Path) -> bool {
+    let mut components = file.components().peekable();
+    components.peek().is_some() && components.all(|c| matches!(c, Component::Normal(_)))
+}
+
+/// Sources that are URLs, e.g. `https://..`, are played from elsewhere. Note
+/// that [Path::starts_with] compares whole components, so `https:` would not
+/// match `http`.
+fn is_remote(source: &Path) -> bool {
+    source.to_str().is_some_and(|s| s.contains("://"))
+}
+
+/// Whether `file` belongs to the stream with the local `source`, both
+/// relative to the root. Everything in the directory of a source belongs to
+/// it, unless that is the root itself. Sources in the root only claim the
+/// files starting with their stem, like the segments ffmpeg writes next to
+/// a playlist.
+fn belongs_to(source: &Path, file: &Path) -> bool {
+    if source == file {
+        return true;
+    }
+
+    match source.parent() {
+        Some(directory) if directory != Path::new("") => file.starts_with(directory),
+        _ => {
+            file.parent() == Some(Path::new(""))
+                && source
+                    .file_stem()
+                    .zip(file.file_name())
+                    .is_some_and(|(stem, name)| {
+                        name.as_encoded_bytes().starts_with(stem.as_encoded_bytes())
+                    })
+        }
+    }
+}
+
+/// A store of the root `root` serving under `/streams`, with the `.stream`
+/// files `streams`, relative to the root, written and loaded.
+#[cfg(test)]
+pub(crate) fn test_store(root: &Path, streams: &[(&str, &MetaFile)]) -> LocalStreamStore {
+    let mut store = LocalStreamStore::new(root.into(), PathBuf::from("/streams"));
+    for (path, meta) in streams {
+        let path = root.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        store.write_meta_file(&path, meta).unwrap();
+    }
+    store.load(&[root.into()]).unwrap();
+    store
 }
 
 #[cfg(test)]
@@ -300,6 +755,13 @@ mod tests {
     use std::str::FromStr;
     use tempdir::TempDir;
 
+    fn endpoint_sources(files: &[&str]) -> Vec<Source> {
+        files
+            .iter()
+            .map(|file| Source::try_from(Path::new("/endpoint").join(file)).unwrap())
+            .collect()
+    }
+
     fn assert_stream(mut a: Stream, mut b: Stream) {
         a.date = Utc::now();
         b.date = a.date;
@@ -311,13 +773,13 @@ mod tests {
         let temp = TempDir::new("test").unwrap();
         let stream_store =
             LocalStreamStore::new(temp.path().into(), PathBuf::from_str("/test").unwrap());
-        assert_eq!(0, stream_store.scan(temp.path()).unwrap().count());
+        assert_eq!(0, stream_store.scan(temp.path()).unwrap().1.count());
 
         tokio::fs::File::create(temp.path().join("asdfa.bla"))
             .await
             .unwrap();
 
-        assert_eq!(0, stream_store.scan(temp.path()).unwrap().count());
+        assert_eq!(0, stream_store.scan(temp.path()).unwrap().1.count());
         assert_eq!(0, stream_store.stream_map.len());
 
         let _ = stream_store
@@ -330,7 +792,7 @@ mod tests {
             .await
             .unwrap();
 
-        assert_eq!(1, stream_store.scan(temp.path()).unwrap().count());
+        assert_eq!(1, stream_store.scan(temp.path()).unwrap().1.count());
 
         tokio::fs::File::create(temp.path().join("test1.dash"))
             .await
@@ -341,10 +803,41 @@ mod tests {
             stream_store
                 .scan(&temp.path().join("test1.dash"))
                 .unwrap()
+                .1
                 .count()
         );
     }
 
+    #[tokio::test]
+    async fn test_unknown_extension() {
+        let temp = TempDir::new("test").unwrap();
+        let mut stream_store =
+            LocalStreamStore::new(temp.path().into(), PathBuf::from_str("/test").unwrap());
+        let mut uuids = Vec::new();
+        for sources in [
+            vec!["game.mkv", "game.webm", "radio.aac"],
+            vec!["game.mkv", "game.avi"],
+            vec!["https://example.com/live"],
+        ] {
+            let sources = sources.into_iter().map(PathBuf::from).collect();
+            let uuid = stream_store
+                .register("unknown".to_string(), sources, Utc::now(), None)
+                .await
+                .unwrap();
+            uuids.push(uuid);
+        }
+
+        // the offending files are skipped, the rest is loaded
+        stream_store.load(&[temp.path().into()]).unwrap();
+        assert_eq!(1, stream_store.stream_map.len());
+        let types = stream_store.stream_map[&uuids[0]]
+            .sources
+            .iter()
+            .map(|source| source.typ.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(vec!["video/x-matroska", "video/webm", "audio/aac"], types);
+    }
+
     #[tokio::test]
     async fn test_load_and_remove() {
         let temp = TempDir::new("test").unwrap();
@@ -370,20 +863,8 @@ mod tests {
         assert_stream(
             Stream {
                 uuid: registered,
-                description: "test1".to_string(),
-                date: Utc::now(),
-                sources: vec![
-                    Source {
-                        url: "/endpoint/test1.dash".into(),
-                        typ: "application/dash+xml".to_string(),
-                    },
-                    Source {
-                        url: "/endpoint/test1.m3u8".into(),
-                        typ: "application/x-mpegURL".to_string(),
-                    },
-                ],
-                fixture_id: None,
                 live: Some(true),
+                ..Stream::new("test1", endpoint_sources(&["test1.dash", "test1.m3u8"]))
             },
             stream_store.stream_map[&registered].clone(),
         );
@@ -404,20 +885,8 @@ mod tests {
         assert_stream(
             Stream {
                 uuid: uuid2,
-                description: "12345".to_string(),
-                sources: vec![
-                    Source {
-                        url: "/endpoint/test2.dash".into(),
-                        typ: "application/dash+xml".to_string(),
-                    },
-                    Source {
-                        url: "/endpoint/test_3.m3u8".into(),
-                        typ: "application/x-mpegURL".to_string(),
-                    },
-                ],
-                date: Utc::now(),
                 live: Some(true),
-                fixture_id: None,
+                ..Stream::new("12345", endpoint_sources(&["test2.dash", "test_3.m3u8"]))
             },
             stream_store.stream_map[&uuid2].clone(),
         );
@@ -425,20 +894,8 @@ mod tests {
         assert_stream(
             Stream {
                 uuid: registered,
-                description: "test1".to_string(),
-                sources: vec![
-                    Source {
-                        url: "/endpoint/test1.dash".into(),
-                        typ: "application/dash+xml".to_string(),
-                    },
-                    Source {
-                        url: "/endpoint/test1.m3u8".into(),
-                        typ: "application/x-mpegURL".to_string(),
-                    },
-                ],
-                date: Utc::now(),
                 live: Some(true),
-                fixture_id: None,
+                ..Stream::new("test1", endpoint_sources(&["test1.dash", "test1.m3u8"]))
             },
             stream_store.stream_map[&registered].clone(),
         );
@@ -457,6 +914,301 @@ mod tests {
         assert!(!stream_store.stream_map.contains_key(&uuid2));
     }
 
+    #[tokio::test]
+    async fn test_nested_directories() {
+        let temp = TempDir::new("test").unwrap();
+        let mut stream_store = LocalStreamStore::new(
+            temp.path().to_owned(),
+            PathBuf::from_str("/endpoint").unwrap(),
+        );
+
+        let directory = temp.path().join("a/b");
+        fs::create_dir_all(&directory).unwrap();
+        let uuid = Uuid::new_v4();
+        let meta = MetaFile {
+            uuid,
+            ..MetaFile::new(
+                "nested",
+                vec!["x.m3u8".into(), "../c/y.mp4".into(), "../../../z.mp4".into()],
+            )
+        };
+        stream_store
+            .write_meta_file(&directory.join("x.stream"), &meta)
+            .unwrap();
+        fs::File::create(directory.join("x.m3u8")).unwrap();
+        fs::File::create(directory.join("ignored.dash")).unwrap();
+
+        stream_store.load(&[temp.path().to_path_buf()]).unwrap();
+        let urls = stream_store.stream_map[&uuid]
+            .sources
+            .iter()
+            .map(|source| source.url.clone())
+            .collect::<Vec<_>>();
+        // sources leaving the root are dropped
+        assert_eq!(
+            vec![
+                PathBuf::from("/endpoint/a/b/x.m3u8"),
+                PathBuf::from("/endpoint/a/c/y.mp4"),
+            ],
+            urls
+        );
+        assert_eq!(
+            vec![temp.path().join("a/b/x.m3u8")],
+            stream_store.local_playlists(&directory.join("x.stream"), &meta)
+        );
+
+        // a moved directory shows up before the old one disappears
+        fs::rename(temp.path().join("a"), temp.path().join("moved")).unwrap();
+        stream_store.load(&[temp.path().join("moved")]).unwrap();
+        assert!(!stream_store.removed(&[temp.path().join("a")]).await);
+        assert_eq!(
+            PathBuf::from("/endpoint/moved/b/x.m3u8"),
+            stream_store.stream_map[&uuid].sources[0].url
+        );
+        assert_eq!(
+            temp.path().join("moved/b/x.stream"),
+            stream_store.meta_file_path(&uuid)
+        );
+
+        fs::remove_dir_all(temp.path().join("moved")).unwrap();
+        assert!(stream_store.removed(&[temp.path().join("moved")]).await);
+        assert!(stream_store.stream_map.is_empty());
+        assert!(stream_store.uuid_lookup.is_empty());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_file_watcher() {
+        for mode in [WatchMode::Native, WatchMode::Poll] {
+            let temp = TempDir::new("test").unwrap();
+            let store = Arc::new(RwLock::new(LocalStreamStore::new(
+                temp.path().to_owned(),
+                PathBuf::from_str("/endpoint").unwrap(),
+            )));
+            let watch = FileWatch {
+                mode,
+                debounce_ms: 50,
+                poll_interval: 1,
+            };
+            LocalStreamStore::run(&store, &watch).await;
+
+            let stream_count = || async { store.read().await.stream_map.len() };
+            let wait_for = |count: usize| async move {
+                for _ in 0..100 {
+                    if stream_count().await == count {
+                        return;
+                    }
+                    tokio::time::sleep(Duration::from_millis(50)).await;
+                }
+                panic!("{:?} did not find {} stream(s)", mode, count);
+            };
+
+            let directory = temp.path().join("recording");
+            fs::create_dir(&directory).unwrap();
+            let meta = MetaFile {
+                live: Some(true),
+                ..MetaFile::new("watched", vec!["recording.m3u8".into()])
+            };
+            fs::write(
+                directory.join("recording.stream"),
+                serde_yaml::to_string(&meta).unwrap(),
+            )
+            .unwrap();
+            wait_for(1).await;
+
+            fs::remove_dir_all(&directory).unwrap();
+            wait_for(0).await;
+        }
+    }
+
+    #[test]
+    fn test_local_path() {
+        let stream_store =
+            LocalStreamStore::new(PathBuf::from("/tmp"), PathBuf::from_str("/test").unwrap());
+        assert_eq!(
+            Some(PathBuf::from("/tmp/roki/roki.m3u8")),
+            stream_store.local_path(Path::new("roki/roki.m3u8"))
+        );
+        assert_eq!(None, stream_store.local_path(Path::new("../etc/passwd")));
+        assert_eq!(None, stream_store.local_path(Path::new("roki/../../x.ts")));
+        assert_eq!(None, stream_store.local_path(Path::new("/etc/passwd")));
+        assert_eq!(None, stream_store.local_path(Path::new("")));
+    }
+
+    #[tokio::test]
+    async fn test_resolve() {
+        let temp = TempDir::new("resolve").unwrap();
+        let root = temp.path().join("videos");
+        let mut stream_store =
+            LocalStreamStore::new(root.clone(), PathBuf::from_str("/streams").unwrap());
+        for source in ["match/match.m3u8", "legacy.m3u8"] {
+            stream_store
+                .register(source.to_string(), vec![source.into()], Utc::now(), None)
+                .await
+                .unwrap();
+        }
+        stream_store.load(std::slice::from_ref(&root)).unwrap();
+
+        fs::create_dir_all(root.join("match/720p")).unwrap();
+        fs::create_dir_all(root.join("unrelated")).unwrap();
+        for file in [
+            "match/match.m3u8",
+            "match/match_00001.ts",
+            "match/720p/match_00001.ts",
+            "legacy.m3u8",
+            "legacy0.ts",
+            "other.ts",
+            "unrelated/file.ts",
+        ] {
+            fs::write(root.join(file), file).unwrap();
+        }
+        fs::write(temp.path().join("secret.ts"), "secret").unwrap();
+        let link = |target: &Path, link: &str| std::os::unix::fs::symlink(target, root.join(link));
+        link(&temp.path().join("secret.ts"), "match/escape.ts").unwrap();
+        link(temp.path(), "match/escape").unwrap();
+        link(&root.join("unrelated/file.ts"), "match/unrelated.ts").unwrap();
+        link(&root.join("match"), "match_link").unwrap();
+
+        let resolve = |file: &str| stream_store.resolve(Path::new(file));
+        for file in [
+            "match/match.m3u8",
+            "match/match_00001.ts",
+            "match/720p/match_00001.ts",
+            "legacy.m3u8",
+            "legacy0.ts",
+        ] {
+            assert_eq!(root.join(file).canonicalize().unwrap(), resolve(file).unwrap());
+        }
+        // symlinks are served as their target
+        assert_eq!(
+            root.join("match/match.m3u8").canonicalize().unwrap(),
+            resolve("match_link/match.m3u8").unwrap()
+        );
+
+        for file in [
+            "../secret.ts",
+            "match/../../secret.ts",
+            "match/../legacy.m3u8",
+            "./legacy.m3u8",
+            &temp.path().join("secret.ts").to_string_lossy(),
+            "",
+        ] {
+            assert!(
+                matches!(resolve(file), Err(ResolveError::InvalidPath(_))),
+                "{}",
+                file
+            );
+        }
+        for file in [
+            "other.ts",
+            "unrelated/file.ts",
+            "match/escape.ts",
+            "match/escape/secret.ts",
+            "match/unrelated.ts",
+            "match/720p",
+        ] {
+            assert!(
+                matches!(resolve(file), Err(ResolveError::NotAStreamFile(_))),
+                "{}",
+                file
+            );
+        }
+        assert!(matches!(
+            resolve("..%2Fsecret.ts"),
+            Err(ResolveError::IoError(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_finish_stream() {
+        let temp = TempDir::new("test").unwrap();
+        let mut stream_store =
+            LocalStreamStore::new(temp.path().into(), PathBuf::from_str("/test").unwrap());
+
+        fs::write(
+            temp.path().join("match.m3u8"),
+            "#EXTM3U\n#EXT-X-PLAYLIST-TYPE:EVENT\n#EXTINF:4.0,\nmatch_00000.ts\n",
+        )
+        .unwrap();
+        let registered = stream_store
+            .register(
+                "match".to_string(),
+                vec![PathBuf::from("match.m3u8")],
+                Utc::now(),
+                Some(1),
+            )
+            .await
+            .unwrap();
+        stream_store.load(&[temp.path().to_path_buf()]).unwrap();
+        let stream_store = RwLock::new(stream_store);
+        let live = || async { stream_store.read().await.stream_map[&registered].live };
+
+        // the playlist was just written
+        LocalStreamStore::finish_stale_streams(&stream_store, Duration::from_secs(600)).await;
+        assert_eq!(Some(true), live().await);
+
+        LocalStreamStore::finish_stale_streams(&stream_store, Duration::ZERO).await;
+        assert_eq!(Some(false), live().await);
+
+        let filename = temp.path().join(format!("{}.stream", registered));
+        let meta = stream_store.read().await.parse_file(&filename).unwrap().1;
+        assert_eq!(Some(false), meta.live);
+        let playlist = fs::read_to_string(temp.path().join("match.m3u8")).unwrap();
+        assert!(playlist.contains("#EXT-X-PLAYLIST-TYPE:VOD"));
+        assert!(playlist.ends_with("#EXT-X-ENDLIST\n"));
+    }
+
+    #[tokio::test]
+    async fn test_known_directories() {
+        let temp = TempDir::new("test").unwrap();
+        let mut stream_store =
+            LocalStreamStore::new(temp.path().into(), PathBuf::from_str("/test").unwrap());
+        let write_meta = |directory: &Path| {
+            fs::create_dir_all(directory).unwrap();
+            let meta = MetaFile {
+                live: Some(true),
+                ..MetaFile::new("match", vec!["match.m3u8".into()])
+            };
+            fs::write(
+                directory.join(format!("{}.stream", meta.uuid)),
+                serde_yaml::to_string(&meta).unwrap(),
+            )
+            .unwrap();
+        };
+        let event = |path: PathBuf| DebouncedEvent {
+            path,
+            kind: notify_debouncer_mini::DebouncedEventKind::Any,
+        };
+
+        let recording = temp.path().join("recording");
+        write_meta(&recording);
+        stream_store.load(&[temp.path().into()]).unwrap();
+        assert_eq!(1, stream_store.stream_map.len());
+
+        // a segment written to a known directory, e.g. when polling
+        write_meta(&recording);
+        stream_store
+            .handle_debounced_events(vec![event(recording.clone())])
+            .await;
+        assert_eq!(1, stream_store.stream_map.len());
+
+        // a directory moved in is scanned
+        let moved = temp.path().join("moved");
+        write_meta(&moved.join("nested"));
+        stream_store
+            .handle_debounced_events(vec![event(moved.clone())])
+            .await;
+        assert_eq!(2, stream_store.stream_map.len());
+        assert!(stream_store.known_directories.contains(&moved.join("nested")));
+
+        fs::remove_dir_all(&moved).unwrap();
+        stream_store.handle_debounced_events(vec![event(moved)]).await;
+        assert_eq!(1, stream_store.stream_map.len());
+        assert_eq!(
+            HashSet::from_iter([temp.path().to_path_buf(), recording]),
+            stream_store.known_directories
+        );
+    }
+
     #[tokio::test]
     async fn test_modification_of_stream_file() {
         let temp = TempDir::new("test").unwrap();
@@ -0,0 +1,354 @@
+//! Keeps the stream directory within the limits of the retention policy.
+//! Streams past the maximum age go first, then the oldest streams until the
+//! directory fits the quota. Live and pinned streams are never evicted.
+use super::{data_types::*, editing::MediaIndex, LocalStreamStore};
+use anyhow::Result;
+use chrono::{serde::ts_seconds, DateTime, Utc};
+use ronaldos_config::Retention;
+use serde::Serialize;
+use std::{fs, path::Path, sync::Arc, time::Duration};
+use tokio::sync::RwLock;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum EvictionReason {
+    MaxAge,
+    Quota,
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct Eviction {
+    pub uuid: Uuid,
+    pub description: String,
+    #[serde(with = "ts_seconds")]
+    pub date: DateTime<Utc>,
+    /// bytes freed by removing the media of the stream
+    pub bytes: u64,
+    pub reason: EvictionReason,
+}
+
+/// Outcome of applying the retention policy, oldest evictions first.
+#[derive(Serialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct RetentionReport {
+    /// bytes used by the stream directory
+    pub used_bytes: u64,
+    /// bytes used once the evictions are done
+    pub remaining_bytes: u64,
+    /// the quota can not be met without live or pinned streams
+    pub over_quota: bool,
+    pub evictions: Vec<Eviction>,
+}
+
+impl LocalStreamStore {
+    /// Evicts old streams every `interval_minutes` of `policy`.
+    pub fn run_retention(instance: Arc<RwLock<LocalStreamStore>>, policy: Retention) {
+        if !policy.enabled() {
+            return;
+        }
+        tokio::spawn(async move {
+            let mut interval =
+                tokio::time::interval(Duration::from_secs(policy.interval_minutes.max(1) * 60));
+            loop {
+                interval.tick().await;
+                let report = match Self::enforce_retention(&instance, &policy).await {
+                    Ok(report) => report,
+                    Err(e) => {
+                        warn!("could not apply the retention policy: {:#}", e);
+                        continue;
+                    }
+                };
+                if report.over_quota {
+                    warn!(
+                        "{} bytes in use, more than the quota of {} bytes",
+                        report.remaining_bytes, policy.max_bytes
+                    );
+                }
+            }
+        });
+    }
+
+    /// The streams `policy` would evict at `now`, without removing anything.
+    /// The directory is measured without holding the lock.
+    pub async fn retention_report(
+        instance: &RwLock<LocalStreamStore>,
+        policy: &Retention,
+        now: DateTime<Utc>,
+    ) -> Result<RetentionReport> {
+        let candidates = instance.read().await.retention_candidates();
+        let policy = policy.clone();
+        Ok(tokio::task::spawn_blocking(move || candidates.report(&policy, now)).await?)
+    }
+
+    /// Deletes the streams of [LocalStreamStore::retention_report] including
+    /// their media. Streams that fail to delete are logged and skipped, as
+    /// are streams that went live or were pinned in the meantime.
+    pub async fn enforce_retention(
+        instance: &RwLock<LocalStreamStore>,
+        policy: &Retention,
+    ) -> Result<RetentionReport> {
+        let mut report = Self::retention_report(instance, policy, Utc::now()).await?;
+        let mut kept = Vec::new();
+        for eviction in &report.evictions {
+            match Self::evict(instance, &eviction.uuid).await {
+                Ok(true) => info!(
+                    "evicted {} {} ({:?}, {} bytes)",
+                    eviction.uuid, eviction.description, eviction.reason, eviction.bytes
+                ),
+                Ok(false) => kept.push(eviction.uuid),
+                Err(e) => {
+                    warn!("could not evict {}: {}", eviction.uuid, e);
+                    kept.push(eviction.uuid);
+                }
+            }
+        }
+
+        if !kept.is_empty() {
+            report.evictions.retain(|e| !kept.contains(&e.uuid));
+            let root = instance.read().await.root.clone();
+            report.remaining_bytes = tokio::task::spawn_blocking(move || disk_usage(&root)).await?;
+            report.over_quota = policy.max_bytes > 0 && report.remaining_bytes > policy.max_bytes;
+        }
+        Ok(report)
+    }
+
+    /// Deletes the stream `uuid` unless it is live or pinned, returns whether
+    /// it was deleted. The store is locked only to drop the stream, its
+    /// media is removed afterwards.
+    async fn evict(
+        instance: &RwLock<LocalStreamStore>,
+        uuid: &Uuid,
+    ) -> Result<bool, RegisterError> {
+        let media = {
+            let mut unlocked = instance.write().await;
+            let Some(stream) = unlocked.stream_map.get(uuid) else {
+                return Ok(false);
+            };
+            if stream.live == Some(true) || stream.pinned == Some(true) {
+                return Ok(false);
+            }
+            unlocked.drop_stream(uuid, true).await?
+        };
+
+        if let Some(media) = media {
+            media.remove().await?;
+        }
+        Ok(true)
+    }
+
+    /// Streams that may be evicted, oldest first.
+    fn retention_candidates(&self) -> RetentionCandidates {
+        let mut streams = self
+            .stream_map
+            .values()
+            .filter(|stream| stream.live != Some(true) && stream.pinned != Some(true))
+            .map(|stream| (stream.date, stream.uuid, stream.description.clone()))
+            .collect::<Vec<_>>();
+        streams.sort();
+        RetentionCandidates {
+            media: self.media_index(),
+            streams,
+        }
+    }
+}
+
+/// What the retention report needs of the store, see
+/// [LocalStreamStore::retention_candidates].
+struct RetentionCandidates {
+    media: MediaIndex,
+    /// date, uuid and description of the streams that may be evicted
+    streams: Vec<(DateTime<Utc>, Uuid, String)>,
+}
+
+impl RetentionCandidates {
+    fn report(&self, policy: &Retention, now: DateTime<Utc>) -> RetentionReport {
+        let used_bytes = disk_usage(self.media.root());
+        let expired_before = (policy.max_age_days > 0)
+            .then(|| now - chrono::Duration::days(policy.max_age_days.into()));
+        let over_quota = |bytes: u64| policy.max_bytes > 0 && bytes > policy.max_bytes;
+
+        let mut remaining_bytes = used_bytes;
+        let mut evictions = Vec::new();
+        for (date, uuid, description) in &self.streams {
+            let reason = match expired_before {
+                Some(before) if *date < before => EvictionReason::MaxAge,
+                _ if over_quota(remaining_bytes) => EvictionReason::Quota,
+                _ => continue,
+            };
+            let bytes = self.media_usage(uuid);
+            // removing the stream would not bring us closer to the quota
+            if reason == EvictionReason::Quota && bytes == 0 {
+                continue;
+            }
+
+            remaining_bytes = remaining_bytes.saturating_sub(bytes);
+            evictions.push(Eviction {
+                uuid: *uuid,
+                description: description.clone(),
+                date: *date,
+                bytes,
+                reason,
+            });
+        }
+
+        RetentionReport {
+            used_bytes,
+            remaining_bytes,
+            over_quota: over_quota(remaining_bytes),
+            evictions,
+        }
+    }
+
+    /// bytes of the local media of the stream `uuid`, see [MediaIndex::media]
+    fn media_usage(&self, uuid: &Uuid) -> u64 {
+        match self.media.media(uuid, self.media.sources(uuid)) {
+            Ok(media) => media.iter().map(|path| disk_usage(path)).sum(),
+            Err(e) => {
+                warn!("could not determine the size of {}: {}", uuid, e);
+                0
+            }
+        }
+    }
+}
+
+/// Bytes of the file, or of all files below the directory, at `path`.
+/// Symlinks are not followed and unreadable entries count as empty.
+fn disk_usage(path: &Path) -> u64 {
+    let mut bytes = 0;
+    let mut paths = vec![path.to_path_buf()];
+    while let Some(path) = paths.pop() {
+        let Ok(metadata) = fs::symlink_metadata(&path) else {
+            continue;
+        };
+        if metadata.is_dir() {
+            if let Ok(entries) = fs::read_dir(&path) {
+                paths.extend(entries.flatten().map(|entry| entry.path()));
+            }
+        } else {
+            bytes += metadata.len();
+        }
+    }
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::middleware::stream_store::test_store;
+    use chrono::TimeZone;
+    use std::path::PathBuf;
+    use tempdir::TempDir;
+
+    /// media of `bytes` and the `.stream` file next to it, in a directory
+    /// of its own unless `name` is "root"
+    fn add(
+        root: &Path,
+        name: &str,
+        year: i32,
+        bytes: usize,
+        live: bool,
+        pinned: bool,
+    ) -> (String, MetaFile) {
+        let (directory, source) = match name {
+            "root" => (String::new(), "root.mp4".to_string()),
+            _ => (format!("{}/", name), format!("{}.m3u8", name)),
+        };
+        fs::create_dir_all(root.join(&directory)).unwrap();
+        fs::write(root.join(&directory).join(&source), vec![0u8; bytes]).unwrap();
+
+        let meta = MetaFile {
+            date: Utc.with_ymd_and_hms(year, 1, 1, 0, 0, 0).unwrap(),
+            live: Some(live),
+            pinned: Some(pinned),
+            ..MetaFile::new(name, vec![PathBuf::from(source)])
+        };
+        (format!("{}{}.stream", directory, name), meta)
+    }
+
+    #[tokio::test]
+    async fn evictions() {
+        let temp = TempDir::new("retention").unwrap();
+        let streams = [
+            add(temp.path(), "live", 2019, 10_000, true, false),
+            add(temp.path(), "pinned", 2020, 10_000, false, true),
+            add(temp.path(), "expired", 2021, 1_000, false, false),
+            add(temp.path(), "old", 2025, 10_000, false, false),
+            add(temp.path(), "root", 2026, 5_000, false, false),
+        ];
+        let [live, pinned, expired, old, root] = streams.each_ref().map(|(_, meta)| meta.uuid);
+        let store = test_store(
+            temp.path(),
+            &streams
+                .iter()
+                .map(|(path, meta)| (path.as_str(), meta))
+                .collect::<Vec<_>>(),
+        );
+        let store = RwLock::new(store);
+
+        let now = Utc.with_ymd_and_hms(2026, 6, 1, 0, 0, 0).unwrap();
+        let mut policy = Retention {
+            max_age_days: 1000,
+            max_bytes: 30_000,
+            interval_minutes: 60,
+        };
+        let report = LocalStreamStore::retention_report(&store, &policy, now)
+            .await
+            .unwrap();
+        assert!(report.used_bytes > 36_000);
+        assert!(!report.over_quota);
+        let evicted = |report: &RetentionReport| {
+            report
+                .evictions
+                .iter()
+                .map(|e| (e.uuid, e.reason))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            vec![
+                (expired, EvictionReason::MaxAge),
+                (old, EvictionReason::Quota)
+            ],
+            evicted(&report)
+        );
+        // the .stream file is part of the media
+        assert!(report.evictions[1].bytes > 10_000);
+
+        // live and pinned streams stay, even above the quota
+        policy.max_bytes = 1_000;
+        let report = LocalStreamStore::retention_report(&store, &policy, now)
+            .await
+            .unwrap();
+        assert!(report.over_quota);
+        assert_eq!(
+            vec![
+                (expired, EvictionReason::MaxAge),
+                (old, EvictionReason::Quota),
+                (root, EvictionReason::Quota)
+            ],
+            evicted(&report)
+        );
+
+        policy.max_bytes = 30_000;
+        // went live or got pinned since the report
+        assert!(!LocalStreamStore::evict(&store, &live).await.unwrap());
+        assert!(!LocalStreamStore::evict(&store, &pinned).await.unwrap());
+        LocalStreamStore::enforce_retention(&store, &policy)
+            .await
+            .unwrap();
+        assert!(!temp.path().join("expired").exists());
+        assert!(!temp.path().join("old").exists());
+        assert!(temp.path().join("root.mp4").exists());
+        let mut kept = store
+            .read()
+            .await
+            .stream_map
+            .keys()
+            .copied()
+            .collect::<Vec<_>>();
+        kept.sort();
+        let mut expected = vec![live, pinned, root];
+        expected.sort();
+        assert_eq!(expected, kept);
+    }
+}
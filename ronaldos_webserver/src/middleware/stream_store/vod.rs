@@ -0,0 +1,613 @@
+//! Rewrites live HLS playlists into VOD playlists once a recording ended.
+//! Segment durations are measured from the presentation timestamps in the
+//! MPEG-TS segments, the durations of the live playlist are used for
+//! segments that cannot be read.
+//! Playlists are summarized here as well, to describe the sources of streams,
+//! and the markers of streams are tagged in them.
+use super::{data_types::Marker, is_local, is_remote};
+use anyhow::{ensure, Context, Result};
+use chrono::{DateTime, SecondsFormat, TimeDelta, Utc};
+use std::{
+    fs,
+    io::{Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+use tracing::{debug, info};
+
+/// MPEG-TS timestamps run at 90kHz.
+const PTS_CLOCK: f64 = 90_000.0;
+/// PTS is a 33 bit counter.
+const PTS_MASK: u64 = (1 << 33) - 1;
+const TS_PACKET_SIZE: usize = 188;
+/// bytes read at the start and the end of a segment to measure it
+const SCAN_WINDOW: u64 = 512 * TS_PACKET_SIZE as u64;
+const PROGRAM_DATE_TIME: &str = "#EXT-X-PROGRAM-DATE-TIME:";
+/// start of the EXT-X-DATERANGE tags written by [write_markers]
+const MARKER_TAG: &str = "#EXT-X-DATERANGE:ID=\"marker-";
+
+/// Rewrites the playlist at `path` as VOD playlist. Master playlists are
+/// followed and all their variants rewritten.
+pub fn finalize_playlist(path: &Path) -> Result<()> {
+    for (path, content) in media_playlists(path)? {
+        finalize_media_playlist(&path, &content)?;
+    }
+    Ok(())
+}
+
+fn finalize_media_playlist(path: &Path, content: &str) -> Result<()> {
+    let playlist = MediaPlaylist::parse(content);
+    let segments = playlist
+        .segments
+        .iter()
+        .map(|segment| resolve(path, &segment.uri))
+        .collect::<Vec<_>>();
+    let vod = playlist.to_vod(&measure_durations(&segments));
+
+    let temp = path.with_extension("m3u8.tmp");
+    fs::write(&temp, vod)?;
+    fs::rename(&temp, path)?;
+    info!(
+        "rewrote {} as VOD, {} segments",
+        path.to_string_lossy(),
+        segments.len()
+    );
+    Ok(())
+}
+
+/// Most recent modification of the playlist at `path` or, for master
+/// playlists, of any of its variants.
+pub fn last_modified(path: &Path) -> Result<SystemTime> {
+    let mut modified = fs::metadata(path)?.modified()?;
+    for (variant, _) in media_playlists(path)? {
+        modified = modified.max(fs::metadata(variant)?.modified()?);
+    }
+    Ok(modified)
+}
+
+/// Segments of a media playlist and whether it ended.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PlaylistSummary {
+    pub segments: usize,
+    /// seconds, the sum of the segment durations
+    pub duration: f64,
+    pub ended: bool,
+}
+
+/// Summary of the playlist at `path`. Master playlists are summarized by
+/// their first variant.
+pub fn summarize_playlist(path: &Path) -> Result<PlaylistSummary> {
+    let (_, content) = first_media_playlist(path)?;
+    let playlist = MediaPlaylist::parse(&content);
+    Ok(PlaylistSummary {
+        segments: playlist.segments.len(),
+        duration: playlist.segments.iter().filter_map(|s| s.duration).sum(),
+        ended: content.lines().any(|line| line.trim() == "#EXT-X-ENDLIST"),
+    })
+}
+
+/// Wall clock time the recording of the playlist at `path` started: the
+/// PROGRAM-DATE-TIME of its first segment or, without one, the last
+/// modification minus the duration of its segments. Master playlists are
+/// timed by their first variant.
+pub fn recording_start(path: &Path) -> Result<DateTime<Utc>> {
+    let (path, content) = first_media_playlist(path)?;
+    let playlist = MediaPlaylist::parse(&content);
+    let program_date = playlist.segments.first().and_then(|segment| {
+        segment
+            .tags
+            .iter()
+            .find_map(|tag| tag.strip_prefix(PROGRAM_DATE_TIME))
+            .and_then(parse_date)
+    });
+    if let Some(start) = program_date {
+        return Ok(start);
+    }
+
+    let modified = DateTime::<Utc>::from(fs::metadata(&path)?.modified()?);
+    let duration: f64 = playlist.segments.iter().filter_map(|s| s.duration).sum();
+    Ok(modified - TimeDelta::milliseconds((duration * 1000.0) as i64))
+}
+
+/// Tags the `markers` of a recording that started at `start` as
+/// EXT-X-DATERANGE in the playlist at `path`, replacing the markers tagged
+/// before. Dates need a PROGRAM-DATE-TIME, it is added to playlists that
+/// have none. Master playlists are followed and all their variants tagged.
+pub fn write_markers(path: &Path, start: DateTime<Utc>, markers: &[Marker]) -> Result<()> {
+    for (path, content) in media_playlists(path)? {
+        write_media_markers(&path, &content, start, markers)?;
+    }
+    Ok(())
+}
+
+fn write_media_markers(
+    path: &Path,
+    content: &str,
+    start: DateTime<Utc>,
+    markers: &[Marker],
+) -> Result<()> {
+    let mut lines = content
+        .lines()
+        .filter(|line| !line.starts_with(MARKER_TAG))
+        .collect::<Vec<_>>();
+    if markers.is_empty()
+        && lines.len() == content.lines().count()
+        && content.contains(PROGRAM_DATE_TIME)
+    {
+        return Ok(());
+    }
+    // the tags apply to the first segment
+    let Some(first) = lines.iter().position(|line| line.starts_with("#EXTINF")) else {
+        return Ok(());
+    };
+
+    let mut tags = Vec::new();
+    if !content.contains(PROGRAM_DATE_TIME) {
+        tags.push(format!("{}{}", PROGRAM_DATE_TIME, format_date(start)));
+    }
+    for (i, marker) in markers.iter().enumerate() {
+        let date = start + TimeDelta::milliseconds((marker.offset * 1000.0) as i64);
+        // quoted strings cannot hold quotes or line breaks
+        let title = marker.title.replace('"', "'").replace(['\r', '\n'], " ");
+        tags.push(format!(
+            "{}{}\",CLASS=\"{}\",START-DATE=\"{}\",X-TITLE=\"{}\"",
+            MARKER_TAG,
+            i,
+            marker.kind.name(),
+            format_date(date),
+            title
+        ));
+    }
+    let tags = tags.iter().map(String::as_str).collect::<Vec<_>>();
+    lines.splice(first..first, tags);
+
+    let temp = path.with_extension("m3u8.tmp");
+    fs::write(&temp, lines.join("\n") + "\n")?;
+    fs::rename(&temp, path)?;
+    debug!(
+        "tagged {} markers in {}",
+        markers.len(),
+        path.to_string_lossy()
+    );
+    Ok(())
+}
+
+/// ffmpeg writes offsets without colon, e.g. `2024-09-11T13:00:00.000+0200`
+fn parse_date(date: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc3339(date)
+        .or_else(|_| DateTime::parse_from_str(date, "%Y-%m-%dT%H:%M:%S%.f%z"))
+        .ok()
+        .map(|date| date.with_timezone(&Utc))
+}
+
+fn format_date(date: DateTime<Utc>) -> String {
+    date.to_rfc3339_opts(SecondsFormat::Millis, true)
+}
+
+fn is_master(content: &str) -> bool {
+    content.contains("#EXT-X-STREAM-INF")
+}
+
+fn read_playlist(path: &Path) -> Result<String> {
+    fs::read_to_string(path).with_context(|| format!("could not read {}", path.to_string_lossy()))
+}
+
+/// The playlist at `path` or, for master playlists, all its variants, with
+/// their content.
+fn media_playlists(path: &Path) -> Result<Vec<(PathBuf, String)>> {
+    let content = read_playlist(path)?;
+    if !is_master(&content) {
+        return Ok(vec![(path.to_path_buf(), content)]);
+    }
+    variants(path, &content).collect()
+}
+
+/// The playlist at `path` or, for master playlists, its first variant, with
+/// its content.
+fn first_media_playlist(path: &Path) -> Result<(PathBuf, String)> {
+    let content = read_playlist(path)?;
+    if !is_master(&content) {
+        return Ok((path.to_path_buf(), content));
+    }
+    let first = variants(path, &content).next();
+    first.with_context(|| format!("{} has no variants", path.to_string_lossy()))?
+}
+
+/// Variants of the master playlist at `path`. They are followed a single
+/// level and have to stay below the directory of the master playlist.
+fn variants<'a>(
+    path: &'a Path,
+    content: &'a str,
+) -> impl Iterator<Item = Result<(PathBuf, String)>> + 'a {
+    uris(content).map(move |uri| {
+        let local = Path::new(uri);
+        ensure!(
+            is_local(local) && !is_remote(local),
+            "variant {} of {} leaves its directory",
+            uri,
+            path.to_string_lossy()
+        );
+        let variant = resolve(path, uri);
+        let content = read_playlist(&variant)?;
+        ensure!(
+            !is_master(&content),
+            "variant {} of {} is a master playlist",
+            uri,
+            path.to_string_lossy()
+        );
+        Ok((variant, content))
+    })
+}
+
+/// All lines that are not tags or comments
+fn uris(content: &str) -> impl Iterator<Item = &str> {
+    content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+}
+
+/// URIs in a playlist are relative to the playlist itself.
+fn resolve(playlist: &Path, uri: &str) -> PathBuf {
+    playlist
+        .parent()
+        .map_or_else(|| PathBuf::from(uri), |dir| dir.join(uri))
+}
+
+struct Segment {
+    /// tags preceding the segment, without #EXTINF
+    tags: Vec<String>,
+    duration: Option<f64>,
+    uri: String,
+}
+
+#[derive(Default)]
+struct MediaPlaylist {
+    /// playlist tags that are kept as they are
+    header: Vec<String>,
+    segments: Vec<Segment>,
+}
+
+impl MediaPlaylist {
+    fn parse(content: &str) -> Self {
+        let mut playlist = MediaPlaylist::default();
+        let mut tags = Vec::new();
+        let mut duration = None;
+        for line in content.lines().map(str::trim).filter(|l| !l.is_empty()) {
+            if let Some(extinf) = line.strip_prefix("#EXTINF:") {
+                duration = extinf.split(',').next().and_then(|d| d.parse().ok());
+            } else if line == "#EXTM3U"
+                || line == "#EXT-X-ENDLIST"
+                || line.starts_with("#EXT-X-TARGETDURATION")
+                || line.starts_with("#EXT-X-PLAYLIST-TYPE")
+            {
+                // regenerated by `to_vod`
+            } else if line.starts_with("#EXT-X-VERSION")
+                || line.starts_with("#EXT-X-MEDIA-SEQUENCE")
+                || line.starts_with("#EXT-X-INDEPENDENT-SEGMENTS")
+                || line.starts_with("#EXT-X-ALLOW-CACHE")
+            {
+                playlist.header.push(line.to_string());
+            } else if line.starts_with('#') {
+                tags.push(line.to_string());
+            } else {
+                playlist.segments.push(Segment {
+                    tags: std::mem::take(&mut tags),
+                    duration: duration.take(),
+                    uri: line.to_string(),
+                });
+            }
+        }
+        playlist
+    }
+
+    /// `measured` holds a duration per segment, missing measurements fall
+    /// back to the duration of the live playlist.
+    fn to_vod(&self, measured: &[Option<f64>]) -> String {
+        let durations = self
+            .segments
+            .iter()
+            .zip(measured.iter().chain(std::iter::repeat(&None)))
+            .map(|(segment, measured)| measured.or(segment.duration).unwrap_or_default())
+            .collect::<Vec<_>>();
+        let target = durations.iter().fold(1.0f64, |max, d| max.max(*d)).ceil();
+
+        let mut out = String::from("#EXTM3U\n");
+        if !self.header.iter().any(|l| l.starts_with("#EXT-X-VERSION")) {
+            out.push_str("#EXT-X-VERSION:3\n");
+        }
+        for line in &self.header {
+            out.push_str(line);
+            out.push('\n');
+        }
+        out.push_str(&format!("#EXT-X-TARGETDURATION:{}\n", target));
+        out.push_str("#EXT-X-PLAYLIST-TYPE:VOD\n");
+        for (segment, duration) in self.segments.iter().zip(durations) {
+            for tag in &segment.tags {
+                out.push_str(tag);
+                out.push('\n');
+            }
+            out.push_str(&format!("#EXTINF:{:.6},\n{}\n", duration, segment.uri));
+        }
+        out.push_str("#EXT-X-ENDLIST\n");
+        out
+    }
+}
+
+/// Timestamps at the start and the end of a segment.
+struct SegmentTiming {
+    first: u64,
+    last: u64,
+    /// shortest distance between the timestamps at the end, one frame
+    frame: u64,
+}
+
+impl SegmentTiming {
+    /// Only the packets at the start and the end of the segment are read,
+    /// segments of long recordings can be large.
+    fn read(path: &Path) -> Option<Self> {
+        Self::scan(path)
+            .map_err(|e| debug!("cannot measure {}: {}", path.to_string_lossy(), e))
+            .ok()
+            .flatten()
+    }
+
+    fn scan(path: &Path) -> std::io::Result<Option<Self>> {
+        let mut file = fs::File::open(path)?;
+        let len = file.metadata()?.len();
+        let mut head = Vec::new();
+        (&mut file).take(SCAN_WINDOW).read_to_end(&mut head)?;
+        let mut tail = Vec::new();
+        if len > SCAN_WINDOW {
+            // packets start at multiples of the packet size
+            let start = (len - SCAN_WINDOW) / TS_PACKET_SIZE as u64 * TS_PACKET_SIZE as u64;
+            file.seek(SeekFrom::Start(start))?;
+            file.read_to_end(&mut tail)?;
+        }
+
+        let Some(&first) = scan_pts(&head).iter().min() else {
+            return Ok(None);
+        };
+        let mut end = scan_pts(if tail.is_empty() { &head } else { &tail });
+        end.sort_unstable();
+        end.dedup();
+        let Some(&last) = end.last() else {
+            return Ok(None);
+        };
+        let frame = end
+            .windows(2)
+            .map(|w| w[1] - w[0])
+            .min()
+            .unwrap_or_default();
+        Ok(Some(SegmentTiming { first, last, frame }))
+    }
+
+    /// time from the first to the last timestamp plus one frame
+    fn span(&self) -> u64 {
+        (self.last.wrapping_sub(self.first) & PTS_MASK) + self.frame
+    }
+}
+
+/// A segment lasts until the next one starts, the last segment lasts as long
+/// as its own timestamps span.
+fn measure_durations(segments: &[PathBuf]) -> Vec<Option<f64>> {
+    let timings = segments
+        .iter()
+        .map(|s| SegmentTiming::read(s))
+        .collect::<Vec<_>>();
+
+    timings
+        .iter()
+        .enumerate()
+        .map(|(i, timing)| {
+            let timing = timing.as_ref()?;
+            let ticks = match timings.get(i + 1) {
+                Some(Some(next)) => next.first.wrapping_sub(timing.first) & PTS_MASK,
+                _ => timing.span(),
+            };
+            Some(ticks as f64 / PTS_CLOCK)
+        })
+        .collect()
+}
+
+/// Collects the PTS of all PES packets in an MPEG-TS buffer. Video
+/// timestamps are preferred, audio timestamps are only returned for audio
+/// only segments.
+fn scan_pts(data: &[u8]) -> Vec<u64> {
+    let mut video = Vec::new();
+    let mut other = Vec::new();
+    for packet in data.chunks_exact(TS_PACKET_SIZE) {
+        let payload_start = packet[1] & 0x40 != 0;
+        let adaptation = (packet[3] >> 4) & 0x3;
+        if packet[0] != 0x47 || !payload_start || adaptation & 0x1 == 0 {
+            continue;
+        }
+
+        let offset = match adaptation & 0x2 != 0 {
+            true => 5 + packet[4] as usize,
+            false => 4,
+        };
+        let Some(pes) = packet.get(offset..).filter(|pes| pes.len() >= 14) else {
+            continue;
+        };
+        if pes[..3] != [0, 0, 1] || pes[7] & 0x80 == 0 {
+            continue;
+        }
+
+        let p = &pes[9..14];
+        let pts = (u64::from(p[0] >> 1) & 0x7) << 30
+            | u64::from(p[1]) << 22
+            | u64::from(p[2] >> 1) << 15
+            | u64::from(p[3]) << 7
+            | u64::from(p[4] >> 1);
+        match pes[3] {
+            0xE0..=0xEF => video.push(pts),
+            _ => other.push(pts),
+        }
+    }
+
+    match video.is_empty() {
+        true => other,
+        false => video,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    const LIVE: &str = "#EXTM3U
+#EXT-X-VERSION:3
+#EXT-X-TARGETDURATION:2
+#EXT-X-MEDIA-SEQUENCE:0
+#EXT-X-PLAYLIST-TYPE:EVENT
+#EXTINF:2.000000,
+match_0_00000.ts
+#EXT-X-DISCONTINUITY
+#EXTINF:1.500000,
+match_0_00001.ts
+";
+
+    /// single TS packet carrying the start of a PES packet with `pts`
+    fn pes_packet(stream_id: u8, pts: u64) -> Vec<u8> {
+        let mut packet = vec![0x47, 0x41, 0x00, 0x10];
+        packet.extend([0, 0, 1, stream_id, 0, 0, 0x80, 0x80, 5]);
+        packet.extend([
+            0x21 | ((pts >> 29) & 0x0E) as u8,
+            (pts >> 22) as u8,
+            0x01 | ((pts >> 14) & 0xFE) as u8,
+            (pts >> 7) as u8,
+            0x01 | ((pts << 1) & 0xFE) as u8,
+        ]);
+        packet.resize(TS_PACKET_SIZE, 0xFF);
+        packet
+    }
+
+    fn segment(stream_id: u8, first: u64, frames: u64, frame: u64) -> Vec<u8> {
+        (0..frames)
+            .flat_map(|i| pes_packet(stream_id, first + i * frame))
+            .collect()
+    }
+
+    #[test]
+    fn pts_scan() {
+        let mut data = segment(0xE0, 1 << 32, 3, 3000);
+        data.extend(segment(0xC0, 10, 2, 1920));
+        assert_eq!(
+            vec![1 << 32, (1 << 32) + 3000, (1 << 32) + 6000],
+            scan_pts(&data)
+        );
+        assert_eq!(vec![10, 1930], scan_pts(&segment(0xC0, 10, 2, 1920)));
+        assert!(scan_pts(&[0u8; TS_PACKET_SIZE]).is_empty());
+    }
+
+    #[test]
+    fn long_segment() {
+        let temp = TempDir::new("vod").unwrap();
+        let path = temp.path().join("long.ts");
+        // far more packets than are scanned, 80 seconds at 25 fps
+        fs::write(&path, segment(0xE0, 90_000, 2000, 3600)).unwrap();
+        let timing = SegmentTiming::read(&path).unwrap();
+        assert_eq!(90_000, timing.first);
+        assert_eq!(90_000 + 1999 * 3600, timing.last);
+        assert_eq!(vec![Some(80.0)], measure_durations(&[path]));
+    }
+
+    #[test]
+    fn live_to_vod() {
+        let temp = TempDir::new("vod").unwrap();
+        let playlist = temp.path().join("match_0.m3u8");
+        fs::write(&playlist, LIVE).unwrap();
+        // 25 fps, first segment 3 seconds, the second 1 second
+        fs::write(
+            temp.path().join("match_0_00000.ts"),
+            segment(0xE0, 90_000, 75, 3600),
+        )
+        .unwrap();
+        fs::write(
+            temp.path().join("match_0_00001.ts"),
+            segment(0xE0, 360_000, 25, 3600),
+        )
+        .unwrap();
+
+        finalize_playlist(&playlist).unwrap();
+        assert_eq!(
+            "#EXTM3U
+#EXT-X-VERSION:3
+#EXT-X-MEDIA-SEQUENCE:0
+#EXT-X-TARGETDURATION:3
+#EXT-X-PLAYLIST-TYPE:VOD
+#EXTINF:3.000000,
+match_0_00000.ts
+#EXT-X-DISCONTINUITY
+#EXTINF:1.000000,
+match_0_00001.ts
+#EXT-X-ENDLIST
+",
+            fs::read_to_string(&playlist).unwrap()
+        );
+    }
+
+    #[test]
+    fn master_without_segments() {
+        let temp = TempDir::new("vod").unwrap();
+        let master = temp.path().join("match.m3u8");
+        fs::write(
+            &master,
+            "#EXTM3U\n#EXT-X-STREAM-INF:BANDWIDTH=800000\nmatch_0.m3u8\n",
+        )
+        .unwrap();
+        fs::write(temp.path().join("match_0.m3u8"), LIVE).unwrap();
+
+        assert!(last_modified(&master).is_ok());
+        let summary = PlaylistSummary {
+            segments: 2,
+            duration: 3.5,
+            ended: false,
+        };
+        assert_eq!(summary, summarize_playlist(&master).unwrap());
+        finalize_playlist(&master).unwrap();
+        assert!(summarize_playlist(&master).unwrap().ended);
+
+        // missing segments keep the durations of the live playlist
+        let variant = fs::read_to_string(temp.path().join("match_0.m3u8")).unwrap();
+        assert!(variant.contains("#EXT-X-PLAYLIST-TYPE:VOD"));
+        assert!(variant.contains("#EXTINF:2.000000,\nmatch_0_00000.ts"));
+        assert!(variant.contains("#EXTINF:1.500000,\nmatch_0_00001.ts"));
+        assert!(variant.ends_with("#EXT-X-ENDLIST\n"));
+        assert!(!temp.path().join("match_0.m3u8.tmp").exists());
+    }
+
+    #[test]
+    fn variants_of_master() {
+        let temp = TempDir::new("vod").unwrap();
+        let master = |name: &str, variant: &str| {
+            let path = temp.path().join(name);
+            let content = format!("#EXTM3U\n#EXT-X-STREAM-INF:BANDWIDTH=800000\n{}\n", variant);
+            fs::write(&path, content).unwrap();
+            path
+        };
+        fs::write(temp.path().join("match_0.m3u8"), LIVE).unwrap();
+
+        // followed a single level
+        let nested = master("nested.m3u8", "match.m3u8");
+        assert!(summarize_playlist(&master("match.m3u8", "match_0.m3u8")).is_ok());
+        assert!(summarize_playlist(&nested).is_err());
+        assert!(finalize_playlist(&nested).is_err());
+        assert!(last_modified(&nested).is_err());
+        assert!(recording_start(&nested).is_err());
+        assert!(write_markers(&nested, Utc::now(), &[]).is_err());
+
+        // and only below the directory of the master
+        fs::create_dir(temp.path().join("match")).unwrap();
+        fs::write(temp.path().join("match").join("match_0.m3u8"), LIVE).unwrap();
+        assert!(summarize_playlist(&master("match/match.m3u8", "match_0.m3u8")).is_ok());
+        assert!(summarize_playlist(&master("match/up.m3u8", "../match_0.m3u8")).is_err());
+        let absolute = temp.path().join("match_0.m3u8");
+        let absolute = master("absolute.m3u8", absolute.to_str().unwrap());
+        assert!(summarize_playlist(&absolute).is_err());
+        let remote = master("remote.m3u8", "https://example.com/match_0.m3u8");
+        assert!(summarize_playlist(&remote).is_err());
+    }
+}
@@ -0,0 +1,156 @@
+//! Poster frames and preview sprites of recordings, decoded by the native
+//! thumbnailer in thumbnailer.cpp. Both block until the images are written,
+//! so they have to run on a blocking thread.
+use anyhow::ensure;
+use std::{
+    ffi::{c_char, CString},
+    fmt::Write,
+    os::unix::ffi::OsStrExt,
+    path::Path,
+};
+
+extern "C" {
+    fn thumbnailer_poster(
+        input: *const c_char,
+        output: *const c_char,
+        width: i32,
+        seconds: f64,
+    ) -> i32;
+    fn thumbnailer_sprite(
+        input: *const c_char,
+        output: *const c_char,
+        tile_width: i32,
+        columns: i32,
+        max_tiles: i32,
+        interval: *mut f64,
+        tiles: *mut i32,
+        tile_height: *mut i32,
+    ) -> i32;
+}
+
+const POSTER_WIDTH: i32 = 640;
+/// the first seconds are often a black screen or a logo
+const POSTER_AT: f64 = 10.0;
+const TILE_WIDTH: i32 = 160;
+const COLUMNS: i32 = 10;
+const MAX_TILES: i32 = 100;
+/// seconds between tiles, longer recordings space them out further
+const MIN_INTERVAL: f64 = 10.0;
+
+/// Layout of a sprite sheet written by [sprite].
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sprite {
+    pub tiles: u32,
+    pub tile_width: u32,
+    pub tile_height: u32,
+    pub columns: u32,
+    /// seconds covered by each tile
+    pub interval: f64,
+}
+
+impl Sprite {
+    /// WebVTT cues mapping each interval to its tile of the sheet
+    /// `image_name`, relative to the location of the WebVTT file.
+    pub fn webvtt(&self, image_name: &str) -> String {
+        let mut vtt = String::from("WEBVTT\n");
+        for tile in 0..self.tiles {
+            let start = timestamp(tile as f64 * self.interval);
+            let end = timestamp((tile + 1) as f64 * self.interval);
+            let x = (tile % self.columns) * self.tile_width;
+            let y = (tile / self.columns) * self.tile_height;
+            let _ = write!(
+                vtt,
+                "\n{} --> {}\n{}#xywh={},{},{},{}\n",
+                start, end, image_name, x, y, self.tile_width, self.tile_height
+            );
+        }
+        vtt
+    }
+}
+
+/// `HH:MM:SS.mmm` of a WebVTT cue
+pub fn timestamp(seconds: f64) -> String {
+    let millis = (seconds * 1000.0).round() as u64;
+    format!(
+        "{:02}:{:02}:{:02}.{:03}",
+        millis / 3_600_000,
+        millis / 60_000 % 60,
+        millis / 1000 % 60,
+        millis % 1000
+    )
+}
+
+/// Writes a JPEG of a frame of the playlist or MP4 `input` to `output`.
+pub fn poster(input: &Path, output: &Path) -> anyhow::Result<()> {
+    let input_c = CString::new(input.as_os_str().as_bytes())?;
+    let output_c = CString::new(output.as_os_str().as_bytes())?;
+    let status =
+        unsafe { thumbnailer_poster(input_c.as_ptr(), output_c.as_ptr(), POSTER_WIDTH, POSTER_AT) };
+    ensure!(
+        status == 0,
+        "could not create a poster of {} ({})",
+        input.to_string_lossy(),
+        status
+    );
+    Ok(())
+}
+
+/// Writes a JPEG sheet of preview tiles of the recording `input` to
+/// `output`. Fails for live playlists, whose duration is unknown.
+pub fn sprite(input: &Path, output: &Path) -> anyhow::Result<Sprite> {
+    let input_c = CString::new(input.as_os_str().as_bytes())?;
+    let output_c = CString::new(output.as_os_str().as_bytes())?;
+    let mut interval = MIN_INTERVAL;
+    let mut tiles = 0;
+    let mut tile_height = 0;
+    let status = unsafe {
+        thumbnailer_sprite(
+            input_c.as_ptr(),
+            output_c.as_ptr(),
+            TILE_WIDTH,
+            COLUMNS,
+            MAX_TILES,
+            &mut interval,
+            &mut tiles,
+            &mut tile_height,
+        )
+    };
+    ensure!(
+        status == 0,
+        "could not create previews of {} ({})",
+        input.to_string_lossy(),
+        status
+    );
+
+    Ok(Sprite {
+        tiles: tiles as u32,
+        // the native side rounds sizes down to even numbers
+        tile_width: (TILE_WIDTH & !1) as u32,
+        tile_height: tile_height as u32,
+        columns: COLUMNS as u32,
+        interval,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn webvtt() {
+        let sprite = Sprite {
+            tiles: 3,
+            tile_width: 160,
+            tile_height: 90,
+            columns: 2,
+            interval: 1800.5,
+        };
+        assert_eq!(
+            "WEBVTT\n\
+             \n00:00:00.000 --> 00:30:00.500\npreviews.jpg#xywh=0,0,160,90\n\
+             \n00:30:00.500 --> 01:00:01.000\npreviews.jpg#xywh=160,0,160,90\n\
+             \n01:00:01.000 --> 01:30:01.500\npreviews.jpg#xywh=0,90,160,90\n",
+            sprite.webvtt("previews.jpg")
+        );
+    }
+}
@@ -1,208 +1,662 @@
+#include "capture_source.hpp"
 #include "tracing.hpp"
+#include <algorithm>
+#include <atomic>
 #include <cstdint>
+#include <cstdlib>
 #include <filesystem>
-#include <fstream>
-#include <iostream>
-#include <linux/videodev2.h>
-#include <optional>
-#include <stdlib.h>
-#include <string.h>
+#include <format>
+#include <memory>
+#include <mutex>
+#include <set>
 #include <string>
-#include <sys/ioctl.h>
-#include <unistd.h>
 #include <vector>
 
-std::optional<std::filesystem::path>
-find_video_path(const std::string &dev_name) {
-  std::filesystem::path video4linux_dir("/sys/class/video4linux");
+extern "C" {
+#include <libavcodec/avcodec.h>
+#include <libavcodec/codec.h>
+#include <libavdevice/avdevice.h>
+#include <libavformat/avformat.h>
+#include <libavutil/audio_fifo.h>
+#include <libavutil/opt.h>
+#include <libswresample/swresample.h>
+#include <libswscale/swscale.h>
+}
 
-  if (!std::filesystem::exists(video4linux_dir)) {
-    return std::nullopt;
+#define AUDIO_SAMPLE_RATE 48000
+#define AUDIO_CHANNELS 2
+#define AUDIO_RESYNC_THRESHOLD_MS 100
+#define AUDIO_MAX_PADDING_MS 10000
+
+/// Drains all packets the encoder has ready and hands them to the muxer,
+/// once for every stream in `streams`. Passing a null frame flushes the
+/// encoder.
+static int encode_and_write(AVCodecContext *codec_ctx, AVFrame *frame,
+                            AVFormatContext *output_ctx,
+                            const std::vector<AVStream *> &streams,
+                            AVPacket *packet) {
+  int ret = avcodec_send_frame(codec_ctx, frame);
+  if (ret < 0) {
+    error("Error sending frame to encoder: {}", ret);
+    return ret;
   }
 
-  for (const auto &entry :
-       std::filesystem::directory_iterator(video4linux_dir)) {
-    std::filesystem::path name_file = entry.path() / "name";
+  for (;;) {
+    ret = avcodec_receive_packet(codec_ctx, packet);
+    if (ret == AVERROR(EAGAIN) || ret == AVERROR_EOF) {
+      return 0;
+    }
 
-    std::ifstream name_stream(name_file);
-    if (name_stream) {
-      std::string name;
-      std::getline(name_stream, name);
+    if (ret < 0) {
+      error("Error encoding frame: {}", ret);
+      return ret;
+    }
 
-      info("found video input device: {} - {}", entry.path().c_str(), name);
-      if (name.find(dev_name) != std::string::npos) {
-        return entry.path() / "dev";
+    for (size_t i = 0; i < streams.size(); ++i) {
+      // the muxer takes ownership of the packet, all but the last stream get
+      // a copy.
+      AVPacket *out = i + 1 < streams.size() ? av_packet_clone(packet) : packet;
+      if (!out) {
+        return AVERROR(ENOMEM);
+      }
+
+      av_packet_rescale_ts(out, codec_ctx->time_base, streams[i]->time_base);
+      out->stream_index = streams[i]->index;
+      ret = av_interleaved_write_frame(output_ctx, out);
+      if (out != packet) {
+        av_packet_free(&out);
+      }
+      if (ret < 0) {
+        error("Error writing packet: {}", ret);
+        return ret;
       }
     }
   }
-
-  return std::nullopt;
 }
 
-extern "C" {
-#include <fcntl.h>
-#include <libavcodec/avcodec.h>
-#include <libavcodec/codec.h>
-#include <libavformat/avformat.h>
-#include <libavutil/opt.h>
-
-#define FRAME_WIDTH 1920
-#define FRAME_HEIGHT 1080
-#define FPS 60
-#define SEGMENT_DURATION 10 // Segment duration in seconds
-
-// Function to set the camera properties for 1080p @ 60fps
-int set_camera_properties(int fd) {
-  struct v4l2_format format;
-  memset(&format, 0, sizeof(format));
-  format.type = V4L2_BUF_TYPE_VIDEO_CAPTURE;
-  format.fmt.pix.width = FRAME_WIDTH;
-  format.fmt.pix.height = FRAME_HEIGHT;
-  format.fmt.pix.pixelformat = V4L2_PIX_FMT_YUYV;
-  format.fmt.pix.field = V4L2_FIELD_INTERLACED;
-
-  if (ioctl(fd, VIDIOC_S_FMT, &format) < 0) {
-    error("Could not set format: {}", strerror(errno));
-    return -1;
+/// One quality level of the bitrate ladder. Shared with the rust side, keep
+/// in sync with `Rendition` in screen_grabber.rs.
+struct Rendition {
+  int32_t width;
+  int32_t height;
+  int64_t bit_rate;
+};
+
+/// Capture and encoder parameters. Shared with the rust side, keep in sync
+/// with `RawCaptureSettings` in screen_grabber.rs.
+struct CaptureSettings {
+  int32_t fps;
+  /// Segment duration in seconds
+  int32_t segment_duration;
+  /// Keyframe interval in frames
+  int32_t gop_size;
+  /// x264 preset
+  const char *preset;
+  /// renditions ordered from highest to lowest quality
+  const Rendition *ladder;
+  size_t ladder_len;
+  /// ALSA device recorded next to a capture card, see `open_frame_source`
+  const char *audio_device;
+  int64_t audio_bit_rate;
+};
+
+/// Encoder state of a single variant stream in the HLS output.
+struct VariantEncoder {
+  Rendition rendition;
+  AVStream *stream = nullptr;
+  AVCodecContext *codec_ctx = nullptr;
+  SwsContext *sws_ctx = nullptr;
+  AVFrame *frame = nullptr;
+
+  ~VariantEncoder() {
+    av_frame_free(&frame);
+    sws_freeContext(sws_ctx);
+    avcodec_free_context(&codec_ctx);
+  }
+};
+
+/// A single recording: the capture source, an encoder per rendition, an AAC
+/// encoder when the source has audio and the HLS muxer writing all variants
+/// plus a master playlist. `open` sets
+/// everything up and writes the playlist headers, `run` captures until
+/// `request_stop` is called from another thread. All resources are released
+/// in the destructor.
+class Grabber {
+public:
+  Grabber() = default;
+  Grabber(const Grabber &) = delete;
+  Grabber &operator=(const Grabber &) = delete;
+
+  ~Grabber() {
+    if (header_written) {
+      av_write_trailer(output_ctx);
+    }
+    av_packet_free(&packet);
+    av_frame_free(&samples);
+    av_frame_free(&audio_frame);
+    av_audio_fifo_free(fifo);
+    swr_free(&swr_ctx);
+    avcodec_free_context(&audio_ctx);
+    variants.clear();
+    if (output_ctx) {
+      if (!(output_ctx->oformat->flags & AVFMT_NOFILE)) {
+        avio_closep(&output_ctx->pb);
+      }
+      avformat_free_context(output_ctx);
+    }
   }
 
-  struct v4l2_streamparm streamparm;
-  memset(&streamparm, 0, sizeof(streamparm));
-  streamparm.type = V4L2_BUF_TYPE_VIDEO_CAPTURE;
-  streamparm.parm.capture.timeperframe.numerator = 1;
-  streamparm.parm.capture.timeperframe.denominator = FPS;
+  /// `output` is the path of the master playlist. variant playlists and
+  /// segments are written next to it.
+  int open(SourceKind kind, const char *input, const char *output,
+           const CaptureSettings &settings) {
+    if (!output || output[0] == '\0') {
+      error("no output path defined to write captures to");
+      return -1;
+    }
 
-  if (ioctl(fd, VIDIOC_S_PARM, &streamparm) < 0) {
-    error("Could not set frame rate");
-    return -1;
-  }
+    if (!settings.ladder || settings.ladder_len == 0) {
+      error("no renditions defined");
+      return -1;
+    }
 
-  return 0;
-}
+    if (settings.fps <= 0 || settings.segment_duration <= 0 ||
+        settings.gop_size <= 0) {
+      error("invalid capture settings: {} fps, {}s segments, gop of {}",
+            settings.fps, settings.segment_duration, settings.gop_size);
+      return -1;
+    }
 
-uint32_t screen_grabber_init() {
-  avformat_network_init();
-  return 0;
-}
+    fps = settings.fps;
+    gop_size = settings.gop_size;
+    preset = settings.preset ? settings.preset : "veryfast";
+    const Rendition *ladder = settings.ladder;
+    size_t ladder_len = settings.ladder_len;
+
+    // the first rendition is the highest quality, the source is captured in
+    // that resolution and scaled down for the others.
+    source = open_frame_source(kind, input, settings.audio_device,
+                               ladder[0].width, ladder[0].height, fps);
+    if (!source) {
+      return 1;
+    }
 
-uint32_t screen_grabber_instances_running() { return 0; }
+    std::filesystem::path master(output);
+    auto variant_pattern =
+        master.parent_path() / (master.stem().string() + "_%v.m3u8");
+    auto segment_pattern =
+        master.parent_path() / (master.stem().string() + "_%v_%05d.ts");
+
+    // Set up the output format context for HLS
+    avformat_alloc_output_context2(&output_ctx, nullptr, "hls",
+                                   variant_pattern.c_str());
+    if (!output_ctx) {
+      error("Could not create output context");
+      return 3;
+    }
 
-int screen_grabber_start(const char *capture_device, const char *output) {
-  if (!output || output[0] == '\0') {
-    error("no output path defined to write captures to");
-    return -1;
-  }
+    // Open the output file
+    if (!(output_ctx->oformat->flags & AVFMT_NOFILE)) {
+      if (avio_open(&output_ctx->pb, output, AVIO_FLAG_WRITE) < 0) {
+        error("Could not open output file {}", output);
+        return 4;
+      }
+    }
+
+    // Video codec setup
+    const AVCodec *codec = avcodec_find_encoder(AV_CODEC_ID_H264);
+    if (!codec) {
+      error("H264 codec not found");
+      return 5;
+    }
 
-  auto video_path = find_video_path(capture_device);
-  if (!video_path) {
-    error("could not find {}", capture_device);
-    return -1;
+    if (source->has_audio()) {
+      int ret = open_audio_encoder(settings.audio_bit_rate);
+      if (ret != 0) {
+        return ret;
+      }
+    }
+
+    // every variant gets its own copy of the audio stream, so each variant
+    // playlist is playable on its own.
+    std::string stream_map;
+    for (size_t i = 0; i < ladder_len; ++i) {
+      int ret = add_variant(codec, ladder[i]);
+      if (ret != 0) {
+        return ret;
+      }
+      stream_map += std::format("{}v:{}{},name:{}p", i == 0 ? "" : " ", i,
+                                audio_ctx ? std::format(",a:{}", i) : "",
+                                ladder[i].height);
+    }
+
+    packet = av_packet_alloc();
+    if (!packet) {
+      error("Could not allocate packet");
+      return 9;
+    }
+
+    // the playlist is an "event" playlist while recording. the trailer
+    // written by `run` appends #EXT-X-ENDLIST once the recording stops.
+    AVDictionary *hls_options = nullptr;
+    av_dict_set_int(&hls_options, "hls_time", settings.segment_duration, 0);
+    av_dict_set_int(&hls_options, "hls_list_size", 0, 0);
+    av_dict_set(&hls_options, "hls_playlist_type", "event", 0);
+    av_dict_set(&hls_options, "hls_segment_filename", segment_pattern.c_str(),
+                0);
+    av_dict_set(&hls_options, "var_stream_map", stream_map.c_str(), 0);
+    av_dict_set(&hls_options, "master_pl_name",
+                master.filename().c_str(), 0);
+
+    // Write the file header
+    int ret = avformat_write_header(output_ctx, &hls_options);
+    av_dict_free(&hls_options);
+    if (ret < 0) {
+      error("Error occurred when writing header");
+      return 8;
+    }
+
+    info("recording {} renditions {} audio to {}", ladder_len,
+         audio_ctx ? "with" : "without", output);
+    header_written = true;
+    return 0;
   }
 
-  // Open the video device
-  int fd = open((*video_path).c_str(), O_RDWR);
-  if (fd < 0) {
-    error("Failed to open {}: {}", video_path->c_str(), strerror(errno));
-    return 1;
+  /// Captures frames until a stop is requested, the input ends or an error
+  /// occurs. The encoders are flushed and the trailer written in all cases.
+  int run() {
+    if (!header_written) {
+      error("recording was not opened");
+      return -1;
+    }
+
+    // frames are captured into the buffer of the first variant
+    AVFrame *captured = variants.front()->frame;
+    int ret = 0;
+    int64_t last_pts = -1;
+    while (ret == 0 && !stop_requested.load()) {
+      if (av_frame_make_writable(captured) < 0) {
+        ret = 12;
+        break;
+      }
+
+      int64_t timestamp_us = 0;
+      AVMediaType type = AVMEDIA_TYPE_UNKNOWN;
+      int read = source->read(captured, samples, type, timestamp_us);
+      if (read == AVERROR(EAGAIN)) {
+        continue;
+      }
+      if (read == AVERROR_EOF) {
+        info("end of input reached");
+        break;
+      }
+      if (read < 0) {
+        error("failed to read frame: {}", read);
+        ret = 11;
+        break;
+      }
+
+      if (type == AVMEDIA_TYPE_AUDIO) {
+        if (process_audio(timestamp_us) < 0) {
+          ret = 15;
+        }
+        av_frame_unref(samples);
+        continue;
+      }
+
+      int64_t pts = av_rescale_q(timestamp_us, AV_TIME_BASE_Q, {1, fps});
+      if (pts <= last_pts) {
+        pts = last_pts + 1;
+      }
+      last_pts = pts;
+
+      for (auto &variant : variants) {
+        AVFrame *frame = variant->frame;
+        if (frame != captured) {
+          if (av_frame_make_writable(frame) < 0) {
+            ret = 12;
+            break;
+          }
+          sws_scale(variant->sws_ctx, captured->data, captured->linesize, 0,
+                    captured->height, frame->data, frame->linesize);
+        }
+
+        frame->pts = pts;
+        if (encode_and_write(variant->codec_ctx, frame, output_ctx,
+                             {variant->stream}, packet) < 0) {
+          ret = 13;
+          break;
+        }
+      }
+    }
+
+    info("finishing recording");
+    for (auto &variant : variants) {
+      encode_and_write(variant->codec_ctx, nullptr, output_ctx,
+                       {variant->stream}, packet);
+    }
+    if (audio_ctx) {
+      int remaining = av_audio_fifo_size(fifo);
+      if (remaining > 0) {
+        encode_audio_frame(remaining);
+      }
+      encode_and_write(audio_ctx, nullptr, output_ctx, audio_streams, packet);
+    }
+    if (av_write_trailer(output_ctx) < 0 && ret == 0) {
+      error("could not write trailer");
+      ret = 14;
+    }
+    header_written = false;
+    return ret;
   }
 
-  // // Set camera properties to 1080p 60fps
-  // if (set_camera_properties(fd) < 0) {
-  //   close(fd);
-  //   return 2;
-  // }
+  void request_stop() { stop_requested.store(true); }
 
-  // Set up the output format context for HLS
-  AVFormatContext *output_ctx = nullptr;
-  avformat_alloc_output_context2(&output_ctx, nullptr, "hls", output);
+private:
+  int open_audio_encoder(int64_t bit_rate) {
+    const AVCodec *codec = avcodec_find_encoder(AV_CODEC_ID_AAC);
+    if (!codec) {
+      error("AAC codec not found");
+      return 15;
+    }
+
+    audio_ctx = avcodec_alloc_context3(codec);
+    audio_ctx->sample_rate = AUDIO_SAMPLE_RATE;
+    av_channel_layout_default(&audio_ctx->ch_layout, AUDIO_CHANNELS);
+    audio_ctx->sample_fmt = AV_SAMPLE_FMT_FLTP;
+    audio_ctx->bit_rate = bit_rate;
+    audio_ctx->time_base = {1, AUDIO_SAMPLE_RATE};
+    if (output_ctx->oformat->flags & AVFMT_GLOBALHEADER) {
+      audio_ctx->flags |= AV_CODEC_FLAG_GLOBAL_HEADER;
+    }
+
+    if (avcodec_open2(audio_ctx, codec, nullptr) < 0) {
+      error("Could not open audio codec");
+      return 15;
+    }
 
-  if (!output_ctx) {
-    error("Could not create output context");
-    close(fd);
-    return 3;
+    fifo = av_audio_fifo_alloc(audio_ctx->sample_fmt, AUDIO_CHANNELS,
+                               audio_ctx->frame_size);
+    samples = av_frame_alloc();
+    audio_frame = av_frame_alloc();
+    if (!fifo || !samples || !audio_frame) {
+      error("Could not allocate audio buffers");
+      return 15;
+    }
+
+    audio_frame->nb_samples = audio_ctx->frame_size;
+    audio_frame->format = audio_ctx->sample_fmt;
+    audio_frame->sample_rate = audio_ctx->sample_rate;
+    av_channel_layout_copy(&audio_frame->ch_layout, &audio_ctx->ch_layout);
+    if (av_frame_get_buffer(audio_frame, 0) < 0) {
+      error("Could not allocate audio frame buffer");
+      return 15;
+    }
+    return 0;
   }
 
-  // Open the output file
-  if (!(output_ctx->oformat->flags & AVFMT_NOFILE)) {
-    if (avio_open(&output_ctx->pb, output, AVIO_FLAG_WRITE) < 0) {
-      error("Could not open output file {}", output);
-      avformat_free_context(output_ctx);
-      close(fd);
-      return 4;
+  /// Resamples the samples in `samples` to the encoder's format and encodes
+  /// every complete AAC frame. Audio timestamps are derived from the number
+  /// of samples written, anchored at the first timestamp of the source. When
+  /// the source clock drifts too far from the sample count, samples are
+  /// dropped or silence is inserted to keep audio and video in sync, so
+  /// timestamps never go backwards.
+  int process_audio(int64_t timestamp_us) {
+    if (!swr_ctx) {
+      int ret = swr_alloc_set_opts2(
+          &swr_ctx, &audio_ctx->ch_layout, audio_ctx->sample_fmt,
+          audio_ctx->sample_rate, &samples->ch_layout,
+          static_cast<AVSampleFormat>(samples->format), samples->sample_rate,
+          0, nullptr);
+      if (ret < 0 || swr_init(swr_ctx) < 0) {
+        error("Could not create resampler");
+        return -1;
+      }
+    }
+
+    int64_t source_pts =
+        av_rescale_q(timestamp_us, AV_TIME_BASE_Q, audio_ctx->time_base);
+    int64_t expected_pts = audio_pts + av_audio_fifo_size(fifo);
+    int64_t drift = source_pts - expected_pts;
+    int64_t drop = 0;
+    if (audio_pts == AV_NOPTS_VALUE) {
+      audio_pts = source_pts;
+    } else if (std::abs(drift) >
+               AUDIO_RESYNC_THRESHOLD_MS * AUDIO_SAMPLE_RATE / 1000) {
+      warn("audio drifted {}ms, resyncing", drift * 1000 / AUDIO_SAMPLE_RATE);
+      if (drift < 0) {
+        // the samples run ahead of the source, drop the difference
+        drop = -drift;
+      } else if (drift <= AUDIO_MAX_PADDING_MS * AUDIO_SAMPLE_RATE / 1000) {
+        // samples went missing, fill the gap with silence
+        int ret = write_silence(static_cast<int>(drift));
+        if (ret < 0) {
+          return ret;
+        }
+      } else {
+        // too long a gap to fill, skip ahead instead
+        audio_pts += drift;
+      }
     }
+
+    int capacity = swr_get_out_samples(swr_ctx, samples->nb_samples);
+    uint8_t **converted = nullptr;
+    if (av_samples_alloc_array_and_samples(&converted, nullptr,
+                                           AUDIO_CHANNELS, capacity,
+                                           audio_ctx->sample_fmt, 0) < 0) {
+      return AVERROR(ENOMEM);
+    }
+
+    int count = swr_convert(
+        swr_ctx, converted, capacity,
+        const_cast<const uint8_t **>(samples->extended_data),
+        samples->nb_samples);
+    if (count > 0) {
+      av_audio_fifo_write(fifo, reinterpret_cast<void **>(converted), count);
+    }
+    av_freep(&converted[0]);
+    av_freep(&converted);
+    if (count < 0) {
+      error("Could not resample audio: {}", count);
+      return count;
+    }
+    if (drop > 0) {
+      av_audio_fifo_drain(
+          fifo, static_cast<int>(std::min<int64_t>(
+                    drop, av_audio_fifo_size(fifo))));
+    }
+
+    while (av_audio_fifo_size(fifo) >= audio_ctx->frame_size) {
+      int ret = encode_audio_frame(audio_ctx->frame_size);
+      if (ret < 0) {
+        return ret;
+      }
+    }
+    return 0;
   }
 
-  // Video codec setup
-  const AVCodec *codec = avcodec_find_encoder(AV_CODEC_ID_H264);
-  if (!codec) {
-    error("H264 codec not found");
-    close(fd);
-    return 5;
+  int write_silence(int count) {
+    uint8_t **silence = nullptr;
+    if (av_samples_alloc_array_and_samples(&silence, nullptr, AUDIO_CHANNELS,
+                                           count, audio_ctx->sample_fmt,
+                                           0) < 0) {
+      return AVERROR(ENOMEM);
+    }
+    av_samples_set_silence(silence, 0, count, AUDIO_CHANNELS,
+                           audio_ctx->sample_fmt);
+    int written =
+        av_audio_fifo_write(fifo, reinterpret_cast<void **>(silence), count);
+    av_freep(&silence[0]);
+    av_freep(&silence);
+    return written < 0 ? written : 0;
   }
 
-  AVStream *video_st = avformat_new_stream(output_ctx, codec);
-  if (!video_st) {
-    error("Failed to create stream");
-    avio_close(output_ctx->pb);
-    avformat_free_context(output_ctx);
-    close(fd);
-    return 6;
+  int encode_audio_frame(int count) {
+    if (av_frame_make_writable(audio_frame) < 0) {
+      return AVERROR(ENOMEM);
+    }
+
+    audio_frame->nb_samples = count;
+    av_audio_fifo_read(fifo, reinterpret_cast<void **>(audio_frame->data),
+                       count);
+    audio_frame->pts = audio_pts;
+    audio_pts += count;
+    return encode_and_write(audio_ctx, audio_frame, output_ctx, audio_streams,
+                            packet);
   }
 
-  AVCodecContext *codec_ctx = avcodec_alloc_context3(codec);
-  codec_ctx->width = FRAME_WIDTH;
-  codec_ctx->height = FRAME_HEIGHT;
-  codec_ctx->time_base = {1, FPS};
-  codec_ctx->framerate = {FPS, 1};
-  codec_ctx->gop_size = 60; // Keyframe every 60 frames
-  codec_ctx->pix_fmt = AV_PIX_FMT_YUV420P;
-  codec_ctx->max_b_frames = 1;
-  codec_ctx->bit_rate = 4000000;
+  int add_variant(const AVCodec *codec, const Rendition &rendition) {
+    auto variant = std::make_unique<VariantEncoder>();
+    variant->rendition = rendition;
 
-  avcodec_parameters_from_context(video_st->codecpar, codec_ctx);
+    variant->stream = avformat_new_stream(output_ctx, nullptr);
+    if (!variant->stream) {
+      error("Failed to create stream");
+      return 6;
+    }
 
-  if (avcodec_open2(codec_ctx, codec, nullptr) < 0) {
-    std::cerr << "Could not open codec\n";
-    avcodec_free_context(&codec_ctx);
-    avio_close(output_ctx->pb);
-    avformat_free_context(output_ctx);
-    close(fd);
-    return 7;
-  }
+    AVCodecContext *codec_ctx = variant->codec_ctx =
+        avcodec_alloc_context3(codec);
+    codec_ctx->width = rendition.width;
+    codec_ctx->height = rendition.height;
+    codec_ctx->time_base = {1, fps};
+    codec_ctx->framerate = {fps, 1};
+    codec_ctx->gop_size = gop_size;
+    codec_ctx->pix_fmt = AV_PIX_FMT_YUV420P;
+    codec_ctx->max_b_frames = 1;
+    codec_ctx->bit_rate = rendition.bit_rate;
+    // fixed GOPs keep segment boundaries aligned across the variants
+    codec_ctx->flags |= AV_CODEC_FLAG_CLOSED_GOP;
+    av_opt_set(codec_ctx->priv_data, "preset", preset.c_str(), 0);
+    av_opt_set(codec_ctx->priv_data, "x264-params", "scenecut=0", 0);
+    if (output_ctx->oformat->flags & AVFMT_GLOBALHEADER) {
+      codec_ctx->flags |= AV_CODEC_FLAG_GLOBAL_HEADER;
+    }
 
-  // Write the file header
-  if (avformat_write_header(output_ctx, nullptr) < 0) {
-    std::cerr << "Error occurred when writing header\n";
-    avcodec_free_context(&codec_ctx);
-    avio_close(output_ctx->pb);
-    avformat_free_context(output_ctx);
-    close(fd);
-    return 8;
-  }
+    if (avcodec_open2(codec_ctx, codec, nullptr) < 0) {
+      error("Could not open codec for {}x{}", rendition.width,
+            rendition.height);
+      return 7;
+    }
 
-  // Main loop to capture frames and encode
-  for (;;) {
-    // Capture video frame using V4L2 (simplified, add actual buffer management)
-    // (Use OpenCV to get the frame from /dev/video0 or use direct V4L2 buffer
-    // handling)
+    avcodec_parameters_from_context(variant->stream->codecpar, codec_ctx);
+    variant->stream->time_base = codec_ctx->time_base;
 
-    // Encode the frame into HLS segment
-    // (Insert frame processing, encoding, and HLS segment creation here)
+    variant->frame = av_frame_alloc();
+    if (!variant->frame) {
+      error("Could not allocate frame");
+      return 9;
+    }
 
-    // Check for segmentation and close current segment after 10 seconds
+    variant->frame->format = codec_ctx->pix_fmt;
+    variant->frame->width = codec_ctx->width;
+    variant->frame->height = codec_ctx->height;
+    if (av_frame_get_buffer(variant->frame, 0) < 0) {
+      error("Could not allocate frame buffer");
+      return 9;
+    }
+
+    if (!variants.empty()) {
+      const auto &top = variants.front()->rendition;
+      variant->sws_ctx = sws_getContext(
+          top.width, top.height, AV_PIX_FMT_YUV420P, rendition.width,
+          rendition.height, AV_PIX_FMT_YUV420P, SWS_BILINEAR, nullptr,
+          nullptr, nullptr);
+      if (!variant->sws_ctx) {
+        error("Could not allocate scaling context");
+        return 9;
+      }
+    }
 
-    // Write the segment to the HLS playlist (e.g., output.m3u8)
+    if (audio_ctx) {
+      AVStream *audio_st = avformat_new_stream(output_ctx, nullptr);
+      if (!audio_st) {
+        error("Failed to create audio stream");
+        return 6;
+      }
+      avcodec_parameters_from_context(audio_st->codecpar, audio_ctx);
+      audio_st->time_base = audio_ctx->time_base;
+      audio_streams.push_back(audio_st);
+    }
+
+    variants.push_back(std::move(variant));
+    return 0;
   }
 
-  // Close resources
-  av_write_trailer(output_ctx);
-  avcodec_free_context(&codec_ctx);
-  avio_close(output_ctx->pb);
-  avformat_free_context(output_ctx);
+  std::atomic_bool stop_requested = false;
+  bool header_written = false;
+  int fps = 0;
+  int gop_size = 0;
+  std::string preset;
+  std::unique_ptr<FrameSource> source;
+  AVFormatContext *output_ctx = nullptr;
+  std::vector<std::unique_ptr<VariantEncoder>> variants;
+  AVPacket *packet = nullptr;
+  AVCodecContext *audio_ctx = nullptr;
+  std::vector<AVStream *> audio_streams;
+  SwrContext *swr_ctx = nullptr;
+  AVAudioFifo *fifo = nullptr;
+  /// audio as delivered by the source
+  AVFrame *samples = nullptr;
+  /// resampled audio passed to the encoder
+  AVFrame *audio_frame = nullptr;
+  int64_t audio_pts = AV_NOPTS_VALUE;
+};
+
+static std::mutex running_mutex;
+static std::set<Grabber *> running;
+
+extern "C" {
 
-  close(fd);
+uint32_t screen_grabber_init() {
+  avdevice_register_all();
+  avformat_network_init();
   return 0;
 }
 
-int screen_grabber_stop_all() { return -1; }
+uint32_t screen_grabber_instances_running() {
+  std::lock_guard lock(running_mutex);
+  return running.size();
+}
+
+/// Opens the capture source and output. On success `grabber` points to a new
+/// instance that has to be released with `screen_grabber_free`.
+int screen_grabber_open(SourceKind kind, const char *input, const char *output,
+                        const CaptureSettings *settings, Grabber **grabber) {
+  auto instance = std::make_unique<Grabber>();
+  int ret = instance->open(kind, input, output, *settings);
+  if (ret != 0) {
+    return ret;
+  }
+
+  *grabber = instance.release();
+  return 0;
+}
+
+/// Blocks the calling thread until the recording is stopped.
+int screen_grabber_run(Grabber *grabber) {
+  {
+    std::lock_guard lock(running_mutex);
+    running.insert(grabber);
+  }
+
+  int ret = grabber->run();
+
+  std::lock_guard lock(running_mutex);
+  running.erase(grabber);
+  return ret;
+}
+
+void screen_grabber_stop(Grabber *grabber) { grabber->request_stop(); }
+
+void screen_grabber_free(Grabber *grabber) { delete grabber; }
+
+/// Requests all running recordings to stop, returns how many were signaled.
+int screen_grabber_stop_all() {
+  std::lock_guard lock(running_mutex);
+  for (auto *grabber : running) {
+    grabber->request_stop();
+  }
+  return running.size();
+}
 }
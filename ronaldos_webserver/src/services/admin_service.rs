@@ -0,0 +1,216 @@
+use crate::middleware::{
+    data_types::RegisterError, ClipRequest, LocalStreamStore, Recorder, StreamEdit,
+};
+use actix_web::{
+    dev::Payload, error::ErrorUnauthorized, http::header, web, FromRequest, HttpRequest,
+    HttpResponse, Responder,
+};
+use ronaldos_config::Config;
+use serde::{Deserialize, Serialize};
+use std::future::{ready, Ready};
+use subtle::ConstantTimeEq;
+use tokio::sync::RwLock;
+use tracing::warn;
+use uuid::Uuid;
+
+pub const ADMIN_SCOPE: &str = "/admin";
+
+/// Administrative endpoints. Every request needs the `admin_token` of the
+/// config as bearer token, the api is disabled when no token is configured.
+pub fn admin_service_config(
+    cfg: &mut web::ServiceConfig,
+    recorder: web::Data<Recorder>,
+    stream_store: web::Data<RwLock<LocalStreamStore>>,
+) {
+    cfg.service(
+        web::scope(ADMIN_SCOPE)
+            .app_data(recorder)
+            .app_data(stream_store)
+            .route("/recordings", web::get().to(list_recordings))
+            .route("/recordings", web::post().to(start_recording))
+            .route("/recordings/{uuid}", web::delete().to(stop_recording))
+            .route("/streams", web::post().to(create_stream))
+            .route("/streams/{uuid}", web::patch().to(update_stream))
+            .route("/streams/{uuid}", web::delete().to(delete_stream))
+            .route("/streams/{uuid}/clips", web::post().to(create_clip))
+            .route("/retention", web::get().to(retention_report)),
+    );
+}
+
+/// Extractor that only succeeds for requests carrying the admin token.
+pub struct Admin;
+
+impl FromRequest for Admin {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let token = req
+            .app_data::<web::Data<Config>>()
+            .map(|cfg| cfg.admin_token().as_str())
+            .unwrap_or_default();
+        let authorization = req
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok());
+
+        ready(match authorized(authorization, token) {
+            true => Ok(Admin),
+            false => Err(ErrorUnauthorized("invalid admin token")),
+        })
+    }
+}
+
+/// Compares in constant time, so the token cannot be guessed byte by byte.
+fn authorized(authorization: Option<&str>, token: &str) -> bool {
+    !token.is_empty()
+        && authorization
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .is_some_and(|bearer| bearer.as_bytes().ct_eq(token.as_bytes()).into())
+}
+
+#[derive(Deserialize)]
+struct StartRequest {
+    description: String,
+    fixture_id: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct UuidResponse {
+    uuid: Uuid,
+}
+
+async fn list_recordings(_: Admin, recorder: web::Data<Recorder>) -> impl Responder {
+    HttpResponse::Ok().json(recorder.list())
+}
+
+async fn start_recording(
+    _: Admin,
+    recorder: web::Data<Recorder>,
+    request: web::Json<StartRequest>,
+) -> HttpResponse {
+    let StartRequest {
+        description,
+        fixture_id,
+    } = request.into_inner();
+    match recorder
+        .start(description, chrono::Utc::now(), fixture_id)
+        .await
+    {
+        Ok(uuid) => HttpResponse::Created().json(UuidResponse { uuid }),
+        Err(e) => {
+            warn!("could not start recording: {:#}", e);
+            HttpResponse::ServiceUnavailable().body(format!("{:#}", e))
+        }
+    }
+}
+
+async fn stop_recording(
+    _: Admin,
+    recorder: web::Data<Recorder>,
+    uuid: web::Path<Uuid>,
+) -> HttpResponse {
+    match recorder.stop(&uuid).await {
+        Ok(true) => HttpResponse::NoContent().finish(),
+        Ok(false) => HttpResponse::NotFound().finish(),
+        Err(e) => HttpResponse::InternalServerError().body(format!("{:#}", e)),
+    }
+}
+
+#[derive(Deserialize)]
+struct DeleteQuery {
+    /// also delete the local sources of the stream
+    #[serde(default)]
+    remove_media: bool,
+}
+
+async fn create_stream(
+    _: Admin,
+    store: web::Data<RwLock<LocalStreamStore>>,
+    edit: web::Json<StreamEdit>,
+) -> HttpResponse {
+    match store.write().await.create_stream(edit.into_inner()) {
+        Ok(uuid) => HttpResponse::Created().json(UuidResponse { uuid }),
+        Err(e) => edit_error(e),
+    }
+}
+
+async fn update_stream(
+    _: Admin,
+    store: web::Data<RwLock<LocalStreamStore>>,
+    uuid: web::Path<Uuid>,
+    edit: web::Json<StreamEdit>,
+) -> HttpResponse {
+    match store.write().await.update_stream(&uuid, edit.into_inner()) {
+        Ok(stream) => HttpResponse::Ok().json(stream),
+        Err(e) => edit_error(e),
+    }
+}
+
+async fn delete_stream(
+    _: Admin,
+    store: web::Data<RwLock<LocalStreamStore>>,
+    uuid: web::Path<Uuid>,
+    query: web::Query<DeleteQuery>,
+) -> HttpResponse {
+    match LocalStreamStore::delete_stream(&store, &uuid, query.remove_media).await {
+        Ok(()) => HttpResponse::NoContent().finish(),
+        Err(e) => edit_error(e),
+    }
+}
+
+/// Cuts a clip from the recording of a stream and registers it as stream.
+async fn create_clip(
+    _: Admin,
+    store: web::Data<RwLock<LocalStreamStore>>,
+    uuid: web::Path<Uuid>,
+    request: web::Json<ClipRequest>,
+) -> HttpResponse {
+    match LocalStreamStore::clip(&store, &uuid, request.into_inner()).await {
+        Ok(uuid) => HttpResponse::Created().json(UuidResponse { uuid }),
+        Err(e) => edit_error(e),
+    }
+}
+
+/// Dry run of the retention policy, lists the streams the next run evicts.
+async fn retention_report(
+    _: Admin,
+    store: web::Data<RwLock<LocalStreamStore>>,
+    cfg: web::Data<Config>,
+) -> impl Responder {
+    match LocalStreamStore::retention_report(&store, cfg.retention(), chrono::Utc::now()).await {
+        Ok(report) => HttpResponse::Ok().json(report),
+        Err(e) => {
+            warn!("could not compute the retention report: {:#}", e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+fn edit_error(error: RegisterError) -> HttpResponse {
+    match error {
+        RegisterError::UnknownStream(_) => HttpResponse::NotFound().body(error.to_string()),
+        RegisterError::IoError(_) | RegisterError::ParseError(_) | RegisterError::ClipFailed(_) => {
+            warn!("could not edit stream: {}", error);
+            HttpResponse::InternalServerError().body(error.to_string())
+        }
+        _ => HttpResponse::UnprocessableEntity().body(error.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bearer_token() {
+        assert!(authorized(Some("Bearer secret"), "secret"));
+        assert!(!authorized(Some("Bearer wrong"), "secret"));
+        assert!(!authorized(Some("Bearer secre"), "secret"));
+        assert!(!authorized(Some("secret"), "secret"));
+        assert!(!authorized(None, "secret"));
+        // an empty token disables the admin api
+        assert!(!authorized(Some("Bearer "), ""));
+        assert!(!authorized(None, ""));
+    }
+}
@@ -1,4 +1,6 @@
+pub mod admin_service;
 pub mod authentication_service;
 pub mod fixture_service;
+mod ranged_file;
 pub mod redirect_service;
 pub mod stream_service;
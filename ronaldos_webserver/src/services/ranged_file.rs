@@ -0,0 +1,146 @@
+//! Streams files from disk in chunks and answers byte range requests, so
+//! players can seek in recordings that are larger than the available RAM.
+use actix_web::{
+    http::header::{self, HttpDate},
+    web::Bytes,
+    HttpRequest, HttpResponse,
+};
+use futures_util::stream;
+use std::{
+    io::{self, SeekFrom},
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    fs::File,
+    io::{AsyncReadExt, AsyncSeekExt},
+};
+
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Responds with the file at `path`, or the part of it a `Range` header
+/// asks for. An `If-Range` that does not match the current version of the
+/// file turns the request into one for the whole file.
+pub async fn ranged_file(
+    req: &HttpRequest,
+    path: &Path,
+    content_type: &'static str,
+) -> io::Result<HttpResponse> {
+    let mut file = File::open(path).await?;
+    let metadata = file.metadata().await?;
+    let len = metadata.len();
+    let modified = metadata.modified()?;
+    let etag = etag(len, modified);
+    let last_modified = HttpDate::from(modified).to_string();
+
+    let if_range = header_str(req, header::IF_RANGE);
+    let range = match if_range {
+        Some(validator) if validator != etag && validator != last_modified => None,
+        _ => header_str(req, header::RANGE),
+    };
+
+    let (mut response, length) = match range.map(|range| parse_range(range, len)) {
+        Some(Err(())) => {
+            return Ok(HttpResponse::RangeNotSatisfiable()
+                .insert_header((header::CONTENT_RANGE, format!("bytes */{}", len)))
+                .finish());
+        }
+        Some(Ok(Some((start, end)))) => {
+            let mut response = HttpResponse::PartialContent();
+            response.insert_header((
+                header::CONTENT_RANGE,
+                format!("bytes {}-{}/{}", start, end, len),
+            ));
+            file.seek(SeekFrom::Start(start)).await?;
+            (response, end + 1 - start)
+        }
+        // no or an unsupported range, e.g. several at once
+        None | Some(Ok(None)) => (HttpResponse::Ok(), len),
+    };
+
+    response
+        .insert_header((header::CONTENT_TYPE, content_type))
+        .insert_header((header::ACCEPT_RANGES, "bytes"))
+        .insert_header((header::ETAG, etag))
+        .insert_header((header::LAST_MODIFIED, last_modified))
+        .no_chunking(length);
+    Ok(response.streaming(chunks(file.take(length))))
+}
+
+fn header_str(req: &HttpRequest, name: header::HeaderName) -> Option<&str> {
+    req.headers()
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+}
+
+/// Parses a single range of `bytes`, returns the first and last byte of it.
+/// Ok(None) stands for ranges that are served as a whole file, Err for ranges
+/// that can not be satisfied.
+fn parse_range(range: &str, len: u64) -> Result<Option<(u64, u64)>, ()> {
+    let Some(range) = range.trim().strip_prefix("bytes=") else {
+        return Ok(None);
+    };
+    if range.contains(',') {
+        return Ok(None);
+    }
+    let Some((start, end)) = range.trim().split_once('-') else {
+        return Ok(None);
+    };
+
+    let (start, end) = match (start.parse::<u64>(), end.parse::<u64>()) {
+        // the last `end` bytes
+        (Err(_), Ok(suffix)) if start.is_empty() && suffix > 0 => {
+            (len.saturating_sub(suffix), len.wrapping_sub(1))
+        }
+        (Ok(start), Err(_)) if end.is_empty() => (start, len.wrapping_sub(1)),
+        (Ok(start), Ok(end)) if start <= end => (start, end.min(len.wrapping_sub(1))),
+        _ => return Ok(None),
+    };
+    if start >= len {
+        return Err(());
+    }
+    Ok(Some((start, end)))
+}
+
+fn etag(len: u64, modified: SystemTime) -> String {
+    let modified = modified
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_micros();
+    format!("\"{:x}-{:x}\"", len, modified)
+}
+
+fn chunks(
+    reader: impl AsyncReadExt + Unpin,
+) -> impl futures_util::Stream<Item = io::Result<Bytes>> {
+    stream::try_unfold(reader, |mut reader| async move {
+        let mut chunk = vec![0u8; CHUNK_SIZE];
+        let read = reader.read(&mut chunk).await?;
+        if read == 0 {
+            return Ok(None);
+        }
+        chunk.truncate(read);
+        Ok(Some((Bytes::from(chunk), reader)))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ranges() {
+        assert_eq!(Ok(Some((2, 5))), parse_range("bytes=2-5", 10));
+        assert_eq!(Ok(Some((2, 9))), parse_range("bytes=2-", 10));
+        assert_eq!(Ok(Some((2, 9))), parse_range("bytes=2-100", 10));
+        assert_eq!(Ok(Some((7, 9))), parse_range("bytes=-3", 10));
+        assert_eq!(Ok(Some((0, 9))), parse_range("bytes=-30", 10));
+        assert_eq!(Err(()), parse_range("bytes=10-", 10));
+        assert_eq!(Err(()), parse_range("bytes=0-", 0));
+        // served as whole file
+        assert_eq!(Ok(None), parse_range("bytes=0-1,4-5", 10));
+        assert_eq!(Ok(None), parse_range("bytes=5-2", 10));
+        assert_eq!(Ok(None), parse_range("items=0-1", 10));
+        assert_eq!(Ok(None), parse_range("bytes=-0", 10));
+    }
+}
@@ -0,0 +1,253 @@
+#include "tracing.hpp"
+#include <cstdint>
+#include <filesystem>
+#include <format>
+#include <string>
+#include <vector>
+
+extern "C" {
+#include <libavformat/avformat.h>
+#include <libavformat/avio.h>
+#include <libavutil/mem.h>
+}
+
+#define INPUT_BUFFER_SIZE 65536
+
+/// Reads up to `size` bytes of the input into `buffer`. returns the number of
+/// bytes read, 0 at the end of the input or a negative value on errors.
+typedef int32_t (*RemuxRead)(void *opaque, uint8_t *buffer, int32_t size);
+
+struct ReadCallback {
+  RemuxRead read;
+  void *opaque;
+};
+
+static int read_packet(void *opaque, uint8_t *buffer, int size) {
+  auto callback = static_cast<ReadCallback *>(opaque);
+  int32_t read = callback->read(callback->opaque, buffer, size);
+  if (read == 0) {
+    return AVERROR_EOF;
+  }
+  return read < 0 ? AVERROR(EIO) : read;
+}
+
+/// Copies the audio and video streams of an input into a HLS playlist,
+/// without transcoding. The input is either a URL, e.g. an SRT socket, or
+/// data handed over by the caller through a read callback.
+class Remuxer {
+public:
+  ~Remuxer() {
+    if (header_written) {
+      av_write_trailer(output_ctx);
+    }
+    avformat_close_input(&input_ctx);
+    if (input_io) {
+      av_freep(&input_io->buffer);
+      avio_context_free(&input_io);
+    }
+    if (output_ctx && !(output_ctx->oformat->flags & AVFMT_NOFILE)) {
+      avio_closep(&output_ctx->pb);
+    }
+    avformat_free_context(output_ctx);
+  }
+
+  /// Opens `url`, or the data delivered by `callback` when no url is given,
+  /// and probes its streams. Blocks until the input delivered enough data,
+  /// for listening protocols that includes waiting for a peer.
+  int open(const char *format, const char *url, AVDictionary **options,
+           ReadCallback callback) {
+    const AVInputFormat *input_format = av_find_input_format(format);
+    if (!input_format) {
+      error("unknown input format {}", format);
+      return 1;
+    }
+
+    if (!url) {
+      this->callback = callback;
+      auto buffer = static_cast<uint8_t *>(av_malloc(INPUT_BUFFER_SIZE));
+      input_io = avio_alloc_context(buffer, INPUT_BUFFER_SIZE, 0,
+                                    &this->callback, read_packet, nullptr,
+                                    nullptr);
+      input_ctx = avformat_alloc_context();
+      if (!buffer || !input_io || !input_ctx) {
+        if (!input_io) {
+          av_free(buffer);
+        }
+        error("Could not allocate input");
+        return 1;
+      }
+      input_ctx->pb = input_io;
+    }
+
+    int ret = avformat_open_input(&input_ctx, url, input_format, options);
+    if (ret < 0) {
+      debug("Could not open {} input {}: {}", format, url ? url : "", ret);
+      return 2;
+    }
+
+    if (avformat_find_stream_info(input_ctx, nullptr) < 0) {
+      error("Could not find stream information");
+      return 2;
+    }
+    return 0;
+  }
+
+  /// Creates a HLS playlist at `output` with a copy of every audio and video
+  /// stream of the input.
+  int open_output(const char *output, int32_t segment_duration) {
+    std::filesystem::path playlist(output);
+    auto segment_pattern =
+        playlist.parent_path() / (playlist.stem().string() + "_%05d.ts");
+
+    avformat_alloc_output_context2(&output_ctx, nullptr, "hls", output);
+    if (!output_ctx) {
+      error("Could not create output context");
+      return 3;
+    }
+
+    for (unsigned i = 0; i < input_ctx->nb_streams; ++i) {
+      AVCodecParameters *codecpar = input_ctx->streams[i]->codecpar;
+      if (codecpar->codec_type != AVMEDIA_TYPE_VIDEO &&
+          codecpar->codec_type != AVMEDIA_TYPE_AUDIO) {
+        stream_map.push_back(-1);
+        continue;
+      }
+
+      AVStream *stream = avformat_new_stream(output_ctx, nullptr);
+      if (!stream) {
+        error("Failed to create stream");
+        return 6;
+      }
+      avcodec_parameters_copy(stream->codecpar, codecpar);
+      stream->codecpar->codec_tag = 0;
+      stream_map.push_back(stream->index);
+    }
+
+    if (output_ctx->nb_streams == 0) {
+      error("input has no audio or video");
+      return 6;
+    }
+
+    AVDictionary *hls_options = nullptr;
+    av_dict_set_int(&hls_options, "hls_time", segment_duration, 0);
+    av_dict_set_int(&hls_options, "hls_list_size", 0, 0);
+    av_dict_set(&hls_options, "hls_playlist_type", "event", 0);
+    av_dict_set(&hls_options, "hls_segment_filename", segment_pattern.c_str(),
+                0);
+
+    int ret = avformat_write_header(output_ctx, &hls_options);
+    av_dict_free(&hls_options);
+    if (ret < 0) {
+      error("Error occurred when writing header");
+      return 8;
+    }
+
+    info("remuxing {} streams to {}", output_ctx->nb_streams, output);
+    header_written = true;
+    return 0;
+  }
+
+  /// Copies packets until the input ends. The trailer is written in all
+  /// cases.
+  int run() {
+    if (!header_written) {
+      error("remux output was not opened");
+      return -1;
+    }
+
+    AVPacket *packet = av_packet_alloc();
+    if (!packet) {
+      return 9;
+    }
+
+    int ret = 0;
+    while (av_read_frame(input_ctx, packet) >= 0) {
+      int index = packet->stream_index < static_cast<int>(stream_map.size())
+                      ? stream_map[packet->stream_index]
+                      : -1;
+      if (index < 0) {
+        av_packet_unref(packet);
+        continue;
+      }
+
+      AVStream *in = input_ctx->streams[packet->stream_index];
+      AVStream *out = output_ctx->streams[index];
+      av_packet_rescale_ts(packet, in->time_base, out->time_base);
+      packet->stream_index = index;
+      packet->pos = -1;
+      if (av_interleaved_write_frame(output_ctx, packet) < 0) {
+        error("Error writing packet");
+        ret = 13;
+        break;
+      }
+    }
+    av_packet_free(&packet);
+
+    info("input ended, finishing playlist");
+    if (av_write_trailer(output_ctx) < 0 && ret == 0) {
+      error("could not write trailer");
+      ret = 14;
+    }
+    header_written = false;
+    return ret;
+  }
+
+private:
+  ReadCallback callback{};
+  AVIOContext *input_io = nullptr;
+  AVFormatContext *input_ctx = nullptr;
+  AVFormatContext *output_ctx = nullptr;
+  /// output stream index per input stream, -1 for dropped streams
+  std::vector<int> stream_map;
+  bool header_written = false;
+};
+
+extern "C" {
+/// Opens an input for remuxing, either `url` with the protocol options in
+/// `keys` and `values`, or the data delivered by `read` when `url` is null.
+int stream_remuxer_open(const char *format, const char *url,
+                        const char *const *keys, const char *const *values,
+                        size_t options_len, RemuxRead read, void *opaque,
+                        Remuxer **remuxer) {
+  if (!format || !remuxer || (!url && !read)) {
+    error("invalid remux arguments");
+    return -1;
+  }
+
+  AVDictionary *options = nullptr;
+  for (size_t i = 0; i < options_len; ++i) {
+    av_dict_set(&options, keys[i], values[i], 0);
+  }
+
+  auto instance = new Remuxer();
+  int ret = instance->open(format, url, &options, ReadCallback{read, opaque});
+  av_dict_free(&options);
+  if (ret != 0) {
+    delete instance;
+    return ret;
+  }
+
+  *remuxer = instance;
+  return 0;
+}
+
+/// Starts the HLS output of an opened remuxer.
+int stream_remuxer_output(Remuxer *remuxer, const char *output,
+                          int32_t segment_duration) {
+  if (!remuxer || !output || segment_duration <= 0) {
+    error("invalid remux output");
+    return -1;
+  }
+  return remuxer->open_output(output, segment_duration);
+}
+
+/// Copies packets until the input ends, returns 0 on success.
+int stream_remuxer_run(Remuxer *remuxer) {
+  if (!remuxer) {
+    return -1;
+  }
+  return remuxer->run();
+}
+
+void stream_remuxer_free(Remuxer *remuxer) { delete remuxer; }
+}
@@ -0,0 +1,349 @@
+#include "tracing.hpp"
+#include <algorithm>
+#include <cmath>
+#include <cstdint>
+#include <cstring>
+#include <fstream>
+
+extern "C" {
+#include <libavcodec/avcodec.h>
+#include <libavformat/avformat.h>
+#include <libswscale/swscale.h>
+}
+
+/// Decodes single frames of the first video stream of a recording and
+/// scales them to the size of a thumbnail.
+class FrameGrabber {
+public:
+  ~FrameGrabber() {
+    sws_freeContext(sws_ctx);
+    av_frame_free(&decoded);
+    av_packet_free(&packet);
+    avcodec_free_context(&decoder);
+    avformat_close_input(&format_ctx);
+  }
+
+  int open(const char *input) {
+    if (avformat_open_input(&format_ctx, input, nullptr, nullptr) < 0) {
+      debug("Could not open {}", input);
+      return 1;
+    }
+    if (avformat_find_stream_info(format_ctx, nullptr) < 0) {
+      error("Could not find stream information of {}", input);
+      return 2;
+    }
+
+    const AVCodec *codec = nullptr;
+    stream_index = av_find_best_stream(format_ctx, AVMEDIA_TYPE_VIDEO, -1, -1,
+                                       &codec, 0);
+    if (stream_index < 0 || !codec) {
+      debug("{} has no video", input);
+      return 3;
+    }
+
+    decoder = avcodec_alloc_context3(codec);
+    decoded = av_frame_alloc();
+    packet = av_packet_alloc();
+    if (!decoder || !decoded || !packet) {
+      error("Could not allocate decoder");
+      return 4;
+    }
+    avcodec_parameters_to_context(decoder,
+                                  format_ctx->streams[stream_index]->codecpar);
+    if (avcodec_open2(decoder, codec, nullptr) < 0) {
+      error("Could not open decoder of {}", input);
+      return 4;
+    }
+    return 0;
+  }
+
+  /// length of the recording in seconds, 0 when unknown, e.g. for live
+  /// playlists
+  double duration() const {
+    if (format_ctx->duration <= 0) {
+      return 0;
+    }
+    return format_ctx->duration / static_cast<double>(AV_TIME_BASE);
+  }
+
+  /// height of a thumbnail `width` pixels wide, rounded to an even number
+  int height_for(int width) const {
+    const AVCodecParameters *codecpar =
+        format_ctx->streams[stream_index]->codecpar;
+    if (codecpar->width <= 0 || codecpar->height <= 0) {
+      return std::max(2, (width * 9 / 16) & ~1);
+    }
+
+    AVRational sar = codecpar->sample_aspect_ratio;
+    double pixel_aspect = sar.num > 0 && sar.den > 0 ? av_q2d(sar) : 1.0;
+    double aspect = codecpar->width * pixel_aspect / codecpar->height;
+    return std::max(2, static_cast<int>(std::lround(width / aspect)) & ~1);
+  }
+
+  /// Decodes the first frame at or after `seconds` into `output`, which has
+  /// the size and pixel format of the thumbnail.
+  int frame_at(double seconds, AVFrame *output) {
+    AVStream *stream = format_ctx->streams[stream_index];
+    int64_t start =
+        format_ctx->start_time != AV_NOPTS_VALUE ? format_ctx->start_time : 0;
+    int64_t target = start + static_cast<int64_t>(seconds * AV_TIME_BASE);
+    if (seconds > 0) {
+      // the keyframe before the target, decoding continues from there
+      if (avformat_seek_file(format_ctx, -1, INT64_MIN, target, target, 0) <
+          0) {
+        debug("Could not seek to {}s", seconds);
+      }
+      avcodec_flush_buffers(decoder);
+    }
+    int64_t target_pts =
+        av_rescale_q(target, AVRational{1, AV_TIME_BASE}, stream->time_base);
+
+    while (true) {
+      int ret = avcodec_receive_frame(decoder, decoded);
+      if (ret == 0) {
+        int64_t pts = decoded->best_effort_timestamp;
+        if (pts == AV_NOPTS_VALUE || pts >= target_pts) {
+          ret = scale(output);
+          av_frame_unref(decoded);
+          return ret;
+        }
+        av_frame_unref(decoded);
+        continue;
+      }
+      if (ret == AVERROR_EOF) {
+        debug("no frame at {}s", seconds);
+        return 5;
+      }
+      if (ret != AVERROR(EAGAIN)) {
+        error("Error decoding frame");
+        return 6;
+      }
+
+      if (av_read_frame(format_ctx, packet) < 0) {
+        // drains the frames the decoder still holds
+        avcodec_send_packet(decoder, nullptr);
+        continue;
+      }
+      if (packet->stream_index == stream_index) {
+        avcodec_send_packet(decoder, packet);
+      }
+      av_packet_unref(packet);
+    }
+  }
+
+private:
+  int scale(AVFrame *output) {
+    sws_ctx = sws_getCachedContext(
+        sws_ctx, decoded->width, decoded->height,
+        static_cast<AVPixelFormat>(decoded->format), output->width,
+        output->height, static_cast<AVPixelFormat>(output->format),
+        SWS_BICUBIC, nullptr, nullptr, nullptr);
+    if (!sws_ctx || av_frame_make_writable(output) < 0) {
+      error("Could not scale frame");
+      return 7;
+    }
+    sws_scale(sws_ctx, decoded->data, decoded->linesize, 0, decoded->height,
+              output->data, output->linesize);
+    return 0;
+  }
+
+  AVFormatContext *format_ctx = nullptr;
+  AVCodecContext *decoder = nullptr;
+  AVFrame *decoded = nullptr;
+  AVPacket *packet = nullptr;
+  SwsContext *sws_ctx = nullptr;
+  int stream_index = -1;
+};
+
+/// Allocates a full range YUV 4:2:0 picture, the format JPEGs are written
+/// in. `width` and `height` have to be even.
+static AVFrame *alloc_picture(int width, int height) {
+  AVFrame *frame = av_frame_alloc();
+  if (!frame) {
+    return nullptr;
+  }
+  frame->format = AV_PIX_FMT_YUVJ420P;
+  frame->width = width;
+  frame->height = height;
+  if (av_frame_get_buffer(frame, 0) < 0) {
+    av_frame_free(&frame);
+  }
+  return frame;
+}
+
+/// Paints `frame` black.
+static void clear_picture(AVFrame *frame) {
+  for (int plane = 0; plane < 3; ++plane) {
+    int rows = plane == 0 ? frame->height : frame->height / 2;
+    std::memset(frame->data[plane], plane == 0 ? 0 : 128,
+                static_cast<size_t>(frame->linesize[plane]) * rows);
+  }
+}
+
+/// Copies `tile` into `sheet` with its top left corner at `x`, `y`.
+static void copy_tile(const AVFrame *tile, AVFrame *sheet, int x, int y) {
+  for (int plane = 0; plane < 3; ++plane) {
+    int shift = plane == 0 ? 0 : 1;
+    int rows = tile->height >> shift;
+    int bytes = tile->width >> shift;
+    for (int row = 0; row < rows; ++row) {
+      std::memcpy(sheet->data[plane] +
+                      ((y >> shift) + row) * sheet->linesize[plane] +
+                      (x >> shift),
+                  tile->data[plane] + row * tile->linesize[plane], bytes);
+    }
+  }
+}
+
+struct JpegEncoder {
+  ~JpegEncoder() {
+    av_packet_free(&packet);
+    avcodec_free_context(&ctx);
+  }
+
+  AVCodecContext *ctx = nullptr;
+  AVPacket *packet = nullptr;
+};
+
+/// Writes `frame` as JPEG to `output`.
+static int write_jpeg(AVFrame *frame, const char *output) {
+  const AVCodec *codec = avcodec_find_encoder(AV_CODEC_ID_MJPEG);
+  JpegEncoder encoder;
+  if (codec) {
+    encoder.ctx = avcodec_alloc_context3(codec);
+    encoder.packet = av_packet_alloc();
+  }
+  if (!encoder.ctx || !encoder.packet) {
+    error("Could not allocate jpeg encoder");
+    return 10;
+  }
+
+  AVCodecContext *ctx = encoder.ctx;
+  ctx->width = frame->width;
+  ctx->height = frame->height;
+  ctx->pix_fmt = static_cast<AVPixelFormat>(frame->format);
+  ctx->time_base = AVRational{1, 25};
+  ctx->flags |= AV_CODEC_FLAG_QSCALE;
+  ctx->global_quality = FF_QP2LAMBDA * 4;
+  if (avcodec_open2(ctx, codec, nullptr) < 0) {
+    error("Could not open jpeg encoder");
+    return 10;
+  }
+
+  frame->quality = ctx->global_quality;
+  frame->pts = 0;
+  if (avcodec_send_frame(ctx, frame) < 0 ||
+      avcodec_send_frame(ctx, nullptr) < 0 ||
+      avcodec_receive_packet(ctx, encoder.packet) < 0) {
+    error("Could not encode {}", output);
+    return 11;
+  }
+
+  std::ofstream file(output, std::ios::binary | std::ios::trunc);
+  file.write(reinterpret_cast<const char *>(encoder.packet->data),
+             encoder.packet->size);
+  if (!file) {
+    error("Could not write {}", output);
+    return 12;
+  }
+  return 0;
+}
+
+extern "C" {
+/// Writes the frame at `seconds` of `input`, `width` pixels wide, as JPEG to
+/// `output`. Short recordings use the frame halfway instead.
+int thumbnailer_poster(const char *input, const char *output, int32_t width,
+                       double seconds) {
+  if (!input || !output || width < 2) {
+    error("invalid poster arguments");
+    return -1;
+  }
+
+  FrameGrabber grabber;
+  int ret = grabber.open(input);
+  if (ret != 0) {
+    return ret;
+  }
+
+  double duration = grabber.duration();
+  double at = duration > 0 ? std::min(seconds, duration / 2) : 0;
+  int even_width = width & ~1;
+  AVFrame *poster = alloc_picture(even_width, grabber.height_for(even_width));
+  if (!poster) {
+    error("Could not allocate poster");
+    return 4;
+  }
+
+  ret = grabber.frame_at(at, poster);
+  if (ret == 0) {
+    ret = write_jpeg(poster, output);
+  }
+  av_frame_free(&poster);
+  return ret;
+}
+
+/// Writes a sheet of thumbnails of `input`, `tile_width` pixels wide in rows
+/// of `columns`, as JPEG to `output`. There is a tile every `interval`
+/// seconds, the interval grows for recordings that would need more than
+/// `max_tiles` tiles. On success `interval`, `tiles` and `tile_height`
+/// describe the sheet.
+int thumbnailer_sprite(const char *input, const char *output,
+                       int32_t tile_width, int32_t columns, int32_t max_tiles,
+                       double *interval, int32_t *tiles,
+                       int32_t *tile_height) {
+  if (!input || !output || tile_width < 2 || columns < 1 || max_tiles < 1 ||
+      !interval || *interval <= 0 || !tiles || !tile_height) {
+    error("invalid sprite arguments");
+    return -1;
+  }
+
+  FrameGrabber grabber;
+  int ret = grabber.open(input);
+  if (ret != 0) {
+    return ret;
+  }
+
+  double duration = grabber.duration();
+  if (duration <= 0) {
+    debug("duration of {} is unknown", input);
+    return 8;
+  }
+  *interval = std::max(*interval, duration / max_tiles);
+  int count = std::clamp(static_cast<int>(std::ceil(duration / *interval)), 1,
+                         static_cast<int>(max_tiles));
+
+  int width = tile_width & ~1;
+  int height = grabber.height_for(width);
+  int sheet_columns = std::min(count, static_cast<int>(columns));
+  int sheet_rows = (count + columns - 1) / columns;
+  AVFrame *tile = alloc_picture(width, height);
+  AVFrame *sheet = alloc_picture(width * sheet_columns, height * sheet_rows);
+  if (!tile || !sheet) {
+    error("Could not allocate sprite");
+    av_frame_free(&tile);
+    av_frame_free(&sheet);
+    return 4;
+  }
+
+  // black background for a partly filled last row
+  clear_picture(sheet);
+  int filled = 0;
+  for (; filled < count; ++filled) {
+    if (grabber.frame_at(filled * *interval, tile) != 0) {
+      break;
+    }
+    copy_tile(tile, sheet, (filled % columns) * width,
+              (filled / columns) * height);
+  }
+
+  ret = filled > 0 ? write_jpeg(sheet, output) : 5;
+  av_frame_free(&tile);
+  av_frame_free(&sheet);
+  if (ret == 0) {
+    info("{} previews of {} every {}s", filled, input, *interval);
+    *tiles = filled;
+    *tile_height = height;
+  }
+  return ret;
+}
+}